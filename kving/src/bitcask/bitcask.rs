@@ -1,5 +1,18 @@
+use crate::bitcask::codec::{CODEC_MASK, CODEC_NONE, Codec};
+use crate::bitcask::crypto::{Cipher, FLAG_ENCRYPTED, NONCE_SIZE, TAG_SIZE};
+use crate::bitcask::io::{DataIo, LOAD_READ_AHEAD, ReadSeek};
+use crate::bitcask::mmap::MappedFiles;
 use crate::kving::config::Config;
-use crate::kving::kv_store::KvStore;
+use crate::kving::diagnostics::{self, ErrorEvent, ErrorSource};
+use crate::kving::kv_store::{BatchOp, KvStore};
+use crate::kving::lock::DirLock;
+use crate::kving::merge::MergeControl;
+use crate::kving::metrics;
+use crate::kving::stats::{FileStats, Stats};
+use crate::kving::value::ValueRef;
+use crate::kving::verify::{
+    BadEntry, CorruptRange, CorruptionKind, OrphanedRecord, RepairReport, Throttle, VerifyReport,
+};
 use byteorder::{BE, ReadBytesExt, WriteBytesExt};
 use crc32fast::Hasher;
 use lru::LruCache;
@@ -7,8 +20,8 @@ use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroUsize;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 type FileHandleCache = Mutex<LruCache<u64, BufReader<File>>>;
@@ -24,6 +37,65 @@ struct RecordPos {
     value_size: u64,
     value_pos: u64,
     timestamp: u64,
+    /// Size of the value once decoded
+    value_len: u64,
+    flags: u8,
+}
+
+impl RecordPos {
+    /// Get the offset of the record holding this value, for a key of `key_size` bytes
+    fn record_start_pos(&self, key_size: u64) -> u64 {
+        self.value_pos - RecordData::header_size_for(self.flags) - key_size
+    }
+
+    /// Get the size of the record holding this value, for a key of `key_size` bytes
+    fn record_size(&self, key_size: u64) -> u64 {
+        self.value_pos + self.value_size - self.record_start_pos(key_size)
+    }
+}
+
+/// Bytes of the live records of each data file, and of the tombstones a merge kept,
+/// the rest of a file is dead
+#[derive(Default)]
+struct FileUsage(HashMap<u64, u64>);
+
+impl FileUsage {
+    /// Sum the records keydir points to
+    fn from_keydir(keydir: &HashMap<Vec<u8>, RecordPos>) -> Self {
+        let mut usage = Self::default();
+        for (key, record_pos) in keydir {
+            usage.replace(key.len() as u64, Some(record_pos), None);
+        }
+        usage
+    }
+
+    /// Account for keydir pointing `key_size` bytes key to `new` instead of `old`
+    fn replace(&mut self, key_size: u64, new: Option<&RecordPos>, old: Option<&RecordPos>) {
+        if let Some(old) = old
+            && let Some(live_bytes) = self.0.get_mut(&old.file_id)
+        {
+            *live_bytes = live_bytes.saturating_sub(old.record_size(key_size));
+        }
+        if let Some(new) = new {
+            *self.0.entry(new.file_id).or_default() += new.record_size(key_size);
+        }
+    }
+
+    /// Get the live bytes of a data file
+    fn live_bytes(&self, file_id: u64) -> u64 {
+        self.0.get(&file_id).copied().unwrap_or(0)
+    }
+
+    /// Account for `bytes` of a data file that must be kept, though keydir doesn't point
+    /// to them
+    fn add(&mut self, file_id: u64, bytes: u64) {
+        *self.0.entry(file_id).or_default() += bytes;
+    }
+
+    /// Forget a deleted data file
+    fn remove(&mut self, file_id: u64) {
+        self.0.remove(&file_id);
+    }
 }
 
 /// The data structure of RecordData stored in a file
@@ -32,6 +104,10 @@ struct RecordData {
     timestamp: u64,
     key_size: u64,
     value_size: u64,
+    /// Stored in the top byte of the value size, zero for a raw value
+    flags: u8,
+    /// Follows the header of an encrypted record, empty otherwise
+    nonce: Vec<u8>,
     key: Vec<u8>,
     value: Vec<u8>,
 }
@@ -40,11 +116,26 @@ impl RecordData {
     /// RecordData header size: `crc(4) + timestamp(8) + key_size(8) + value_size(8)` bytes len.
     const HEADER_SIZE: u64 = 4 + 8 + 8 + 8;
 
+    /// Bits of the on-disk value size holding the size, the top byte holds the flags
+    const VALUE_SIZE_MASK: u64 = (1 << 56) - 1;
+
     /// Tombstone value, indicating deletion
     const TOMBSTONE: &'static [u8] = &[0];
 
+    /// Reserved key of the marker record written in front of a batch.
+    /// Its value is the number of records in the batch as a big-endian `u64`.
+    const BATCH_KEY: &'static [u8] = &[0xFF, b'b', b'a', b't', b'c', b'h'];
+
+    /// Most records preallocated for a batch being read, whatever its marker announces
+    const BATCH_PREALLOC: u64 = 1024;
+
     /// Create a new RecordData instance
     fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self::with_flags(key, value, CODEC_NONE)
+    }
+
+    /// Create a new RecordData instance holding a value encoded as described by `flags`
+    fn with_flags(key: Vec<u8>, value: Vec<u8>, flags: u8) -> Self {
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
@@ -55,37 +146,122 @@ impl RecordData {
             timestamp,
             key_size: key.len() as u64,
             value_size: value.len() as u64,
+            flags,
+            nonce: Vec::new(),
             key,
             value,
         }
     }
 
+    /// Create a record holding `value` encoded with the codec
+    fn encoded(codec: &Codec, key: Vec<u8>, value: &[u8]) -> crate::Result<Self> {
+        let (flags, value) = codec.encode(value)?;
+        Ok(Self::with_flags(key, value, flags))
+    }
+
     /// Create a tombstone record for deletion
     fn tombstone(key: Vec<u8>) -> Self {
         Self::new(key, Self::TOMBSTONE.to_vec())
     }
 
+    /// Create a marker record announcing a batch of `count` records
+    fn batch_marker(count: u64) -> Self {
+        Self::new(Self::BATCH_KEY.to_vec(), count.to_be_bytes().to_vec())
+    }
+
     /// Check if this record is a tombstone
     fn is_tombstone(&self) -> bool {
-        self.value_size as usize == Self::TOMBSTONE.len() && self.value == Self::TOMBSTONE
+        self.flags & CODEC_MASK == CODEC_NONE && self.value == Self::TOMBSTONE
+    }
+
+    /// Get the in-memory position of this record, stored at `record_start_pos` of a file
+    fn pos(&self, file_id: u64, record_start_pos: u64) -> RecordPos {
+        RecordPos {
+            file_id,
+            value_size: self.value_size,
+            value_pos: record_start_pos + self.header_size() + self.key_size,
+            timestamp: self.timestamp,
+            value_len: Codec::decoded_len(self.flags, &self.value),
+            flags: self.flags,
+        }
+    }
+
+    /// Get the record count if this record is a batch marker. Batches are never empty and
+    /// their records can't outgrow a file, so any other count means the marker is corrupt.
+    fn batch_count(&self) -> Option<crate::Result<u64>> {
+        if self.key != Self::BATCH_KEY {
+            return None;
+        }
+        let count = match <[u8; 8]>::try_from(self.value.as_slice()) {
+            Ok(count) => u64::from_be_bytes(count),
+            Err(_) => return Some(Err(crate::Error::CorruptedData)),
+        };
+        if count == 0 || count > u64::MAX / Self::HEADER_SIZE {
+            return Some(Err(crate::Error::CorruptedData));
+        }
+        Some(Ok(count))
+    }
+
+    /// Preallocate the records of a batch of `count` records
+    fn batch_records<T>(count: u64) -> Vec<T> {
+        Vec::with_capacity(count.min(Self::BATCH_PREALLOC) as usize)
+    }
+
+    /// Get the header size of a record with `flags`, including the nonce if encrypted
+    fn header_size_for(flags: u8) -> u64 {
+        if flags & FLAG_ENCRYPTED != 0 {
+            Self::HEADER_SIZE + NONCE_SIZE
+        } else {
+            Self::HEADER_SIZE
+        }
+    }
+
+    /// Get the header size of this record
+    fn header_size(&self) -> u64 {
+        Self::header_size_for(self.flags)
     }
 
     /// Calculate total record size
     fn total_size(&self) -> u64 {
-        Self::HEADER_SIZE + self.key_size + self.value_size
+        self.header_size() + self.key_size + self.value_size
+    }
+
+    /// Get the header fields authenticated along with an encrypted payload
+    fn associated_data(&self) -> crate::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(8 + 8 + 8);
+        buf.write_u64::<BE>(self.timestamp)?;
+        buf.write_u64::<BE>(self.key_size)?;
+        buf.write_u64::<BE>(self.value_size | (self.flags as u64) << 56)?;
+        Ok(buf)
     }
 
-    /// Encode RecordData into a byte array
-    fn encode(&self) -> crate::Result<Vec<u8>> {
+    /// Encode RecordData into a byte array, encrypting the key and value with `cipher`
+    fn encode(&mut self, cipher: Option<&Cipher>) -> crate::Result<Vec<u8>> {
+        self.value_size = self.value.len() as u64;
+        if cipher.is_some() {
+            self.flags |= FLAG_ENCRYPTED;
+            self.value_size += TAG_SIZE;
+        }
+
         let mut buf = Vec::with_capacity(self.total_size() as usize);
 
         // Reserve CRC position
         buf.write_u32::<BE>(0)?;
-        buf.write_u64::<BE>(self.timestamp)?;
-        buf.write_u64::<BE>(self.key_size)?;
-        buf.write_u64::<BE>(self.value_size)?;
-        buf.write_all(&self.key)?;
-        buf.write_all(&self.value)?;
+        buf.write_all(&self.associated_data()?)?;
+        match cipher {
+            Some(cipher) => {
+                let mut payload = Vec::with_capacity((self.key_size + self.value_size) as usize);
+                payload.extend_from_slice(&self.key);
+                payload.extend_from_slice(&self.value);
+                self.nonce = cipher.encrypt(&buf[4..], &mut payload)?;
+                buf.write_all(&self.nonce)?;
+                buf.write_all(&payload)?;
+            }
+            None => {
+                buf.write_all(&self.key)?;
+                buf.write_all(&self.value)?;
+            }
+        }
 
         // Calculate CRC and fill in
         let mut hasher = Hasher::new();
@@ -95,50 +271,346 @@ impl RecordData {
 
         Ok(buf)
     }
+
+    /// Decrypt the key and value of a record read from a data file, if it is encrypted
+    fn decrypt(&mut self, cipher: Option<&Cipher>) -> crate::Result<()> {
+        if self.flags & FLAG_ENCRYPTED == 0 {
+            return Ok(());
+        }
+        let cipher = cipher.ok_or(crate::Error::DecryptionFailed)?;
+
+        let mut payload = std::mem::take(&mut self.key);
+        payload.append(&mut self.value);
+        cipher.decrypt(&self.nonce, &self.associated_data()?, &mut payload)?;
+        self.value = payload.split_off(self.key_size as usize);
+        self.key = payload;
+        Ok(())
+    }
+}
+
+/// Largest key and stored value a record read from a data file may hold. Checked before
+/// they are allocated, so a corrupted size field fails cleanly instead of exhausting memory.
+#[derive(Clone, Copy)]
+struct SizeLimits {
+    key: u64,
+    value: u64,
+}
+
+impl SizeLimits {
+    /// Limits no record exceeds, for data already bounded in memory
+    const NONE: Self = Self {
+        key: u64::MAX,
+        value: u64::MAX,
+    };
+
+    /// Get the limits of the config, making room for batch markers and authentication tags
+    fn with_config(config: &Config) -> Self {
+        Self {
+            key: config
+                .max_key_size()
+                .max(RecordData::BATCH_KEY.len() as u64),
+            value: config.max_value_size().max(8).saturating_add(TAG_SIZE),
+        }
+    }
+}
+
+/// The data files a merge writes live records to, each under an id reserved once the
+/// previous one is full
+struct MergeOutput<'a> {
+    file_id: u64,
+    file: BufWriter<File>,
+    offset: u64,
+    /// Reserves the id of the next output
+    next_id: Box<dyn FnMut() -> crate::Result<u64> + 'a>,
+    /// Outputs written before the current one
+    full_files: Vec<(u64, BufWriter<File>)>,
+    /// Bytes of the tombstones kept in each output
+    tombstone_bytes: HashMap<u64, u64>,
+}
+
+impl<'a> MergeOutput<'a> {
+    /// Start writing to an output under an id reserved with `next_id`
+    fn create(
+        config: &Config,
+        mut next_id: impl FnMut() -> crate::Result<u64> + 'a,
+    ) -> crate::Result<Self> {
+        let file_id = next_id()?;
+        Ok(Self {
+            file_id,
+            file: Bitcask::open_merge_data_file(config, file_id)?,
+            offset: 0,
+            next_id: Box::new(next_id),
+            full_files: Vec::new(),
+            tombstone_bytes: HashMap::new(),
+        })
+    }
+
+    /// Switch to an output under a newly reserved id once the current output reaches
+    /// `max_file_size`
+    fn maybe_rotate(&mut self, config: &Config) -> crate::Result<()> {
+        if self.offset < config.max_file_size() {
+            return Ok(());
+        }
+        let file_id = (self.next_id)()?;
+        let file = Bitcask::open_merge_data_file(config, file_id)?;
+        let full_file = std::mem::replace(&mut self.file, file);
+        self.full_files.push((self.file_id, full_file));
+        self.file_id = file_id;
+        self.offset = 0;
+        Ok(())
+    }
+
+    /// Remove every output, after a failed or cancelled merge.
+    fn discard(self, config: &Config) -> crate::Result<()> {
+        let outputs = self
+            .full_files
+            .into_iter()
+            .chain([(self.file_id, self.file)]);
+        for (file_id, file) in outputs {
+            drop(file);
+            Bitcask::remove_merge_data_file(config, file_id)?;
+        }
+        Ok(())
+    }
+
+    /// Sync every output, leaving them under their temporary names. Returns the output ids.
+    /// The last output is removed if nothing was written to it.
+    fn sync(self, config: &Config) -> crate::Result<Vec<u64>> {
+        let mut file_ids = Vec::with_capacity(self.full_files.len() + 1);
+        let mut outputs = self.full_files;
+        if self.offset > 0 {
+            outputs.push((self.file_id, self.file));
+        } else {
+            drop(self.file);
+            Bitcask::remove_merge_data_file(config, self.file_id)?;
+        }
+        for (file_id, mut file) in outputs {
+            file.flush()?;
+            file.get_ref().sync_all()?;
+            file_ids.push(file_id);
+        }
+        Ok(file_ids)
+    }
+
+    /// Sync every output and give it its final name. Returns the output ids.
+    fn finish(self, config: &Config) -> crate::Result<Vec<u64>> {
+        let file_ids = self.sync(config)?;
+        for &file_id in &file_ids {
+            Bitcask::finish_merge_data_file(config, file_id)?;
+        }
+        Ok(file_ids)
+    }
+}
+
+/// What a repair removes from one data file
+struct FileRepair {
+    file_id: u64,
+    /// Corrupt ranges and the torn tail, sorted by offset
+    removed: Vec<CorruptRange>,
+    /// Valid records of incomplete batches
+    orphaned: Vec<OrphanedRecord>,
+    /// Byte ranges of incomplete batches, sorted by offset
+    dropped: Vec<(u64, u64)>,
+}
+
+/// Progress of a read-only instance through the data files, which a primary
+/// instance may still be writing
+struct TailState {
+    /// Data files loaded into keydir, sorted
+    file_ids: Vec<u64>,
+    /// Offset of the last loaded file to continue reading from
+    offset: u64,
+}
+
+/// A batch whose records are still being read: marker offset, record count and records read
+type PendingBatch = (u64, u64, Vec<OrphanedRecord>);
+
+/// A live record a merge copied: its key, the file and offset it was read from, and
+/// where it was written
+type MovedRecord = (Vec<u8>, (u64, u64), RecordPos);
+
+/// Puts waiting for the next group commit
+#[derive(Default)]
+struct PutQueue {
+    /// Sequence number of the next queued put
+    next_seq: u64,
+    /// Every put below this sequence number was appended or failed
+    committed_seq: u64,
+    /// Set while a caller appends a group
+    appending: bool,
+    records: Vec<RecordData>,
+    /// Errors of the failed puts, taken by their callers
+    failures: HashMap<u64, (ErrorKind, String)>,
 }
 
+/// Number of moved records installed into keydir per write lock
+const INSTALL_BATCH_SIZE: usize = 1024;
+
+/// Name of the file recording a rekey whose outputs are synced, until the data files
+/// written with the old key are deleted
+const REKEY_FILE_NAME: &str = "REKEY";
+
 pub struct Bitcask {
     config: Config,
+    codec: Codec,
+    /// Set when encryption at rest is enabled, replaced by `rekey`
+    cipher: RwLock<Option<Cipher>>,
     keydir: RwKeyDir,
-    active_file: RwLock<BufWriter<File>>,
+    /// `None` for a read-only instance
+    active_file: RwLock<Option<BufWriter<File>>>,
     active_file_id: AtomicU64,
     next_file_id: AtomicU64,
     file_ids: RwLock<Vec<u64>>,
     file_handle_caches: FileHandleCache,
+    /// Set when immutable data files are read through memory maps
+    mapped_files: Option<MappedFiles>,
+    /// Live bytes of each data file, locked after keydir. Not maintained by read-only
+    /// instances, which never merge.
+    usage: Mutex<FileUsage>,
+    /// Reads served by a cached file handle
+    cache_hits: AtomicU64,
+    /// Reads that had to open their data file
+    cache_misses: AtomicU64,
+    /// How far a read-only instance has read the data files, `None` otherwise
+    tail: Option<Mutex<TailState>>,
+    io: DataIo,
+    /// Puts waiting to be appended together, when the I/O backend groups them
+    put_queue: Mutex<PutQueue>,
+    /// Signalled each time a group of puts was appended
+    put_committed: Condvar,
+    /// Released last, once the data files are closed. Read-only instances don't lock.
+    _lock: Option<DirLock>,
 }
 
 impl Bitcask {
     /// Open bitcask storage engine
     pub fn with_config(config: Config) -> crate::Result<Self> {
-        std::fs::create_dir_all(&config.database_path())?;
-
-        let file_ids = Self::get_file_ids(&config)?;
-        let (active_file_id, keydir) = Self::load_existing_files(&config, &file_ids)?;
+        if config.read_only() {
+            return Self::open_read_only(config);
+        }
+        let lock = DirLock::acquire(&config)?;
+        Self::recover_rekey(&config)?;
+
+        let cipher = Cipher::with_config(&config)?;
+        let io = DataIo::with_config(&config);
+        let mapped_files = MappedFiles::with_config(&config)?;
+        let mut file_ids = Self::get_file_ids(&config)?;
+        let (active_file_id, keydir) =
+            Self::load_existing_files(&config, cipher.as_ref(), &io, &file_ids)?;
         let active_file = Self::open_append_data_file(&config, active_file_id)?;
+        // The active file of a new database was only just created
+        if file_ids.last() != Some(&active_file_id) {
+            file_ids.push(active_file_id);
+        }
+        let usage = FileUsage::from_keydir(&keydir.read().expect("Failed to read keydir"));
         let cap = NonZeroUsize::new(config.max_file_handle_caches() as usize)
             .expect("Failed to new lru cap");
         let lru_cache = FileHandleCache::new(LruCache::new(cap));
 
         Ok(Bitcask {
+            codec: Codec::with_config(&config)?,
+            cipher: RwLock::new(cipher),
             config,
             keydir,
-            active_file: RwLock::new(active_file),
+            active_file: RwLock::new(Some(active_file)),
             active_file_id: AtomicU64::new(active_file_id),
             next_file_id: AtomicU64::new(active_file_id + 1),
             file_ids: RwLock::new(file_ids),
+            usage: Mutex::new(usage),
             file_handle_caches: lru_cache,
+            mapped_files,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            tail: None,
+            io,
+            put_queue: Mutex::new(PutQueue::default()),
+            put_committed: Condvar::new(),
+            _lock: Some(lock),
+        })
+    }
+
+    /// Open a read-only instance, for read-only media or as the secondary of a primary
+    /// instance possibly running in another process.
+    ///
+    /// No lock is taken and no file is ever created, written or deleted, writes fail
+    /// with `Error::ReadOnly`. The records a primary writes later are loaded by `catch_up`.
+    pub fn open_read_only(config: Config) -> crate::Result<Self> {
+        let cipher = Cipher::with_config(&config)?;
+        let io = DataIo::with_config(&config);
+        let mapped_files = MappedFiles::with_config(&config)?;
+        let (keydir, tail) = Self::load_read_only(&config, cipher.as_ref(), &io)?;
+        let cap = NonZeroUsize::new(config.max_file_handle_caches() as usize)
+            .expect("Failed to new lru cap");
+        let last_file_id = tail.file_ids.last().copied().unwrap_or(0);
+
+        Ok(Bitcask {
+            codec: Codec::with_config(&config)?,
+            cipher: RwLock::new(cipher),
+            config,
+            keydir: RwLock::new(keydir),
+            active_file: RwLock::new(None),
+            active_file_id: AtomicU64::new(last_file_id),
+            next_file_id: AtomicU64::new(last_file_id + 1),
+            file_ids: RwLock::new(tail.file_ids.clone()),
+            usage: Mutex::new(FileUsage::default()),
+            file_handle_caches: FileHandleCache::new(LruCache::new(cap)),
+            mapped_files,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            tail: Some(Mutex::new(tail)),
+            io,
+            put_queue: Mutex::new(PutQueue::default()),
+            put_committed: Condvar::new(),
+            _lock: None,
         })
     }
 
+    /// Load every data file into a new keydir for a read-only instance.
+    /// Retried while a merge of the primary deletes files under our feet.
+    fn load_read_only(
+        config: &Config,
+        cipher: Option<&Cipher>,
+        io: &DataIo,
+    ) -> crate::Result<(HashMap<Vec<u8>, RecordPos>, TailState)> {
+        const MAX_ATTEMPTS: u32 = 10;
+        let mut attempt = 1;
+        loop {
+            let file_ids = Self::scan_file_ids(config, false)?;
+            let keydir = RwLock::new(HashMap::new());
+            let mut offset = 0;
+            let result = file_ids.iter().try_for_each(|file_id| {
+                offset = Self::process_data_file(config, cipher, io, *file_id, 0, &keydir)?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => {
+                    let keydir = keydir.into_inner().expect("Failed to read keydir");
+                    return Ok((keydir, TailState { file_ids, offset }));
+                }
+                Err(crate::Error::IOError(e))
+                    if e.kind() == ErrorKind::NotFound && attempt < MAX_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Load existing files into memory
-    fn load_existing_files(config: &Config, file_ids: &Vec<u64>) -> crate::Result<(u64, RwKeyDir)> {
+    fn load_existing_files(
+        config: &Config,
+        cipher: Option<&Cipher>,
+        io: &DataIo,
+        file_ids: &Vec<u64>,
+    ) -> crate::Result<(u64, RwKeyDir)> {
         if file_ids.is_empty() {
             return Ok((0, RwLock::new(HashMap::new())));
         }
 
         let keydir = RwLock::new(HashMap::new());
         for file_id in file_ids {
-            Self::process_data_file(config, *file_id, &keydir)?;
+            Self::process_data_file(config, cipher, io, *file_id, 0, &keydir)?;
         }
 
         // Every time it is opened, a new active file is generated
@@ -146,233 +618,573 @@ impl Bitcask {
         Ok((next_file_id, keydir))
     }
 
-    /// Process a single data file and populate keydir
-    fn process_data_file(config: &Config, file_id: u64, keydir: &RwKeyDir) -> crate::Result<()> {
+    /// Process a single data file from `start_offset` and populate keydir.
+    /// Returns the offset to continue from once more records were appended, which is
+    /// the start of the last batch if the file ends before all of its records.
+    fn process_data_file(
+        config: &Config,
+        cipher: Option<&Cipher>,
+        io: &DataIo,
+        file_id: u64,
+        start_offset: u64,
+        keydir: &RwKeyDir,
+    ) -> crate::Result<u64> {
         let mut file = Self::open_read_only_data_file(config, file_id)?;
-        let mut offset = 0;
+        io.with_reader(&mut file, LOAD_READ_AHEAD, |file| {
+            Self::process_records(config, cipher, file, file_id, start_offset, keydir)
+        })
+    }
+
+    /// Read the records of data file `file_id` from `start_offset` into keydir,
+    /// see `process_data_file`
+    fn process_records(
+        config: &Config,
+        cipher: Option<&Cipher>,
+        file: &mut dyn ReadSeek,
+        file_id: u64,
+        start_offset: u64,
+        keydir: &RwKeyDir,
+    ) -> crate::Result<u64> {
+        let mut offset = start_offset;
+        let mut batch_start_pos = offset;
 
         let mut keydir = keydir.write().expect("Failed to write keydir");
-        while let Some(record_result) =
-            Self::read_next_record(&mut file, offset, config.strict_crc_validation())?
-        {
+        // Records of a batch are only applied once all of them have been read
+        let mut pending_batch: Option<(u64, Vec<(RecordData, u64)>)> = None;
+        let limits = SizeLimits::with_config(config);
+        while let Some(record_result) = Self::read_next_record(
+            file,
+            file_id,
+            offset,
+            limits,
+            config.strict_crc_validation(),
+            cipher,
+        )? {
             match record_result {
                 Ok((record, record_start_pos)) => {
-                    if record.is_tombstone() {
-                        keydir.remove(&record.key);
+                    offset = record_start_pos + record.total_size();
+                    if let Some(count) = record.batch_count() {
+                        // A new marker while a batch is pending means the pending one was torn
+                        pending_batch = None;
+                        match count {
+                            Ok(count) => {
+                                pending_batch = Some((count, RecordData::batch_records(count)));
+                                batch_start_pos = record_start_pos;
+                            }
+                            Err(e) => diagnostics::report(
+                                config.error_handler(),
+                                ErrorEvent::new(ErrorSource::Load, &e)
+                                    .at(file_id, Some(record_start_pos)),
+                            ),
+                        }
+                    } else if let Some((count, records)) = pending_batch.as_mut() {
+                        records.push((record, record_start_pos));
+                        if records.len() as u64 >= *count {
+                            for (record, record_start_pos) in records.drain(..) {
+                                Self::apply_record(&mut keydir, file_id, record, record_start_pos);
+                            }
+                            pending_batch = None;
+                        }
                     } else {
-                        let record_pos = RecordPos {
-                            file_id,
-                            value_size: record.value_size,
-                            value_pos: record_start_pos + RecordData::HEADER_SIZE + record.key_size,
-                            timestamp: record.timestamp,
-                        };
-                        keydir.insert(record.key.to_vec(), record_pos);
+                        Self::apply_record(&mut keydir, file_id, record, record_start_pos);
                     }
-                    offset = record_start_pos + record.total_size();
                 }
                 Err(skip_size) => {
+                    diagnostics::report(
+                        config.error_handler(),
+                        ErrorEvent::new(ErrorSource::Load, &crate::Error::CorruptedData)
+                            .at(file_id, Some(offset)),
+                    );
                     offset += skip_size;
+                    // A corrupted record invalidates the whole batch it belongs to
+                    pending_batch = None;
                 }
             }
             file.seek(SeekFrom::Start(offset))?;
         }
 
-        Ok(())
+        Ok(if pending_batch.is_some() {
+            batch_start_pos
+        } else {
+            offset
+        })
+    }
+
+    /// Apply a record read from a data file to keydir
+    fn apply_record(
+        keydir: &mut HashMap<Vec<u8>, RecordPos>,
+        file_id: u64,
+        record: RecordData,
+        record_start_pos: u64,
+    ) {
+        if record.is_tombstone() {
+            keydir.remove(&record.key);
+        } else {
+            let record_pos = record.pos(file_id, record_start_pos);
+            keydir.insert(record.key, record_pos);
+        }
     }
 
     /// Read the next record from file, returning either the record or skip size on CRC failure
-    fn read_next_record(
-        file: &mut BufReader<File>,
+    fn read_next_record<R: Read + Seek + ?Sized>(
+        file: &mut R,
+        file_id: u64,
         start_offset: u64,
+        limits: SizeLimits,
         strict_crc: bool,
+        cipher: Option<&Cipher>,
     ) -> crate::Result<Option<Result<(RecordData, u64), u64>>> {
-        file.seek(SeekFrom::Start(start_offset))?;
-
         let record_start_pos = start_offset;
-        let stored_crc = match file.read_u32::<BE>() {
-            Ok(crc) => crc,
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
-
-        let record = match Self::read_record_data(file) {
-            Ok(record) => record,
-            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(e.into()),
-        };
+        let (stored_crc, mut record) =
+            match Self::read_raw_record(file, file_id, start_offset, limits)? {
+                Some(raw) => raw,
+                None => return Ok(None),
+            };
 
         // Check CRC
         if stored_crc != record.crc {
+            metrics::crc_failure("bitcask");
             if strict_crc {
                 return Err(crate::Error::CorruptedData);
             }
-
-            eprintln!(
-                "CRC check failed for record at offset {}, expected: {}, got: {}",
-                record_start_pos, stored_crc, record.crc
-            );
             return Ok(Some(Err(record.total_size())));
         }
 
+        // The CRC matched, so a record that fails to decrypt was sealed with another key
+        record.decrypt(cipher)?;
         Ok(Some(Ok((record, record_start_pos))))
     }
 
-    /// Compact existing files into a new file
-    fn merge_existing_files(&self) -> crate::Result<()> {
-        // Get old file IDs (excluding active file)
-        let active_file_id = self.active_file_id.load(Ordering::Relaxed);
-        let old_file_ids: Vec<u64> = Self::get_file_ids(&self.config)?
-            .into_iter()
-            .filter(|&id| id != active_file_id)
-            .collect();
+    /// Read the record at `start_offset` with its stored CRC, without checking or decrypting it.
+    /// Returns `None` at the end of the file, or if the file ends in the middle of the record.
+    fn read_raw_record<R: Read + Seek + ?Sized>(
+        file: &mut R,
+        file_id: u64,
+        start_offset: u64,
+        limits: SizeLimits,
+    ) -> crate::Result<Option<(u32, RecordData)>> {
+        file.seek(SeekFrom::Start(start_offset))?;
+
+        let stored_crc = match file.read_u32::<BE>() {
+            Ok(crc) => crc,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        match Self::read_record_data(file, limits) {
+            Ok(record) => Ok(Some((stored_crc, record))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(crate::Error::InvalidData(message)) => Err(crate::Error::InvalidData(format!(
+                "Record at offset {} of data file {}: {}",
+                start_offset, file_id, message
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Compact `requested` files, or the files picked by the merge policy, into new files
+    /// split at `max_file_size`.
+    ///
+    /// Each output id is reserved as the output is opened, moving the active file past it,
+    /// and records are only checked for liveness once their output is reserved. A record
+    /// written during the merge either made the copy dead, or sorts after the output when
+    /// the files are loaded again. Tombstones are kept while an older file left out of
+    /// the merge may still hold a put of their key.
+    ///
+    /// Writers are never blocked for long: liveness is checked one record at a time, and
+    /// the new positions are installed in small batches, each only replacing a position
+    /// that still points to the record that was copied.
+    fn merge_existing_files(
+        &self,
+        requested: Option<&[u64]>,
+        control: &MergeControl,
+    ) -> crate::Result<()> {
+        let historical_ids = self.historical_file_ids()?;
+        let input_ids = match requested {
+            Some(file_ids) => Self::check_merge_files(&historical_ids, file_ids)?,
+            None => self.select_merge_files(&historical_ids, true)?,
+        };
 
         // Skip if empty
-        if old_file_ids.is_empty() {
+        if input_ids.is_empty() {
             return Ok(());
         }
+        let oldest_unmerged = historical_ids
+            .iter()
+            .copied()
+            .find(|file_id| input_ids.binary_search(file_id).is_err());
 
-        // Merge files
-        let merge_file_id = self.next_file_id.load(Ordering::Relaxed);
-        self.next_file_id
-            .store(merge_file_id + 1, Ordering::Relaxed);
-
-        let mut merge_file = Self::open_merge_data_file(&self.config, merge_file_id)?;
-        let mut new_file_offset = 0;
+        let input_bytes = self
+            .file_garbage(&input_ids)?
+            .iter()
+            .map(|(_, total_bytes, _)| total_bytes)
+            .sum::<u64>();
+        control.begin(input_ids.len() as u64, input_bytes);
 
-        let merge_keydir = Self::merge_data_files(
+        let mut output = MergeOutput::create(&self.config, || self.reserve_merge_output_id())?;
+        let moved = match Self::merge_data_files(
             &self.config,
-            &old_file_ids,
+            &self.codec,
+            &self.cipher,
+            &input_ids,
+            oldest_unmerged,
             &self.keydir,
-            merge_file_id,
-            &mut merge_file,
-            &mut new_file_offset,
-        )?;
+            &mut output,
+            control,
+        ) {
+            Ok(moved) => moved,
+            Err(e) => {
+                // Nothing points to the outputs yet, the inputs stay as they were
+                output.discard(&self.config)?;
+                return Err(e);
+            }
+        };
 
-        // Finish merge data
-        merge_file.flush()?;
-        Self::finish_merge_data_file(&self.config, merge_file_id)?;
+        // Finish merge data, the outputs are listed before keydir points to them
+        let tombstone_bytes = std::mem::take(&mut output.tombstone_bytes);
+        let output_ids = output.finish(&self.config)?;
+        self.update_file_ids(|file_ids| file_ids.extend(&output_ids))?;
+        let mut usage = self.lock_usage()?;
+        for (file_id, bytes) in tombstone_bytes {
+            usage.add(file_id, bytes);
+        }
+        drop(usage);
+
+        // Point keydir to the copies a batch at a time, so writers only wait for short
+        // steps. Keys written or deleted during the merge no longer point to the record
+        // that was copied, and keep their new value.
+        let mut moved = moved.into_iter().peekable();
+        while moved.peek().is_some() {
+            let mut keydir = self.keydir.write().expect("Failed to write keydir");
+            let mut usage = self.lock_usage()?;
+            let batch = moved.by_ref().take(INSTALL_BATCH_SIZE);
+            for (key, (file_id, record_start_pos), pos) in batch {
+                let key_size = key.len() as u64;
+                if let Some(current) = keydir.get_mut(&key)
+                    && current.file_id == file_id
+                    && current.record_start_pos(key_size) == record_start_pos
+                {
+                    usage.replace(key_size, Some(&pos), Some(current));
+                    *current = pos;
+                }
+            }
+        }
 
-        // Update keydir and delete old files
-        let mut keydir = self.keydir.write().expect("Failed to write keydir");
-        for (key, pos) in merge_keydir {
-            keydir.insert(key, pos);
+        // Nothing points to the old files anymore, and readers release keydir only once
+        // they are done reading
+        let mut usage = self.lock_usage()?;
+        for &file_id in &input_ids {
+            usage.remove(file_id);
         }
-        self.delete_data_files(&old_file_ids)?;
+        drop(usage);
+        self.delete_data_files(&input_ids)?;
+        self.update_file_ids(|file_ids| {
+            file_ids.retain(|file_id| input_ids.binary_search(file_id).is_err())
+        })
+    }
 
-        // Refresh file_ids
+    /// Change the list of data files with `f`, keeping it sorted. Unlike a directory
+    /// scan, this never races with a concurrent rotation.
+    fn update_file_ids(&self, f: impl FnOnce(&mut Vec<u64>)) -> crate::Result<()> {
         let mut file_ids = self
             .file_ids
             .write()
             .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))?;
-        *file_ids = Self::get_file_ids(&self.config)?;
-
+        f(&mut file_ids);
+        file_ids.sort_unstable();
         Ok(())
     }
 
-    /// Merge multiple data files into one
+    /// Reserve the id of the next merge output. The active file moves past it, so any
+    /// record written from now on, which a copy made into the output can't know about,
+    /// sorts after the output.
+    fn reserve_merge_output_id(&self) -> crate::Result<u64> {
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        let file_id = self.next_file_id.load(Ordering::Relaxed);
+        self.rotate_active_file(active_file, file_id + 1)?;
+        Ok(file_id)
+    }
+
+    /// Get the ids of the data files other than the active one, sorted
+    fn historical_file_ids(&self) -> crate::Result<Vec<u64>> {
+        let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+        Ok(self
+            .file_ids
+            .read()
+            .map_err(|_| crate::Error::PoisonError("Failed to read file_ids".to_string()))?
+            .iter()
+            .copied()
+            .filter(|&id| id != active_file_id)
+            .collect())
+    }
+
+    /// Check that the files a caller asked to merge are historical files.
+    /// Returns their ids sorted and deduplicated.
+    fn check_merge_files(historical_ids: &[u64], file_ids: &[u64]) -> crate::Result<Vec<u64>> {
+        let mut file_ids = file_ids.to_vec();
+        file_ids.sort_unstable();
+        file_ids.dedup();
+        if let Some(file_id) = file_ids
+            .iter()
+            .find(|file_id| historical_ids.binary_search(file_id).is_err())
+        {
+            return Err(crate::Error::InvalidData(format!(
+                "Data file {} is not a historical data file",
+                file_id
+            )));
+        }
+        Ok(file_ids)
+    }
+
+    /// Pick the historical files to merge, sorted.
+    ///
+    /// All of them once `max_historical_files` is reached. Otherwise the files whose dead
+    /// ratio reaches `max_dead_ratio`, and once the dead bytes of all files reach
+    /// `max_dead_bytes`, the files with the most dead bytes until that much is reclaimed.
+    /// With `fallback`, all of them if the policy picks none.
+    fn select_merge_files(
+        &self,
+        historical_ids: &[u64],
+        fallback: bool,
+    ) -> crate::Result<Vec<u64>> {
+        let policy = self.config.merge_policy();
+        if historical_ids.len() >= self.config.max_historical_files() as usize {
+            return Ok(historical_ids.to_vec());
+        }
+        if policy.max_dead_ratio.is_none() && policy.max_dead_bytes.is_none() {
+            return Ok(if fallback {
+                historical_ids.to_vec()
+            } else {
+                Vec::new()
+            });
+        }
+
+        let mut files = self.file_garbage(historical_ids)?;
+        let total_dead_bytes = files
+            .iter()
+            .map(|(_, _, dead_bytes)| dead_bytes)
+            .sum::<u64>();
+        let mut bytes_to_reclaim = policy
+            .max_dead_bytes
+            .filter(|max_bytes| total_dead_bytes >= *max_bytes)
+            .unwrap_or(0);
+        files.sort_by_key(|&(_, _, dead_bytes)| std::cmp::Reverse(dead_bytes));
+
+        let mut selected = Vec::new();
+        for (file_id, total_bytes, dead_bytes) in files {
+            let over_ratio = policy.max_dead_ratio.is_some_and(|max_ratio| {
+                total_bytes > 0 && dead_bytes as f64 / total_bytes as f64 >= max_ratio
+            });
+            if over_ratio || (bytes_to_reclaim > 0 && dead_bytes > 0) {
+                selected.push(file_id);
+                bytes_to_reclaim = bytes_to_reclaim.saturating_sub(dead_bytes);
+            }
+        }
+        if selected.is_empty() && fallback {
+            return Ok(historical_ids.to_vec());
+        }
+        selected.sort_unstable();
+        Ok(selected)
+    }
+
+    /// Get the id, total bytes and dead bytes of each of the data files `file_ids`.
+    /// Files a merge deletes meanwhile are skipped.
+    fn file_garbage(&self, file_ids: &[u64]) -> crate::Result<Vec<(u64, u64, u64)>> {
+        let usage = self.lock_usage()?;
+        let mut files = Vec::with_capacity(file_ids.len());
+        for &file_id in file_ids {
+            let file_path = self
+                .config
+                .database_path()
+                .join(Self::get_file_name(&self.config, file_id));
+            let total_bytes = match std::fs::metadata(file_path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let dead_bytes = total_bytes.saturating_sub(usage.live_bytes(file_id));
+            files.push((file_id, total_bytes, dead_bytes));
+        }
+        Ok(files)
+    }
+
+    /// Copy the live records of multiple data files to `output`, without blocking writers.
+    /// Tombstones of deleted keys are copied from the files newer than `oldest_unmerged`,
+    /// the oldest historical file left out of the merge.
+    #[allow(clippy::too_many_arguments)]
     fn merge_data_files(
         config: &Config,
+        codec: &Codec,
+        cipher: &RwLock<Option<Cipher>>,
         old_file_ids: &[u64],
+        oldest_unmerged: Option<u64>,
         keydir: &RwKeyDir,
-        merge_file_id: u64,
-        merge_file: &mut BufWriter<File>,
-        new_file_offset: &mut u64,
-    ) -> crate::Result<HashMap<Vec<u8>, RecordPos>> {
-        let mut merge_keydir = HashMap::new();
+        output: &mut MergeOutput,
+        control: &MergeControl,
+    ) -> crate::Result<Vec<MovedRecord>> {
+        let mut moved = Vec::new();
 
         for &old_file_id in old_file_ids {
+            let cipher = cipher.read().expect("Failed to read cipher");
+            let keep_tombstones = oldest_unmerged.is_some_and(|file_id| file_id < old_file_id);
+            // Writers only wait for one lookup at a time, not for a whole file
+            let is_live = |record: &RecordData, record_start_pos: u64| {
+                let keydir = keydir.read().expect("Failed to read keydir");
+                if record.is_tombstone() {
+                    keep_tombstones && !keydir.contains_key(&record.key)
+                } else {
+                    Self::is_live_record(&keydir, old_file_id, &record.key, record_start_pos)
+                }
+            };
             Self::merge_single_file(
                 config,
+                codec,
                 old_file_id,
-                keydir,
-                merge_file_id,
-                merge_file,
-                new_file_offset,
-                &mut merge_keydir,
+                is_live,
+                output,
+                &mut moved,
+                cipher.as_ref(),
+                cipher.as_ref(),
+                control,
             )?;
         }
 
-        Ok(merge_keydir)
+        Ok(moved)
+    }
+
+    /// Check if keydir points to the record of `key` at `record_start_pos` in `file_id`.
+    /// Only that record is live, whatever the file order.
+    fn is_live_record(
+        keydir: &HashMap<Vec<u8>, RecordPos>,
+        file_id: u64,
+        key: &[u8],
+        record_start_pos: u64,
+    ) -> bool {
+        keydir.get(key).is_some_and(|pos| {
+            pos.file_id == file_id && pos.record_start_pos(key.len() as u64) == record_start_pos
+        })
     }
 
-    /// Merge a single data file
+    /// Merge a single data file, reading records with `read_cipher` and writing them
+    /// with `write_cipher`. Records are only checked with `is_live` once the output they
+    /// go to is open. Stops with `Error::MergeCancelled` once `control` asks to.
+    #[allow(clippy::too_many_arguments)]
     fn merge_single_file(
         config: &Config,
+        codec: &Codec,
         old_file_id: u64,
-        keydir: &RwKeyDir,
-        merge_file_id: u64,
-        merge_file: &mut BufWriter<File>,
-        new_file_offset: &mut u64,
-        merge_keydir: &mut HashMap<Vec<u8>, RecordPos>,
+        is_live: impl Fn(&RecordData, u64) -> bool,
+        output: &mut MergeOutput,
+        moved: &mut Vec<MovedRecord>,
+        read_cipher: Option<&Cipher>,
+        write_cipher: Option<&Cipher>,
+        control: &MergeControl,
     ) -> crate::Result<()> {
         let mut file = Self::open_read_only_data_file(config, old_file_id)?;
         let mut old_file_offset = 0;
 
-        let keydir = keydir.read().expect("Failed to read keydir");
-        while let Some(record_result) =
-            Self::read_next_record(&mut file, old_file_offset, config.strict_crc_validation())?
-        {
+        let limits = SizeLimits::with_config(config);
+        while let Some(record_result) = Self::read_next_record(
+            &mut file,
+            old_file_id,
+            old_file_offset,
+            limits,
+            config.strict_crc_validation(),
+            read_cipher,
+        )? {
             match record_result {
                 Ok((record, record_start_pos)) => {
                     let total_size = record.total_size();
-                    let should_merge_record = match keydir.get(&record.key) {
-                        None => false,
-                        Some(memory_record_pos) => {
-                            memory_record_pos.file_id == old_file_id
-                                && memory_record_pos.value_pos
-                                    == record_start_pos + RecordData::HEADER_SIZE + record.key_size
-                        }
-                    };
-                    if should_merge_record {
-                        file.seek(SeekFrom::Start(record_start_pos))?;
-
-                        let mut record_bytes = vec![0; total_size as usize];
-                        file.read_exact(&mut record_bytes)?;
-
-                        let bytes_written =
-                            merge_file.write_all(&record_bytes).map(|_| total_size)?;
-
-                        let new_record_pos = RecordPos {
-                            file_id: merge_file_id, // Note: This should point to the merged new file ID
-                            value_size: record.value_size,
-                            value_pos: *new_file_offset + RecordData::HEADER_SIZE + record.key_size,
-                            timestamp: record.timestamp,
-                        };
-
-                        merge_keydir.insert(record.key, new_record_pos);
-                        *new_file_offset += bytes_written;
-                    } else {
-                        merge_keydir.remove(&record.key);
+                    let mut bytes_written = 0;
+                    output.maybe_rotate(config)?;
+                    let live = is_live(&record, record_start_pos);
+                    if live && record.is_tombstone() {
+                        let mut tombstone = RecordData::tombstone(record.key);
+                        tombstone.timestamp = record.timestamp;
+                        let record_bytes = tombstone.encode(write_cipher)?;
+                        output.file.write_all(&record_bytes)?;
+                        output.offset += record_bytes.len() as u64;
+                        bytes_written = record_bytes.len() as u64;
+                        *output.tombstone_bytes.entry(output.file_id).or_default() += bytes_written;
+                    } else if live {
+                        // Re-encode the value, so it is recompressed with the current codec
+                        let value = codec.decode(record.flags, record.value)?;
+                        let mut merged = RecordData::encoded(codec, record.key, &value)?;
+                        merged.timestamp = record.timestamp;
+
+                        let record_bytes = merged.encode(write_cipher)?;
+                        output.file.write_all(&record_bytes)?;
+
+                        // Note: This should point to the merged new file ID
+                        let new_record_pos = merged.pos(output.file_id, output.offset);
+                        moved.push((merged.key, (old_file_id, record_start_pos), new_record_pos));
+                        output.offset += record_bytes.len() as u64;
+                        bytes_written = record_bytes.len() as u64;
                     }
 
                     old_file_offset = record_start_pos + total_size;
+                    if !control.consume(total_size, bytes_written) {
+                        return Err(crate::Error::MergeCancelled);
+                    }
                 }
                 Err(skip_size) => {
+                    diagnostics::report(
+                        config.error_handler(),
+                        ErrorEvent::new(ErrorSource::Merge, &crate::Error::CorruptedData)
+                            .at(old_file_id, Some(old_file_offset)),
+                    );
                     old_file_offset += skip_size;
                 }
             }
         }
-        merge_file.flush()?;
+        output.file.flush()?;
+        control.file_done();
         Ok(())
     }
 
-    /// Read record data from file (after CRC)
-    fn read_record_data(file: &mut BufReader<File>) -> crate::Result<RecordData> {
+    /// Read record data from file (after CRC), rejecting sizes above `limits`
+    fn read_record_data<R: Read + ?Sized>(
+        file: &mut R,
+        limits: SizeLimits,
+    ) -> crate::Result<RecordData> {
         let timestamp = file.read_u64::<BE>()?;
         let key_size = file.read_u64::<BE>()?;
-        let value_size = file.read_u64::<BE>()?;
+        let raw_value_size = file.read_u64::<BE>()?;
+        let value_size = raw_value_size & RecordData::VALUE_SIZE_MASK;
+        let flags = (raw_value_size >> 56) as u8;
+
+        if key_size > limits.key {
+            return Err(crate::Error::InvalidData(format!(
+                "Key size {} exceeds the limit of {} bytes",
+                key_size, limits.key
+            )));
+        }
+        if value_size > limits.value {
+            return Err(crate::Error::InvalidData(format!(
+                "Value size {} exceeds the limit of {} bytes",
+                value_size, limits.value
+            )));
+        }
 
-        let mut key_buff = vec![0; key_size as usize];
-        file.read_exact(&mut key_buff)?;
+        let mut nonce = Vec::new();
+        if flags & FLAG_ENCRYPTED != 0 {
+            nonce.resize(NONCE_SIZE as usize, 0);
+            file.read_exact(&mut nonce)?;
+        }
 
-        let mut value_buf = vec![0; value_size as usize];
-        file.read_exact(&mut value_buf)?;
+        let key_buff = Self::read_sized(file, key_size)?;
+        let value_buf = Self::read_sized(file, value_size)?;
 
         // Calculate CRC
         let mut hasher = Hasher::new();
         hasher.update(&timestamp.to_be_bytes());
         hasher.update(&key_size.to_be_bytes());
-        hasher.update(&value_size.to_be_bytes());
+        hasher.update(&raw_value_size.to_be_bytes());
+        hasher.update(&nonce);
         hasher.update(&key_buff);
         hasher.update(&value_buf);
         let computed_crc = hasher.finalize();
@@ -382,17 +1194,35 @@ impl Bitcask {
             timestamp,
             key_size,
             value_size,
+            flags,
+            nonce,
             key: key_buff,
             value: value_buf,
         })
     }
 
+    /// Read exactly `size` bytes. The buffer grows with the data actually read, so a
+    /// corrupted size field can't trigger a huge allocation.
+    fn read_sized<R: Read + ?Sized>(file: &mut R, size: u64) -> crate::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        Read::take(&mut *file, size).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < size {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buf)
+    }
+
     /// Get all data file IDs in the data directory
     fn get_file_ids(config: &Config) -> crate::Result<Vec<u64>> {
+        Self::scan_file_ids(config, true)
+    }
+
+    /// Get all data file IDs in the data directory, optionally removing leftover merge files
+    fn scan_file_ids(config: &Config, remove_merge_files: bool) -> crate::Result<Vec<u64>> {
         let mut file_ids = Vec::new();
         let extension = config.store_model().extension();
 
-        for entry in std::fs::read_dir(&config.database_path())? {
+        for entry in std::fs::read_dir(config.database_path())? {
             let path = entry?.path();
 
             if path.is_dir() {
@@ -400,24 +1230,23 @@ impl Bitcask {
             }
 
             // Clear `.merge` file, they may be invalid files remaining from the previous failed merge, so you can safely delete them.
-            if path.extension().map_or(false, |ext| ext == "merge") {
-                std::fs::remove_file(path)?;
+            if path.extension().is_some_and(|ext| ext == "merge") {
+                if remove_merge_files {
+                    std::fs::remove_file(path)?;
+                }
                 continue;
             }
 
             // Check file extension
-            if path
-                .extension()
-                .map_or(true, |ext| ext != extension.as_str())
-            {
+            if path.extension().is_none_or(|ext| ext != extension.as_str()) {
                 continue;
             }
 
             // Extract file ID from filename
-            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                if let Ok(file_id) = stem.parse::<u64>() {
-                    file_ids.push(file_id);
-                }
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+                && let Ok(file_id) = stem.parse::<u64>()
+            {
+                file_ids.push(file_id);
             }
         }
 
@@ -472,131 +1301,526 @@ impl Bitcask {
         Ok(())
     }
 
+    /// Remove a merge file that won't be finished
+    fn remove_merge_data_file(config: &Config, file_id: u64) -> crate::Result<()> {
+        let file_name = Self::get_file_name(config, file_id);
+        std::fs::remove_file(config.database_path().join(format!("{}.merge", file_name)))?;
+        Ok(())
+    }
+
     /// Delete multiple data files
     fn delete_data_files(&self, file_ids: &[u64]) -> crate::Result<()> {
+        if let Some(mapped_files) = &self.mapped_files {
+            mapped_files.forget(file_ids)?;
+        }
         for &file_id in file_ids {
             Self::delete_data_file(&self.config, file_id)?;
         }
         Ok(())
     }
 
+    /// Lock the live bytes of the data files, after keydir if both are needed
+    fn lock_usage(&self) -> crate::Result<std::sync::MutexGuard<'_, FileUsage>> {
+        self.usage
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock file usage".to_string()))
+    }
+
     /// Delete a single data file
     fn delete_data_file(config: &Config, file_id: u64) -> crate::Result<()> {
         let file_path = config
             .database_path()
             .join(Self::get_file_name(config, file_id));
         if let Err(e) = std::fs::remove_file(&file_path) {
-            eprintln!("Failed to delete file {}: {}", file_path.display(), e);
+            diagnostics::report(
+                config.error_handler(),
+                ErrorEvent::new(ErrorSource::DeleteFile, &e.into()).at(file_id, None),
+            );
         }
         Ok(())
     }
 
     /// Internal get method
     fn get_internal(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        Ok(self.get_ref_internal(key)?.map(ValueRef::into_vec))
+    }
+
+    /// Internal get_ref method. Records of immutable data files are read from their map
+    /// when memory-mapped reads are enabled, other records through a cached file handle.
+    fn get_ref_internal(&self, key: &[u8]) -> crate::Result<Option<ValueRef>> {
         let keydir = self.keydir.read().expect("Failed to read keydir");
         let record_pos = match keydir.get(key) {
             Some(pos) => pos,
             None => return Ok(None),
         };
 
+        #[cfg(feature = "mmap")]
+        if let Some(map) = self.mapped_file(record_pos)? {
+            return self.read_mapped(map, key, record_pos);
+        }
+
+        let mut value = Vec::new();
+        let found = self.read_value(key, record_pos, &mut value)?;
+        Ok(found.then(|| value.into()))
+    }
+
+    /// Internal get_into method
+    fn get_into_internal(&self, key: &[u8], buf: &mut Vec<u8>) -> crate::Result<bool> {
+        buf.clear();
+        let keydir = self.keydir.read().expect("Failed to read keydir");
+        let record_pos = match keydir.get(key) {
+            Some(pos) => pos,
+            None => return Ok(false),
+        };
+
+        #[cfg(feature = "mmap")]
+        if let Some(map) = self.mapped_file(record_pos)? {
+            let value = self.read_mapped(map, key, record_pos)?;
+            buf.extend_from_slice(value.as_deref().unwrap_or_default());
+            return Ok(value.is_some());
+        }
+
+        self.read_value(key, record_pos, buf)
+    }
+
+    /// Get the map of the data file of `record_pos`, if memory-mapped reads are enabled
+    /// and the file is immutable
+    #[cfg(feature = "mmap")]
+    fn mapped_file(
+        &self,
+        record_pos: &RecordPos,
+    ) -> crate::Result<Option<std::sync::Arc<memmap2::Mmap>>> {
+        let Some(mapped_files) = &self.mapped_files else {
+            return Ok(None);
+        };
+        // Only the active file is still appended to
+        if record_pos.file_id >= self.active_file_id.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let file_name = Self::get_file_name(&self.config, record_pos.file_id);
+        let path = self.config.database_path().join(file_name);
+        mapped_files.get(record_pos.file_id, path).map(Some)
+    }
+
+    /// Read the value of `key` at `record_pos` into `buf` through a cached file handle.
+    /// The whole record is read into `buf` at once, so a raw value is moved into place
+    /// without any allocation once `buf` is large enough. Returns `false` if the file ends
+    /// before the record.
+    fn read_value(
+        &self,
+        key: &[u8],
+        record_pos: &RecordPos,
+        buf: &mut Vec<u8>,
+    ) -> crate::Result<bool> {
         let file_id = record_pos.file_id;
         let mut cache = self
             .file_handle_caches
             .lock()
             .map_err(|_| crate::Error::PoisonError("Failed to lock file cache".to_string()))?;
+        if cache.contains(&file_id) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
 
-        let mut file = cache.get_or_insert_mut(file_id, || {
+        // The file may have been merged away by the primary of a read-only instance
+        let file = cache.try_get_or_insert_mut(file_id, || {
             Self::open_read_only_data_file(&self.config, file_id)
-                .expect(&format!("Failed to open data file id: {}", file_id))
+        })?;
+
+        let start_offset = record_pos.record_start_pos(key.len() as u64);
+        let record_size = record_pos.record_size(key.len() as u64) as usize;
+        buf.clear();
+        buf.resize(record_size, 0);
+        let read = self.io.with_reader(file, record_size, |file| {
+            file.seek(SeekFrom::Start(start_offset))?;
+            file.read_exact(buf)
         });
+        drop(cache);
+        match read {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                buf.clear();
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if record_pos.flags & (CODEC_MASK | FLAG_ENCRYPTED) == CODEC_NONE {
+            Self::check_record_crc(buf)?;
+            let value_start = (record_pos.value_pos - start_offset) as usize;
+            buf.copy_within(value_start.., 0);
+            buf.truncate(record_size - value_start);
+            return Ok(true);
+        }
+
+        let value = self.decode_record(buf, 0, file_id)?;
+        buf.clear();
+        buf.extend_from_slice(value.as_deref().unwrap_or_default());
+        Ok(value.is_some())
+    }
+
+    /// Check the stored CRC of the whole `record`, in its on-disk layout
+    fn check_record_crc(record: &[u8]) -> crate::Result<()> {
+        let stored_crc = u32::from_be_bytes(record[..4].try_into().expect("Record too short"));
+        if crc32fast::hash(&record[4..]) != stored_crc {
+            metrics::crc_failure("bitcask");
+            return Err(crate::Error::CorruptedData);
+        }
+        Ok(())
+    }
 
-        let start_offset = record_pos.value_pos - RecordData::HEADER_SIZE - key.len() as u64;
-        let next_record = Self::read_next_record(&mut file, start_offset, true)?;
+    /// Parse, check and decode the record at `start` of `bytes`, read from data file
+    /// `file_id`. Returns `None` if `bytes` end before the record.
+    fn decode_record(
+        &self,
+        bytes: &[u8],
+        start: u64,
+        file_id: u64,
+    ) -> crate::Result<Option<Vec<u8>>> {
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+        let next_record = Self::read_next_record(
+            &mut std::io::Cursor::new(bytes),
+            file_id,
+            start,
+            SizeLimits::with_config(&self.config),
+            true,
+            cipher.as_ref(),
+        )?;
         match next_record {
-            Some(next_record) => Ok(next_record.map_or(None, |data| Some(data.0.value))),
-            None => Ok(None),
+            Some(Ok((record, _))) => Ok(Some(self.codec.decode(record.flags, record.value)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the record of `key` at `record_pos` from the map of its data file. A raw value
+    /// is lent in place once the checksum of its record matches, others are decoded.
+    #[cfg(feature = "mmap")]
+    fn read_mapped(
+        &self,
+        map: std::sync::Arc<memmap2::Mmap>,
+        key: &[u8],
+        record_pos: &RecordPos,
+    ) -> crate::Result<Option<ValueRef>> {
+        let key_size = key.len() as u64;
+        let start = record_pos.record_start_pos(key_size) as usize;
+        let end = start + record_pos.record_size(key_size) as usize;
+        let Some(record) = map.get(start..end) else {
+            return Ok(None);
+        };
+
+        if record_pos.flags & (CODEC_MASK | FLAG_ENCRYPTED) == CODEC_NONE {
+            Self::check_record_crc(record)?;
+            return Ok(Some(ValueRef::mapped(
+                map,
+                record_pos.value_pos as usize..end,
+            )));
+        }
+
+        let value = self.decode_record(&map[..], start as u64, record_pos.file_id)?;
+        Ok(value.map(ValueRef::from))
+    }
+
+    /// Drop the maps of every data file, before they are deleted or replaced
+    fn clear_mapped_files(&self) -> crate::Result<()> {
+        match &self.mapped_files {
+            Some(mapped_files) => mapped_files.clear(),
+            None => Ok(()),
         }
     }
 
+    /// Reject the keys reserved for internal records, such as batch markers
+    fn check_key(key: &[u8]) -> crate::Result<()> {
+        if key.first() == Some(&0xFF) {
+            return Err(crate::Error::InvalidData(
+                "Keys starting with 0xFF are reserved for internal records".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     /// Internal put method
     fn put_internal(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        Self::check_key(key)?;
+        if self.io.groups_appends() {
+            return self.put_grouped(key, value);
+        }
+
         // Check if file rotation is needed
         let mut active_file = self
             .active_file
             .write()
             .expect("Failed to write active file");
-        self.maybe_rotate_file(&mut active_file)?;
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        self.maybe_rotate_file(active_file)?;
 
-        let record = RecordData::new(key.to_vec(), value.to_vec());
+        let mut record = RecordData::encoded(&self.codec, key.to_vec(), value)?;
         let record_start_pos = active_file.seek(SeekFrom::End(0))?;
 
-        active_file.write_all(&record.encode()?)?;
-        active_file.flush()?;
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+        let buf = record.encode(cipher.as_ref())?;
+        drop(cipher);
+        self.io.append(active_file, record_start_pos, &buf)?;
 
-        let record_pos = RecordPos {
-            file_id: self.active_file_id.load(Ordering::Relaxed),
-            value_size: record.value_size,
-            value_pos: record_start_pos + RecordData::HEADER_SIZE + record.key_size,
-            timestamp: record.timestamp,
-        };
+        let record_pos = record.pos(
+            self.active_file_id.load(Ordering::Relaxed),
+            record_start_pos,
+        );
 
         let mut keydir = self.keydir.write().expect("Failed to write keydir");
+        self.lock_usage()?
+            .replace(key.len() as u64, Some(&record_pos), keydir.get(key));
         keydir.insert(key.to_vec(), record_pos);
         Ok(())
     }
 
+    /// Put through group commit: the put is queued, and whichever caller finds no group
+    /// being appended appends every queued put with a single write, then wakes the others.
+    fn put_grouped(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        if self.tail.is_some() {
+            return Err(crate::Error::ReadOnly);
+        }
+        let record = RecordData::encoded(&self.codec, key.to_vec(), value)?;
+
+        let mut queue = self.lock_put_queue()?;
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.records.push(record);
+        loop {
+            if queue.committed_seq > seq {
+                return match queue.failures.remove(&seq) {
+                    Some((kind, message)) => Err(std::io::Error::new(kind, message).into()),
+                    None => Ok(()),
+                };
+            }
+            if queue.appending {
+                queue = self.put_committed.wait(queue).map_err(|_| {
+                    crate::Error::PoisonError("Failed to lock put queue".to_string())
+                })?;
+                continue;
+            }
+
+            queue.appending = true;
+            let records = std::mem::take(&mut queue.records);
+            let end_seq = queue.next_seq;
+            drop(queue);
+            let result = self.append_group(records);
+
+            queue = self.lock_put_queue()?;
+            if let Err(e) = &result {
+                let kind = match e {
+                    crate::Error::IOError(e) => e.kind(),
+                    _ => ErrorKind::Other,
+                };
+                let first_seq = queue.committed_seq;
+                for failed_seq in (first_seq..end_seq).filter(|s| *s != seq) {
+                    queue.failures.insert(failed_seq, (kind, e.to_string()));
+                }
+            }
+            queue.committed_seq = end_seq;
+            queue.appending = false;
+            self.put_committed.notify_all();
+            return result;
+        }
+    }
+
+    /// Append a group of queued puts with a single write and index them
+    fn append_group(&self, records: Vec<RecordData>) -> crate::Result<()> {
+        let count = records.len();
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        self.maybe_rotate_file(active_file)?;
+
+        let group_start_pos = active_file.seek(SeekFrom::End(0))?;
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+        let mut buf = Vec::new();
+        let mut positions = Vec::with_capacity(count);
+        for mut record in records {
+            let record_start_pos = group_start_pos + buf.len() as u64;
+            buf.extend_from_slice(&record.encode(cipher.as_ref())?);
+            positions.push((record, record_start_pos));
+        }
+        drop(cipher);
+        self.io.append(active_file, group_start_pos, &buf)?;
+        metrics::group_commit(count);
+
+        let file_id = self.active_file_id.load(Ordering::Relaxed);
+        let mut keydir = self.keydir.write().expect("Failed to write keydir");
+        let mut usage = self.lock_usage()?;
+        for (record, record_start_pos) in positions {
+            let record_pos = record.pos(file_id, record_start_pos);
+            let key_size = record.key.len() as u64;
+            usage.replace(key_size, Some(&record_pos), keydir.get(&record.key));
+            keydir.insert(record.key, record_pos);
+        }
+        Ok(())
+    }
+
+    fn lock_put_queue(&self) -> crate::Result<std::sync::MutexGuard<'_, PutQueue>> {
+        self.put_queue
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock put queue".to_string()))
+    }
+
     /// Rotate file if current file exceeds size limit
     fn maybe_rotate_file(&self, active_file: &mut BufWriter<File>) -> crate::Result<()> {
         let meta = active_file.get_ref().metadata()?;
         if meta.len() >= self.config.max_file_size() {
-            active_file.flush()?;
-            active_file.get_ref().sync_all()?;
-
             let next_file_id = self.next_file_id.load(Ordering::Relaxed);
+            self.rotate_active_file(active_file, next_file_id)?;
+        }
 
-            self.active_file_id.store(next_file_id, Ordering::Relaxed);
-            *active_file = Self::open_append_data_file(&self.config, next_file_id)?;
+        Ok(())
+    }
 
-            self.next_file_id.store(next_file_id + 1, Ordering::Relaxed);
+    /// Switch writes to the new active file `file_id`. An empty active file is deleted
+    /// instead of being kept as a historical file.
+    fn rotate_active_file(
+        &self,
+        active_file: &mut BufWriter<File>,
+        file_id: u64,
+    ) -> crate::Result<()> {
+        let _span = diagnostics::rotation_span(file_id);
+        active_file.flush()?;
+        active_file.get_ref().sync_all()?;
+        let old_file_id = self.active_file_id.load(Ordering::Relaxed);
+        let old_file_empty = active_file.get_ref().metadata()?.len() == 0;
 
-            let mut file_ids = self
-                .file_ids
-                .write()
-                .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))?;
-            file_ids.push(next_file_id);
-        }
+        self.active_file_id.store(file_id, Ordering::Relaxed);
+        *active_file = Self::open_append_data_file(&self.config, file_id)?;
+        self.next_file_id.store(file_id + 1, Ordering::Relaxed);
 
+        let mut file_ids = self
+            .file_ids
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))?;
+        if old_file_empty {
+            file_ids.retain(|&id| id != old_file_id);
+            Self::delete_data_file(&self.config, old_file_id)?;
+        }
+        file_ids.push(file_id);
+        metrics::file_rotation();
         Ok(())
     }
 
     /// Internal remove method
     fn delete_internal(&self, key: &[u8]) -> crate::Result<()> {
+        Self::check_key(key)?;
+        // Lock the active file before keydir, in the same order as `put_internal`
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
         let mut keydir = self.keydir.write().expect("Failed to write keydir");
         if keydir.contains_key(key) {
             // Write tombstone record
-            let tombstone = RecordData::tombstone(key.to_vec());
-            let mut active_file = self
-                .active_file
-                .write()
-                .expect("Failed to write active file");
-            let _record_start_pos = active_file.seek(SeekFrom::End(0))?;
-            active_file.write_all(&tombstone.encode()?)?;
-            active_file.flush()?;
+            let mut tombstone = RecordData::tombstone(key.to_vec());
+            let record_start_pos = active_file.seek(SeekFrom::End(0))?;
+            let cipher = self.cipher.read().expect("Failed to read cipher");
+            let buf = tombstone.encode(cipher.as_ref())?;
+            drop(cipher);
+            self.io.append(active_file, record_start_pos, &buf)?;
 
             // Remove from memory index
-            keydir.remove(key);
+            let old_pos = keydir.remove(key);
+            self.lock_usage()?
+                .replace(key.len() as u64, None, old_pos.as_ref());
+        }
+
+        Ok(())
+    }
+
+    /// Internal write_batch method
+    fn write_batch_internal(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        for op in ops {
+            match op {
+                BatchOp::Put(key, _) | BatchOp::Delete(key) => Self::check_key(key)?,
+            }
         }
 
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        self.maybe_rotate_file(active_file)?;
+
+        // The marker and all records are written with a single write, so a batch always
+        // lives in one file and a torn tail can be detected on load
+        let batch_start_pos = active_file.seek(SeekFrom::End(0))?;
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+        let mut buf = RecordData::batch_marker(ops.len() as u64).encode(cipher.as_ref())?;
+        let mut records = Vec::with_capacity(ops.len());
+        for op in ops {
+            let mut record = match op {
+                BatchOp::Put(key, value) => RecordData::encoded(&self.codec, key.clone(), value)?,
+                BatchOp::Delete(key) => RecordData::tombstone(key.clone()),
+            };
+            let record_start_pos = batch_start_pos + buf.len() as u64;
+            buf.extend_from_slice(&record.encode(cipher.as_ref())?);
+            records.push((op, record, record_start_pos));
+        }
+        drop(cipher);
+
+        self.io.append(active_file, batch_start_pos, &buf)?;
+
+        let file_id = self.active_file_id.load(Ordering::Relaxed);
+        let mut keydir = self.keydir.write().expect("Failed to write keydir");
+        let mut usage = self.lock_usage()?;
+        for (op, record, record_start_pos) in records {
+            match op {
+                BatchOp::Put(..) => {
+                    let record_pos = record.pos(file_id, record_start_pos);
+                    let key_size = record.key.len() as u64;
+                    usage.replace(key_size, Some(&record_pos), keydir.get(&record.key));
+                    keydir.insert(record.key, record_pos);
+                }
+                BatchOp::Delete(key) => {
+                    let old_pos = keydir.remove(key);
+                    usage.replace(key.len() as u64, None, old_pos.as_ref());
+                }
+            }
+        }
         Ok(())
     }
 
     /// Internal clear method
     fn clear_internal(&self) -> crate::Result<()> {
-        self.close_internal()?;
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        let mut keydir = self.keydir.write().expect("Failed to write keydir");
+        active_file.flush()?;
+        self.file_handle_caches
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to clear data file".to_string()))?
+            .clear();
+        self.clear_mapped_files()?;
+
         let file_ids = Self::get_file_ids(&self.config)?;
         for file_id in file_ids {
             Self::delete_data_file(&self.config, file_id)?;
         }
+        keydir.clear();
+        *self.lock_usage()? = FileUsage::default();
+
+        // Start over with an empty active file
+        let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+        *active_file = Self::open_append_data_file(&self.config, active_file_id)?;
+        *self
+            .file_ids
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))? =
+            vec![active_file_id];
         Ok(())
     }
 
@@ -612,41 +1836,769 @@ impl Bitcask {
         Ok(keydir.contains_key(key))
     }
 
+    /// Internal value_len method
+    fn value_len_internal(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        let keydir = self.keydir.read().expect("Failed to read keydir");
+        Ok(keydir.get(key).map(|pos| pos.value_len))
+    }
+
     /// Internal sync method
     fn sync_internal(&self) -> crate::Result<()> {
         let mut active_file = self
             .active_file
             .write()
             .expect("Failed to write active file");
-        active_file.flush()?;
-        active_file.get_ref().sync_all()?;
+        // A read-only instance has nothing to flush
+        if let Some(active_file) = active_file.as_mut() {
+            self.io.sync(active_file)?;
+        }
         Ok(())
     }
 
     /// Internal can_merge method
     fn can_merge_internal(&self) -> crate::Result<bool> {
-        // Get old file IDs (excluding active file)
-        let file_ids = self
+        // Only the primary merges
+        if self.tail.is_some() {
+            return Ok(false);
+        }
+
+        // Only merge what the policy picks, not everything as an explicit merge does
+        let historical_ids = self.historical_file_ids()?;
+        Ok(!self.select_merge_files(&historical_ids, false)?.is_empty())
+    }
+
+    /// Internal merge method
+    fn merge_internal(&self, control: &MergeControl) -> crate::Result<()> {
+        if self.tail.is_some() {
+            return Err(crate::Error::ReadOnly);
+        }
+        self.merge_existing_files(None, control)
+    }
+
+    /// Internal merge_files method
+    fn merge_files_internal(&self, file_ids: &[u64], control: &MergeControl) -> crate::Result<()> {
+        if self.tail.is_some() {
+            return Err(crate::Error::ReadOnly);
+        }
+        self.merge_existing_files(Some(file_ids), control)
+    }
+
+    /// Internal catch-up method.
+    ///
+    /// New records of the last loaded file and new files with higher ids are read
+    /// incrementally. Any other change of the files, after a merge, clear or rekey of
+    /// the primary, reloads keydir from scratch.
+    fn catch_up_internal(&self) -> crate::Result<()> {
+        let mut tail = match &self.tail {
+            Some(tail) => tail.lock().expect("Failed to lock tail state"),
+            None => return Ok(()),
+        };
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+
+        let file_ids = Self::scan_file_ids(&self.config, false)?;
+        let last_file_id = tail.file_ids.last().copied();
+        let last_file_len = match last_file_id {
+            Some(file_id) => std::fs::metadata(
+                self.config
+                    .database_path()
+                    .join(Self::get_file_name(&self.config, file_id)),
+            )
+            .map_or(0, |metadata| metadata.len()),
+            None => 0,
+        };
+
+        if file_ids.starts_with(&tail.file_ids) && last_file_len >= tail.offset {
+            match self.read_appended_records(cipher.as_ref(), &tail, &file_ids) {
+                Ok(offset) => {
+                    tail.offset = offset;
+                    tail.file_ids = file_ids;
+                    return self.refresh_tail_file_ids(&tail);
+                }
+                // Merged away while it was read, keydir is reloaded below
+                Err(crate::Error::IOError(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let (keydir, new_tail) = Self::load_read_only(&self.config, cipher.as_ref(), &self.io)?;
+        *self.keydir.write().expect("Failed to write keydir") = keydir;
+        self.file_handle_caches
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock file cache".to_string()))?
+            .clear();
+        self.clear_mapped_files()?;
+        *tail = new_tail;
+        self.refresh_tail_file_ids(&tail)
+    }
+
+    /// Read the records appended to the last loaded file, then the new files.
+    /// Returns the offset to continue from in the last of `file_ids`.
+    fn read_appended_records(
+        &self,
+        cipher: Option<&Cipher>,
+        tail: &TailState,
+        file_ids: &[u64],
+    ) -> crate::Result<u64> {
+        let resume = tail.file_ids.last().map(|file_id| (*file_id, tail.offset));
+        let new_files = file_ids[tail.file_ids.len()..]
+            .iter()
+            .map(|file_id| (*file_id, 0));
+
+        let mut offset = tail.offset;
+        for (file_id, start_offset) in resume.into_iter().chain(new_files) {
+            offset = Self::process_data_file(
+                &self.config,
+                cipher,
+                &self.io,
+                file_id,
+                start_offset,
+                &self.keydir,
+            )?;
+        }
+        Ok(offset)
+    }
+
+    /// Publish the files loaded by a read-only instance
+    fn refresh_tail_file_ids(&self, tail: &TailState) -> crate::Result<()> {
+        let last_file_id = tail.file_ids.last().copied().unwrap_or(0);
+        self.active_file_id.store(last_file_id, Ordering::Relaxed);
+        self.next_file_id.store(last_file_id + 1, Ordering::Relaxed);
+        *self
+            .file_ids
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))? =
+            tail.file_ids.clone();
+        Ok(())
+    }
+
+    /// Internal stats method.
+    ///
+    /// Live bytes are summed from the record positions in keydir, total bytes are the
+    /// sizes of the data files on disk. Files a merge deletes meanwhile are skipped.
+    fn stats_internal(&self) -> crate::Result<Stats> {
+        let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+        let mut file_ids = self
             .file_ids
             .read()
-            .map_err(|_| crate::Error::PoisonError("Failed to read file_ids".to_string()))?;
+            .map_err(|_| crate::Error::PoisonError("Failed to read file_ids".to_string()))?
+            .clone();
+        if self.tail.is_none() {
+            file_ids.push(active_file_id);
+        }
+        file_ids.sort_unstable();
+        file_ids.dedup();
+
+        let mut live_bytes: HashMap<u64, u64> = HashMap::new();
+        let (key_count, keydir_memory) = {
+            let keydir = self.keydir.read().expect("Failed to read keydir");
+            let mut key_bytes = 0;
+            for (key, record_pos) in keydir.iter() {
+                *live_bytes.entry(record_pos.file_id).or_default() +=
+                    record_pos.record_size(key.len() as u64);
+                key_bytes += key.capacity() as u64;
+            }
+            // Each bucket holds an entry and a control byte, keys live on the heap
+            let entry_size = size_of::<(Vec<u8>, RecordPos)>() as u64 + 1;
+            (
+                keydir.len() as u64,
+                keydir.capacity() as u64 * entry_size + key_bytes,
+            )
+        };
 
-        let old_file_ids: Vec<&u64> = file_ids
+        let mut files = Vec::with_capacity(file_ids.len());
+        for file_id in file_ids {
+            let file_path = self
+                .config
+                .database_path()
+                .join(Self::get_file_name(&self.config, file_id));
+            let total_bytes = match std::fs::metadata(file_path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            files.push(FileStats {
+                file_id,
+                total_bytes,
+                live_bytes: live_bytes.get(&file_id).copied().unwrap_or(0),
+            });
+        }
+
+        let active_file_size = files
             .iter()
-            .filter(|&id| *id != self.active_file_id.load(Ordering::Relaxed))
+            .find(|f| f.file_id == active_file_id)
+            .map_or(0, |f| f.total_bytes);
+        Ok(Stats {
+            key_count,
+            historical_files: files.iter().filter(|f| f.file_id != active_file_id).count() as u64,
+            files,
+            active_file_size,
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            keydir_memory,
+            ..Stats::default()
+        })
+    }
+
+    /// Internal verify method.
+    ///
+    /// Scans every data file for corrupt ranges and records of incomplete batches, then
+    /// reads back the record of every live key. Of the active file, only the part written
+    /// before the verification started is scanned.
+    fn verify_internal(
+        &self,
+        max_bytes_per_sec: Option<u64>,
+        stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut throttle = Throttle::new(max_bytes_per_sec, stop);
+
+        let (active_file_id, active_file_len) = {
+            let mut active_file = self
+                .active_file
+                .write()
+                .expect("Failed to write active file");
+            match (active_file.as_mut(), &self.tail) {
+                (Some(active_file), _) => {
+                    active_file.flush()?;
+                    let active_file_id = self.active_file_id.load(Ordering::Relaxed);
+                    (active_file_id, active_file.get_ref().metadata()?.len())
+                }
+                // A read-only instance checks the files up to where it has loaded them
+                (None, Some(tail)) => {
+                    let tail = tail.lock().expect("Failed to lock tail state");
+                    (tail.file_ids.last().copied().unwrap_or(0), tail.offset)
+                }
+                (None, None) => (0, 0),
+            }
+        };
+        // A merge may be writing its output, so leftovers of failed merges are kept
+        for file_id in Self::scan_file_ids(&self.config, false)? {
+            let end = (file_id == active_file_id).then_some(active_file_len);
+            if !self.verify_data_file(file_id, end, &mut report, &mut throttle)? {
+                report.interrupted = true;
+                return Ok(report);
+            }
+        }
+
+        for key in self.list_keys_internal()? {
+            let keydir = self.keydir.read().expect("Failed to read keydir");
+            let record_pos = match keydir.get(&key) {
+                Some(pos) => pos,
+                // Deleted since the keys were listed
+                None => continue,
+            };
+            report.keys_checked += 1;
+            if let Err(e) = self.verify_entry(&key, record_pos) {
+                report.bad_entries.push(BadEntry {
+                    file_id: record_pos.file_id,
+                    offset: record_pos.record_start_pos(key.len() as u64),
+                    error: e.to_string(),
+                    key: key.clone(),
+                });
+            }
+            let record_size = RecordData::header_size_for(record_pos.flags)
+                + key.len() as u64
+                + record_pos.value_size;
+            drop(keydir);
+
+            if !throttle.consume(record_size) {
+                report.interrupted = true;
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Scan a data file up to `end`, or to its end if `None`, adding its problems to the report.
+    /// Returns `false` if the verification was stopped.
+    fn verify_data_file(
+        &self,
+        file_id: u64,
+        end: Option<u64>,
+        report: &mut VerifyReport,
+        throttle: &mut Throttle,
+    ) -> crate::Result<bool> {
+        let mut file = match Self::open_read_only_data_file(&self.config, file_id) {
+            Ok(file) => file,
+            // Merged away since the file ids were listed
+            Err(crate::Error::IOError(e)) if e.kind() == ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        let end = match end {
+            Some(end) => end,
+            None => file.get_ref().metadata()?.len(),
+        };
+        report.files_checked += 1;
+
+        // Held for the whole file, so a rekey can't swap the key in the middle of it
+        let cipher = self.cipher.read().expect("Failed to read cipher");
+        let limits = SizeLimits::with_config(&self.config);
+        let mut offset = 0;
+        let mut pending_batch: Option<(u64, Vec<OrphanedRecord>)> = None;
+        while offset < end {
+            let (stored_crc, mut record) =
+                match Self::read_raw_record(&mut file, file_id, offset, limits)? {
+                    Some(raw) => raw,
+                    None => {
+                        report.add_corrupt_range(CorruptRange {
+                            file_id,
+                            offset,
+                            len: end - offset,
+                            kind: CorruptionKind::TornTail,
+                        });
+                        break;
+                    }
+                };
+            report.records_checked += 1;
+            let total_size = record.total_size();
+
+            let corruption = if stored_crc != record.crc {
+                metrics::crc_failure("bitcask");
+                Some(CorruptionKind::Checksum)
+            } else if record.decrypt(cipher.as_ref()).is_err() {
+                Some(CorruptionKind::Decryption)
+            } else if let Some(Err(_)) = record.batch_count() {
+                Some(CorruptionKind::InvalidRecord)
+            } else {
+                None
+            };
+            if let Some(kind) = corruption {
+                report.add_corrupt_range(CorruptRange {
+                    file_id,
+                    offset,
+                    len: total_size.min(end - offset),
+                    kind,
+                });
+                // A corrupted record invalidates the whole batch it belongs to
+                Self::orphan_batch(report, pending_batch.take());
+            } else if let Some(Ok(count)) = record.batch_count() {
+                Self::orphan_batch(report, pending_batch.take());
+                pending_batch = Some((count, RecordData::batch_records(count)));
+            } else if let Some((count, records)) = pending_batch.as_mut() {
+                records.push(OrphanedRecord {
+                    file_id,
+                    offset,
+                    key: record.key,
+                });
+                if records.len() as u64 >= *count {
+                    pending_batch = None;
+                }
+            }
+
+            offset += total_size;
+            if !throttle.consume(total_size) {
+                return Ok(false);
+            }
+        }
+        Self::orphan_batch(report, pending_batch);
+        Ok(true)
+    }
+
+    /// Report the records read so far of a batch that turned out to be incomplete
+    fn orphan_batch(report: &mut VerifyReport, pending_batch: Option<(u64, Vec<OrphanedRecord>)>) {
+        if let Some((_, records)) = pending_batch {
+            report.orphaned_records.extend(records);
+        }
+    }
+
+    /// Read back the record of a live key and decode its value
+    fn verify_entry(&self, key: &[u8], record_pos: &RecordPos) -> crate::Result<()> {
+        let file_id = record_pos.file_id;
+        let mut cache = self
+            .file_handle_caches
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock file cache".to_string()))?;
+        let file = cache.try_get_or_insert_mut(file_id, || {
+            Self::open_read_only_data_file(&self.config, file_id)
+        })?;
+
+        let start_offset = record_pos.record_start_pos(key.len() as u64);
+        let limits = SizeLimits::with_config(&self.config);
+        let (stored_crc, mut record) = Self::read_raw_record(file, file_id, start_offset, limits)?
+            .ok_or_else(|| crate::Error::InvalidData("The record is truncated".to_string()))?;
+        if stored_crc != record.crc {
+            metrics::crc_failure("bitcask");
+            return Err(crate::Error::CorruptedData);
+        }
+        record.decrypt(self.cipher.read().expect("Failed to read cipher").as_ref())?;
+        if record.key != key {
+            return Err(crate::Error::InvalidData(
+                "The record holds another key".to_string(),
+            ));
+        }
+        self.codec.decode(record.flags, record.value)?;
+        Ok(())
+    }
+
+    /// Repair the data files of a closed database.
+    ///
+    /// Torn tails are truncated, corrupt regions are moved into a `.quarantine` file next
+    /// to their data file and the records of incomplete batches are removed. Bitcask keeps
+    /// no hint files or manifest, the keydir is rebuilt from the repaired files on open.
+    /// Every file is checked before any is modified, so a wrong encryption key fails with
+    /// `Error::DecryptionFailed` and leaves the files untouched.
+    pub fn repair(config: &Config) -> crate::Result<RepairReport> {
+        if config.read_only() {
+            return Err(crate::Error::ReadOnly);
+        }
+        let _lock = DirLock::acquire(config)?;
+        let cipher = Cipher::with_config(config)?;
+        let mut repairs = Vec::new();
+        for file_id in Self::get_file_ids(config)? {
+            repairs.push(Self::plan_repair(config, cipher.as_ref(), file_id)?);
+        }
+
+        let mut report = RepairReport::default();
+        for repair in repairs {
+            Self::apply_repair(config, repair, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// Scan a data file for the ranges a repair has to remove
+    fn plan_repair(
+        config: &Config,
+        cipher: Option<&Cipher>,
+        file_id: u64,
+    ) -> crate::Result<FileRepair> {
+        let path = config
+            .database_path()
+            .join(Self::get_file_name(config, file_id));
+        let data = std::fs::read(path)?;
+        let file_len = data.len() as u64;
+
+        let mut repair = FileRepair {
+            file_id,
+            removed: Vec::new(),
+            orphaned: Vec::new(),
+            dropped: Vec::new(),
+        };
+        let mut offset = 0;
+        let mut pending_batch: Option<PendingBatch> = None;
+        while offset < file_len {
+            let mut record = match Self::parse_valid_record(&data, offset) {
+                Some(record) => record,
+                None => {
+                    // Resume at the next valid record, the file ends in a torn write if there is none
+                    let next = (offset + 1..file_len)
+                        .find(|pos| Self::parse_valid_record(&data, *pos).is_some());
+                    let end = next.unwrap_or(file_len);
+                    Self::drop_batch(&mut repair, pending_batch.take(), offset);
+                    repair.removed.push(CorruptRange {
+                        file_id,
+                        offset,
+                        len: end - offset,
+                        kind: match next {
+                            Some(_) => CorruptionKind::Checksum,
+                            None => CorruptionKind::TornTail,
+                        },
+                    });
+                    offset = end;
+                    continue;
+                }
+            };
+
+            // The CRC matched, so a record that fails to decrypt was sealed with another key
+            record.decrypt(cipher)?;
+            let total_size = record.total_size();
+            match record.batch_count() {
+                Some(Ok(count)) => {
+                    Self::drop_batch(&mut repair, pending_batch.take(), offset);
+                    pending_batch = Some((offset, count, RecordData::batch_records(count)));
+                }
+                Some(Err(_)) => {
+                    Self::drop_batch(&mut repair, pending_batch.take(), offset);
+                    repair.removed.push(CorruptRange {
+                        file_id,
+                        offset,
+                        len: total_size,
+                        kind: CorruptionKind::InvalidRecord,
+                    });
+                }
+                None => {
+                    if let Some((_, count, records)) = pending_batch.as_mut() {
+                        records.push(OrphanedRecord {
+                            file_id,
+                            offset,
+                            key: record.key,
+                        });
+                        if records.len() as u64 >= *count {
+                            pending_batch = None;
+                        }
+                    }
+                }
+            }
+            offset += total_size;
+        }
+        Self::drop_batch(&mut repair, pending_batch, file_len);
+        Ok(repair)
+    }
+
+    /// Remove the records of an incomplete batch, up to `end`. They are never applied on
+    /// load, and once a corrupt region is cut out they would be mistaken for a full batch.
+    fn drop_batch(repair: &mut FileRepair, pending_batch: Option<PendingBatch>, end: u64) {
+        if let Some((marker_offset, _, records)) = pending_batch {
+            repair.dropped.push((marker_offset, end));
+            repair.orphaned.extend(records);
+        }
+    }
+
+    /// Parse the record at `offset` of a data file read into memory.
+    /// Returns `None` unless the record is complete and its CRC matches.
+    fn parse_valid_record(data: &[u8], offset: u64) -> Option<RecordData> {
+        let mut reader = data.get(offset as usize..)?;
+
+        // Check the sizes against the data first, so garbage is skipped cheaply
+        let mut header = reader;
+        let stored_crc = header.read_u32::<BE>().ok()?;
+        let _timestamp = header.read_u64::<BE>().ok()?;
+        let key_size = header.read_u64::<BE>().ok()?;
+        let raw_value_size = header.read_u64::<BE>().ok()?;
+        let size = RecordData::header_size_for((raw_value_size >> 56) as u8)
+            .checked_add(key_size)?
+            .checked_add(raw_value_size & RecordData::VALUE_SIZE_MASK)?;
+        if size > reader.len() as u64 {
+            return None;
+        }
+
+        reader = &reader[4..];
+        let record = Self::read_record_data(&mut reader, SizeLimits::NONE).ok()?;
+        (record.crc == stored_crc).then_some(record)
+    }
+
+    /// Remove the planned ranges of a data file, truncating it if they only cover its
+    /// tail and rewriting it otherwise
+    fn apply_repair(
+        config: &Config,
+        mut repair: FileRepair,
+        report: &mut RepairReport,
+    ) -> crate::Result<()> {
+        report.files_checked += 1;
+        let mut cuts: Vec<(u64, u64)> = repair
+            .removed
+            .iter()
+            .map(|range| (range.offset, range.offset + range.len))
+            .chain(repair.dropped.iter().copied())
             .collect();
+        cuts.sort_unstable();
+        cuts.dedup_by(|next, prev| {
+            // Merge adjacent ranges, so a torn batch followed by a torn record is one tail
+            let adjacent = next.0 == prev.1;
+            if adjacent {
+                prev.1 = next.1;
+            }
+            adjacent
+        });
 
-        // If the threshold is not exceeded
-        if old_file_ids.len() >= self.config.max_historical_files() as usize {
-            return Ok(true);
+        if !cuts.is_empty() {
+            let path = config
+                .database_path()
+                .join(Self::get_file_name(config, repair.file_id));
+            let data = std::fs::read(&path)?;
+
+            let mut corrupt = repair
+                .removed
+                .iter()
+                .filter(|range| range.kind != CorruptionKind::TornTail)
+                .peekable();
+            if corrupt.peek().is_some() {
+                let extension = config.store_model().extension();
+                let quarantine_path = path.with_extension(format!("{}.quarantine", extension));
+                let mut quarantine = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&quarantine_path)?;
+                for range in corrupt {
+                    quarantine.write_all(
+                        &data[range.offset as usize..(range.offset + range.len) as usize],
+                    )?;
+                }
+                quarantine.sync_all()?;
+                report.quarantine_files.push(quarantine_path);
+            }
+
+            if let [(start, end)] = cuts[..]
+                && end == data.len() as u64
+            {
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(start)?;
+                file.sync_all()?;
+            } else {
+                let mut output = Self::open_merge_data_file(config, repair.file_id)?;
+                let mut pos = 0;
+                for (start, end) in cuts {
+                    output.write_all(&data[pos as usize..start as usize])?;
+                    pos = end;
+                }
+                output.write_all(&data[pos as usize..])?;
+                output.flush()?;
+                output.get_ref().sync_all()?;
+                Self::finish_merge_data_file(config, repair.file_id)?;
+            }
         }
 
-        Ok(false)
+        report.removed_ranges.append(&mut repair.removed);
+        report.orphaned_records.append(&mut repair.orphaned);
+        Ok(())
     }
 
-    /// Internal merge method
-    fn merge_internal(&self) -> crate::Result<()> {
-        self.merge_existing_files()
+    /// Internal rekey method.
+    ///
+    /// Every file, the active one included, is rewritten through the merge path into
+    /// files encrypted with the new key. Writers and readers are blocked meanwhile.
+    /// A marker written once the new files are synced lets the next open complete a rekey
+    /// interrupted while installing them or deleting the old ones.
+    fn rekey_internal(&self, key: &[u8; 32]) -> crate::Result<()> {
+        let new_cipher = Cipher::new(key)?;
+
+        let mut active_file = self
+            .active_file
+            .write()
+            .expect("Failed to write active file");
+        let active_file = active_file.as_mut().ok_or(crate::Error::ReadOnly)?;
+        active_file.flush()?;
+        let mut keydir = self.keydir.write().expect("Failed to write keydir");
+        let mut cipher = self.cipher.write().expect("Failed to write cipher");
+
+        let old_file_ids = Self::get_file_ids(&self.config)?;
+        // Writers are blocked, the outputs take the next ids and the new active file the
+        // one after them
+        let mut output = MergeOutput::create(&self.config, || {
+            Ok(self.next_file_id.fetch_add(1, Ordering::Relaxed))
+        })?;
+        let mut moved = Vec::new();
+        // Never limited nor cancelled
+        let control = MergeControl::new(0);
+        for &old_file_id in &old_file_ids {
+            Self::merge_single_file(
+                &self.config,
+                &self.codec,
+                old_file_id,
+                |record, record_start_pos| {
+                    Self::is_live_record(&keydir, old_file_id, &record.key, record_start_pos)
+                },
+                &mut output,
+                &mut moved,
+                cipher.as_ref(),
+                Some(&new_cipher),
+                &control,
+            )?;
+        }
+        // Once the marker is written, a crash leaves the rekey for the next open to complete
+        let mut new_file_ids = output.sync(&self.config)?;
+        let active_file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        Self::write_rekey_marker(&self.config, &old_file_ids, &new_file_ids)?;
+        for &file_id in &new_file_ids {
+            Self::finish_merge_data_file(&self.config, file_id)?;
+        }
+        new_file_ids.push(active_file_id);
+        let merge_keydir: HashMap<_, _> =
+            moved.into_iter().map(|(key, _, pos)| (key, pos)).collect();
+
+        // Switch to the new file and key, then drop everything written with the old key
+        *active_file = Self::open_append_data_file(&self.config, active_file_id)?;
+        self.active_file_id.store(active_file_id, Ordering::Relaxed);
+        *self.lock_usage()? = FileUsage::from_keydir(&merge_keydir);
+        *keydir = merge_keydir;
+        *cipher = Some(new_cipher);
+        self.file_handle_caches
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock file cache".to_string()))?
+            .clear();
+        if let Some(mapped_files) = &self.mapped_files {
+            mapped_files.forget(&old_file_ids)?;
+        }
+        Self::complete_rekey(&self.config, &old_file_ids)?;
+
+        *self
+            .file_ids
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write file_ids".to_string()))? =
+            new_file_ids;
+        Ok(())
+    }
+
+    /// Record a rekey: `crc(4) + old_count(4) + old_file_id(8)... + new_count(4) +
+    /// new_file_id(8)...`. The new files must be synced under their temporary names.
+    fn write_rekey_marker(
+        config: &Config,
+        old_file_ids: &[u64],
+        new_file_ids: &[u64],
+    ) -> crate::Result<()> {
+        let mut payload = Vec::new();
+        for file_ids in [old_file_ids, new_file_ids] {
+            payload.write_u32::<BE>(file_ids.len() as u32)?;
+            for &file_id in file_ids {
+                payload.write_u64::<BE>(file_id)?;
+            }
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+
+        let path = config.database_path().join(REKEY_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_u32::<BE>(hasher.finalize())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Complete a rekey interrupted after its marker was written: give the new files
+    /// their final names and delete the old ones. Without a marker, the new files are
+    /// left under temporary names, which `get_file_ids` removes.
+    fn recover_rekey(config: &Config) -> crate::Result<()> {
+        let path = config.database_path().join(REKEY_FILE_NAME);
+        let buf = match std::fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut reader = buf.as_slice();
+        let stored_crc = reader
+            .read_u32::<BE>()
+            .map_err(|_| crate::Error::CorruptedData)?;
+        let mut hasher = Hasher::new();
+        hasher.update(reader);
+        if hasher.finalize() != stored_crc {
+            metrics::crc_failure("bitcask");
+            return Err(crate::Error::CorruptedData);
+        }
+        let mut read_file_ids = || -> std::io::Result<Vec<u64>> {
+            let count = reader.read_u32::<BE>()?;
+            (0..count).map(|_| reader.read_u64::<BE>()).collect()
+        };
+        let old_file_ids = read_file_ids().map_err(|_| crate::Error::CorruptedData)?;
+        let new_file_ids = read_file_ids().map_err(|_| crate::Error::CorruptedData)?;
+
+        for file_id in new_file_ids {
+            let merge_file_name = format!("{}.merge", Self::get_file_name(config, file_id));
+            if config.database_path().join(merge_file_name).exists() {
+                Self::finish_merge_data_file(config, file_id)?;
+            }
+        }
+        Self::complete_rekey(config, &old_file_ids)
+    }
+
+    /// Delete the data files written with the old key, then the rekey marker
+    fn complete_rekey(config: &Config, old_file_ids: &[u64]) -> crate::Result<()> {
+        for &file_id in old_file_ids {
+            let path = config
+                .database_path()
+                .join(Self::get_file_name(config, file_id));
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        std::fs::remove_file(config.database_path().join(REKEY_FILE_NAME))?;
+        Ok(())
     }
 
     /// Internal close method
@@ -655,13 +2607,14 @@ impl Bitcask {
             .active_file
             .write()
             .expect("Failed to write active file");
-        active_file.flush()?;
-        active_file.get_ref().sync_all()?;
+        if let Some(active_file) = active_file.as_mut() {
+            self.io.sync(active_file)?;
+        }
         self.file_handle_caches
             .lock()
             .map_err(|_| crate::Error::PoisonError("Failed to clear data file".to_string()))?
             .clear();
-        Ok(())
+        self.clear_mapped_files()
     }
 }
 
@@ -670,6 +2623,14 @@ impl KvStore for Bitcask {
         self.get_internal(key)
     }
 
+    fn get_ref(&self, key: &[u8]) -> crate::Result<Option<ValueRef>> {
+        self.get_ref_internal(key)
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> crate::Result<bool> {
+        self.get_into_internal(key, buf)
+    }
+
     fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
         self.put_internal(key, value)
     }
@@ -682,6 +2643,14 @@ impl KvStore for Bitcask {
         self.contains_internal(key)
     }
 
+    fn value_len(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        self.value_len_internal(key)
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        self.write_batch_internal(ops)
+    }
+
     fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
         self.list_keys_internal()
     }
@@ -698,8 +2667,32 @@ impl KvStore for Bitcask {
         self.can_merge_internal()
     }
 
-    fn merge(&self) -> crate::Result<()> {
-        self.merge_internal()
+    fn merge(&self, control: &MergeControl) -> crate::Result<()> {
+        self.merge_internal(control)
+    }
+
+    fn merge_files(&self, file_ids: &[u64], control: &MergeControl) -> crate::Result<()> {
+        self.merge_files_internal(file_ids, control)
+    }
+
+    fn rekey(&self, key: &[u8; 32]) -> crate::Result<()> {
+        self.rekey_internal(key)
+    }
+
+    fn verify(
+        &self,
+        max_bytes_per_sec: Option<u64>,
+        stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        self.verify_internal(max_bytes_per_sec, stop)
+    }
+
+    fn catch_up(&self) -> crate::Result<()> {
+        self.catch_up_internal()
+    }
+
+    fn stats(&self) -> crate::Result<Stats> {
+        self.stats_internal()
     }
 
     fn close(&self) -> crate::Result<()> {
@@ -710,7 +2703,932 @@ impl KvStore for Bitcask {
 impl Drop for Bitcask {
     fn drop(&mut self) {
         if let Err(e) = self.close() {
-            eprint!("bitcask close err: {}", e);
+            diagnostics::report(
+                self.config.error_handler(),
+                ErrorEvent::new(ErrorSource::Close, &e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "lz4")]
+    use crate::kving::config::Compression;
+    use crate::kving::config::MergePolicy;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    fn config(dir: &Path) -> crate::kving::config::Builder {
+        Config::builder().set_data_dir(dir.to_path_buf())
+    }
+
+    /// Append raw `bytes` to the last data file
+    fn append_to_last_file(config: &Config, bytes: &[u8]) {
+        let file_id = *Bitcask::get_file_ids(config).unwrap().last().unwrap();
+        let path = config
+            .database_path()
+            .join(Bitcask::get_file_name(config, file_id));
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn rejects_reserved_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let bitcask = Bitcask::with_config(config(dir.path()).build()).unwrap();
+
+        assert!(bitcask.put(RecordData::BATCH_KEY, &[0; 8]).is_err());
+        assert!(bitcask.delete(b"\xFFkey").is_err());
+        let ops = [BatchOp::Put(b"\xFF".to_vec(), b"v".to_vec())];
+        assert!(bitcask.write_batch(&ops).is_err());
+        // Namespaced keys are handled by `Kving`, the engine stores them as they are
+        bitcask.put(b"\xFEns\x00key", b"v").unwrap();
+    }
+
+    #[test]
+    fn skips_impossible_batch_markers_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&events);
+        let config = config(dir.path())
+            .set_error_handler(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .build();
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            bitcask.put(b"a", b"1").unwrap();
+            bitcask.close().unwrap();
+        }
+        for count in [0, u64::MAX] {
+            let mut marker =
+                RecordData::new(RecordData::BATCH_KEY.to_vec(), count.to_be_bytes().to_vec());
+            append_to_last_file(&config, &marker.encode(None).unwrap());
+        }
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        bitcask.put(b"b", b"2").unwrap();
+        assert_eq!(bitcask.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(bitcask.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(events.load(Ordering::Relaxed), 2);
+
+        let report = bitcask.verify(None, &AtomicBool::new(false)).unwrap();
+        // Both markers are adjacent, so they are reported as a single range
+        let invalid = report
+            .corrupt_ranges
+            .iter()
+            .filter(|range| range.kind == CorruptionKind::InvalidRecord)
+            .collect::<Vec<_>>();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].len, 2 * (RecordData::HEADER_SIZE + 6 + 8));
+    }
+
+    #[test]
+    fn round_trips_batches_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).build();
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            bitcask.put(b"gone", b"v").unwrap();
+            let ops = [
+                BatchOp::Put(b"a".to_vec(), b"1".to_vec()),
+                BatchOp::Delete(b"gone".to_vec()),
+            ];
+            bitcask.write_batch(&ops).unwrap();
+            bitcask.close().unwrap();
         }
+        let bitcask = Bitcask::with_config(config).unwrap();
+        assert_eq!(bitcask.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(bitcask.get(b"gone").unwrap(), None);
+    }
+
+    #[test]
+    fn round_trips_concurrent_puts_with_the_io_uring_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        // Falls back to standard I/O without the feature, the puts must round trip either way
+        let config = config(dir.path())
+            .set_io_backend(crate::kving::config::IoBackend::IoUring)
+            .build();
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            std::thread::scope(|scope| {
+                for thread in 0..8 {
+                    let bitcask = &bitcask;
+                    scope.spawn(move || {
+                        for i in 0..200 {
+                            let key = format!("{thread}-{i}");
+                            bitcask
+                                .put(key.as_bytes(), key.repeat(3).as_bytes())
+                                .unwrap();
+                        }
+                    });
+                }
+            });
+            bitcask.close().unwrap();
+        }
+        let bitcask = Bitcask::with_config(config).unwrap();
+        for thread in 0..8 {
+            for i in 0..200 {
+                let key = format!("{thread}-{i}");
+                assert_eq!(
+                    bitcask.get(key.as_bytes()).unwrap(),
+                    Some(key.repeat(3).into_bytes())
+                );
+            }
+        }
+    }
+
+    /// Get the path of the last data file
+    fn last_file_path(config: &Config) -> std::path::PathBuf {
+        let file_id = *Bitcask::get_file_ids(config).unwrap().last().unwrap();
+        config
+            .database_path()
+            .join(Bitcask::get_file_name(config, file_id))
+    }
+
+    /// Get the path of the first data file
+    #[cfg(feature = "mmap")]
+    fn first_file_path(config: &Config) -> std::path::PathBuf {
+        let file_id = Bitcask::get_file_ids(config).unwrap()[0];
+        config
+            .database_path()
+            .join(Bitcask::get_file_name(config, file_id))
+    }
+
+    /// Write one-byte values for `keys` into a closed database
+    fn write_and_close(config: &Config, keys: &[&[u8]]) {
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        for key in keys {
+            bitcask.put(key, b"1").unwrap();
+        }
+        bitcask.close().unwrap();
+    }
+
+    #[test]
+    fn repair_truncates_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).build();
+        write_and_close(&config, &[b"a", b"b"]);
+        let record = RecordData::new(b"c".to_vec(), b"1".to_vec())
+            .encode(None)
+            .unwrap();
+        append_to_last_file(&config, &record[..10]);
+
+        let report = Bitcask::repair(&config).unwrap();
+        let record_size = RecordData::HEADER_SIZE + 2;
+        assert_eq!(
+            report.removed_ranges,
+            vec![CorruptRange {
+                file_id: *Bitcask::get_file_ids(&config).unwrap().last().unwrap(),
+                offset: 2 * record_size,
+                len: 10,
+                kind: CorruptionKind::TornTail,
+            }]
+        );
+        assert!(report.quarantine_files.is_empty());
+        let len = std::fs::metadata(last_file_path(&config)).unwrap().len();
+        assert_eq!(len, 2 * record_size);
+        assert!(Bitcask::repair(&config).unwrap().is_clean());
+
+        let bitcask = Bitcask::with_config(config).unwrap();
+        assert_eq!(bitcask.get(b"b").unwrap().as_deref(), Some(&b"1"[..]));
+    }
+
+    #[test]
+    fn repair_quarantines_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).build();
+        write_and_close(&config, &[b"a", b"b", b"c"]);
+        let record_size = RecordData::HEADER_SIZE + 2;
+        let path = last_file_path(&config);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[2 * record_size as usize - 1] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = Bitcask::repair(&config).unwrap();
+        assert_eq!(report.removed_ranges.len(), 1);
+        assert_eq!(report.removed_ranges[0].kind, CorruptionKind::Checksum);
+        assert_eq!(report.removed_ranges[0].offset, record_size);
+        assert_eq!(report.quarantine_files.len(), 1);
+        let quarantined = std::fs::read(&report.quarantine_files[0]).unwrap();
+        assert!(quarantined.len() as u64 >= report.removed_ranges[0].len);
+
+        let bitcask = Bitcask::with_config(config).unwrap();
+        assert_eq!(bitcask.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(bitcask.get(b"b").unwrap(), None);
+        assert_eq!(bitcask.get(b"c").unwrap().as_deref(), Some(&b"1"[..]));
+        assert!(
+            bitcask
+                .verify(None, &AtomicBool::new(false))
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn repair_removes_impossible_batch_markers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).build();
+        write_and_close(&config, &[b"a"]);
+        let mut marker = RecordData::new(
+            RecordData::BATCH_KEY.to_vec(),
+            u64::MAX.to_be_bytes().to_vec(),
+        );
+        append_to_last_file(&config, &marker.encode(None).unwrap());
+
+        let report = Bitcask::repair(&config).unwrap();
+        assert_eq!(report.removed_ranges.len(), 1);
+        assert_eq!(report.removed_ranges[0].kind, CorruptionKind::InvalidRecord);
+        assert!(Bitcask::repair(&config).unwrap().is_clean());
+        let bitcask = Bitcask::with_config(config).unwrap();
+        assert!(
+            bitcask
+                .verify(None, &AtomicBool::new(false))
+                .unwrap()
+                .is_ok()
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn repair_with_the_wrong_key_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let right_key = config(dir.path()).set_encryption_key([1; 32]).build();
+        let wrong_key = config(dir.path()).set_encryption_key([2; 32]).build();
+        write_and_close(&right_key, &[b"a"]);
+        append_to_last_file(&right_key, &[0; 10]);
+        let before = std::fs::read(last_file_path(&right_key)).unwrap();
+
+        assert!(matches!(
+            Bitcask::repair(&wrong_key),
+            Err(crate::Error::DecryptionFailed)
+        ));
+        assert_eq!(std::fs::read(last_file_path(&right_key)).unwrap(), before);
+    }
+
+    #[test]
+    fn rejects_entries_over_the_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path())
+            .set_max_key_size(4)
+            .set_max_value_size(8)
+            .build();
+        assert!(config.check_entry_size(b"four", b"eight by").is_ok());
+        assert!(matches!(
+            config.check_entry_size(b"fives", b"v"),
+            Err(crate::Error::InvalidData(_))
+        ));
+        assert!(matches!(
+            config.check_entry_size(b"k", b"nine byte"),
+            Err(crate::Error::InvalidData(_))
+        ));
+
+        // Enforced by `Kving` for single writes and batches alike
+        let kving = crate::Kving::with_config(config).unwrap();
+        assert!(kving.put_blob("k", b"nine byte").is_err());
+        let mut batch = crate::WriteBatch::new();
+        batch.put_blob("a", b"1").put_blob("b", b"nine byte");
+        assert!(kving.write_batch(batch).is_err());
+        assert!(kving.list_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_records_before_allocating() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).set_max_value_size(1024).build();
+        write_and_close(&config, &[b"a"]);
+        let mut record = RecordData::new(b"b".to_vec(), b"1".to_vec())
+            .encode(None)
+            .unwrap();
+        // A corrupted size no allocation could satisfy, checked before the checksum
+        record[20..28].copy_from_slice(&(RecordData::VALUE_SIZE_MASK).to_be_bytes());
+        append_to_last_file(&config, &record);
+
+        let offset = RecordData::HEADER_SIZE + 2;
+        let file_id = *Bitcask::get_file_ids(&config).unwrap().last().unwrap();
+        match Bitcask::with_config(config) {
+            Err(crate::Error::InvalidData(message)) => assert!(message.contains(&format!(
+                "Record at offset {} of data file {}",
+                offset, file_id
+            ))),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn secondary_catches_up_with_the_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).set_max_file_size(128).build();
+        let primary = Bitcask::with_config(config.clone()).unwrap();
+        for i in 0..10u8 {
+            primary.put(&[i], b"old").unwrap();
+        }
+        primary.sync().unwrap();
+        // Left by an interrupted merge, only the primary may remove it
+        let merge_file = config.database_path().join("999.merge");
+        std::fs::write(&merge_file, b"").unwrap();
+
+        let secondary = Bitcask::open_read_only(config.clone()).unwrap();
+        assert_eq!(secondary.get(&[3]).unwrap().as_deref(), Some(&b"old"[..]));
+        assert!(matches!(
+            secondary.put(b"k", b"v"),
+            Err(crate::Error::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.delete(&[3]),
+            Err(crate::Error::ReadOnly)
+        ));
+
+        primary.put(&[3], b"new").unwrap();
+        primary.delete(&[4]).unwrap();
+        primary.sync().unwrap();
+        assert_eq!(secondary.get(&[3]).unwrap().as_deref(), Some(&b"old"[..]));
+        secondary.catch_up().unwrap();
+        assert_eq!(secondary.get(&[3]).unwrap().as_deref(), Some(&b"new"[..]));
+        assert_eq!(secondary.get(&[4]).unwrap(), None);
+
+        // The merge deletes files the secondary loaded, it reloads everything
+        primary.merge(&MergeControl::new(0)).unwrap();
+        primary.put(&[10], b"after").unwrap();
+        primary.sync().unwrap();
+        secondary.catch_up().unwrap();
+        assert_eq!(secondary.list_keys().unwrap().len(), 10);
+        assert_eq!(secondary.get(&[3]).unwrap().as_deref(), Some(&b"new"[..]));
+        assert_eq!(
+            secondary.get(&[10]).unwrap().as_deref(),
+            Some(&b"after"[..])
+        );
+        drop(secondary);
+        assert!(merge_file.exists());
+    }
+
+    /// List the files of a directory with their contents
+    fn snapshot_dir(dir: &Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| {
+                let bytes = std::fs::read(&path).unwrap();
+                (path, bytes)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn read_only_mode_never_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let writable = config(dir.path()).set_max_file_size(64).build();
+        let read_only = config(dir.path())
+            .set_max_file_size(64)
+            .set_read_only(true)
+            .build();
+        write_and_close(&writable, &[b"a", b"b", b"c"]);
+        std::fs::write(writable.database_path().join("9.merge"), b"").unwrap();
+        let before = snapshot_dir(&writable.database_path());
+
+        let kving = crate::Kving::with_config(read_only.clone()).unwrap();
+        assert_eq!(kving.get_blob("c").as_deref(), Some(&b"1"[..]));
+        assert_eq!(kving.list_keys().unwrap().len(), 3);
+        assert!(matches!(
+            kving.put_blob("d", b"1"),
+            Err(crate::Error::ReadOnly)
+        ));
+        assert!(matches!(kving.clear(), Err(crate::Error::ReadOnly)));
+        assert!(kving.merge().is_err());
+        kving.close().unwrap();
+        drop(kving);
+        assert_eq!(snapshot_dir(&writable.database_path()), before);
+
+        let bitcask = Bitcask::with_config(read_only).unwrap();
+        assert_eq!(bitcask.get(b"c").unwrap().as_deref(), Some(&b"1"[..]));
+        assert!(matches!(bitcask.delete(b"a"), Err(crate::Error::ReadOnly)));
+        assert!(matches!(
+            bitcask.write_batch(&[BatchOp::Delete(b"a".to_vec())]),
+            Err(crate::Error::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn read_only_mode_does_not_create_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir.path().join("missing"))
+            .set_read_only(true)
+            .build();
+        assert!(Bitcask::with_config(config).is_err());
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    /// Contents of data files, by id
+    #[cfg(feature = "encryption")]
+    type DataFiles = Vec<(u64, Vec<u8>)>;
+
+    /// Read every data file
+    #[cfg(feature = "encryption")]
+    fn read_data_files(config: &Config) -> DataFiles {
+        Bitcask::get_file_ids(config)
+            .unwrap()
+            .into_iter()
+            .map(|file_id| {
+                let path = config
+                    .database_path()
+                    .join(Bitcask::get_file_name(config, file_id));
+                (file_id, std::fs::read(path).unwrap())
+            })
+            .collect()
+    }
+
+    /// Replace the data files with `files`, the ones in `merge_files` under their
+    /// temporary merge names
+    #[cfg(feature = "encryption")]
+    fn restore_data_files(
+        config: &Config,
+        files: &[(u64, Vec<u8>)],
+        merge_files: &[(u64, Vec<u8>)],
+    ) {
+        for file_id in Bitcask::get_file_ids(config).unwrap() {
+            Bitcask::delete_data_file(config, file_id).unwrap();
+        }
+        for (file_id, bytes) in files {
+            let name = Bitcask::get_file_name(config, *file_id);
+            std::fs::write(config.database_path().join(name), bytes).unwrap();
+        }
+        for (file_id, bytes) in merge_files {
+            let name = format!("{}.merge", Bitcask::get_file_name(config, *file_id));
+            std::fs::write(config.database_path().join(name), bytes).unwrap();
+        }
+    }
+
+    /// Write values under key `[1]` in several data files and close, then rekey from
+    /// `[1]` to `[2]`. Returns the data files before and after the rekey.
+    #[cfg(feature = "encryption")]
+    fn rekeyed_files(dir: &Path) -> (DataFiles, DataFiles) {
+        let old = config(dir)
+            .set_max_file_size(256)
+            .set_encryption_key([1; 32])
+            .build();
+        {
+            let bitcask = Bitcask::with_config(old.clone()).unwrap();
+            for i in 0..20u8 {
+                bitcask.put(&[i], &[i; 32]).unwrap();
+            }
+            bitcask.close().unwrap();
+        }
+        let old_files = read_data_files(&old);
+        {
+            let bitcask = Bitcask::with_config(old.clone()).unwrap();
+            bitcask.rekey(&[2; 32]).unwrap();
+            bitcask.close().unwrap();
+        }
+        (old_files, read_data_files(&old))
+    }
+
+    #[cfg(feature = "encryption")]
+    fn open_with_key(dir: &Path, key: u8) -> crate::Result<Bitcask> {
+        Bitcask::with_config(
+            config(dir)
+                .set_max_file_size(256)
+                .set_encryption_key([key; 32])
+                .build(),
+        )
+    }
+
+    #[cfg(feature = "encryption")]
+    fn assert_values(bitcask: &Bitcask) {
+        for i in 0..20u8 {
+            assert_eq!(bitcask.get(&[i]).unwrap(), Some(vec![i; 32]));
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn rekeys_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        rekeyed_files(dir.path());
+
+        assert!(matches!(
+            open_with_key(dir.path(), 1),
+            Err(crate::Error::DecryptionFailed)
+        ));
+        let bitcask = open_with_key(dir.path(), 2).unwrap();
+        assert_values(&bitcask);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn completes_a_rekey_interrupted_after_the_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_files, new_files) = rekeyed_files(dir.path());
+        let config = config(dir.path()).build();
+
+        // Crashed while renaming the new files: the first one has its final name
+        let old_ids: Vec<u64> = old_files.iter().map(|(id, _)| *id).collect();
+        let new_ids: Vec<u64> = new_files.iter().map(|(id, _)| *id).collect();
+        let files: Vec<_> = old_files.iter().chain(&new_files[..1]).cloned().collect();
+        restore_data_files(&config, &files, &new_files[1..]);
+        Bitcask::write_rekey_marker(&config, &old_ids, &new_ids).unwrap();
+
+        // Opening with the old key completes the rekey all the same
+        assert!(matches!(
+            open_with_key(dir.path(), 1),
+            Err(crate::Error::DecryptionFailed)
+        ));
+        assert!(!config.database_path().join(REKEY_FILE_NAME).exists());
+        let bitcask = open_with_key(dir.path(), 2).unwrap();
+        assert_values(&bitcask);
+        let file_ids = Bitcask::get_file_ids(&config).unwrap();
+        assert!(old_ids.iter().all(|id| !file_ids.contains(id)));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn discards_a_rekey_interrupted_before_the_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_files, new_files) = rekeyed_files(dir.path());
+        let config = config(dir.path()).build();
+        restore_data_files(&config, &old_files, &new_files);
+
+        assert!(matches!(
+            open_with_key(dir.path(), 2),
+            Err(crate::Error::DecryptionFailed)
+        ));
+        let bitcask = open_with_key(dir.path(), 1).unwrap();
+        assert_values(&bitcask);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn recompresses_values_on_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let value = br#"{"id":1,"name":"kving","tags":["a","b","c"]}"#.repeat(20);
+        let raw = || config(dir.path()).set_max_file_size(4096);
+        {
+            let bitcask = Bitcask::with_config(raw().build()).unwrap();
+            for i in 0..20u8 {
+                bitcask.put(&[i], &value).unwrap();
+            }
+            bitcask.close().unwrap();
+        }
+
+        let compressed = raw().set_compression(Compression::Lz4).build();
+        let bitcask = Bitcask::with_config(compressed).unwrap();
+        let before = bitcask.stats().unwrap().total_bytes();
+        bitcask.merge(&MergeControl::new(0)).unwrap();
+        assert!(bitcask.stats().unwrap().total_bytes() < before / 2);
+        assert_eq!(bitcask.get(&[3]).unwrap().as_deref(), Some(&value[..]));
+        assert_eq!(bitcask.value_len(&[3]).unwrap(), Some(value.len() as u64));
+        drop(bitcask);
+
+        // Compressed records stay readable once compression is turned off
+        let bitcask = Bitcask::with_config(config(dir.path()).build()).unwrap();
+        assert_eq!(bitcask.get(&[19]).unwrap().as_deref(), Some(&value[..]));
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    #[test]
+    fn mmap_reads_need_the_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).set_mmap_reads(true).build();
+        assert!(Bitcask::with_config(config).is_err());
+    }
+
+    /// Bitcask with memory-mapped reads, whose `a` and `b` are in an immutable file
+    #[cfg(feature = "mmap")]
+    fn mapped(dir: &Path) -> (Config, Bitcask) {
+        let config = config(dir).set_mmap_reads(true).build();
+        write_and_close(&config, &[b"a", b"b"]);
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        (config, bitcask)
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn lends_values_of_immutable_files_from_their_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, bitcask) = mapped(dir.path());
+        bitcask.put(b"c", b"active").unwrap();
+
+        let a = bitcask.get_ref(b"a").unwrap().unwrap();
+        assert!(a.is_mapped());
+        assert_eq!(&a[..], b"1");
+        // The active file is still appended to, so its values are copied
+        let c = bitcask.get_ref(b"c").unwrap().unwrap();
+        assert!(!c.is_mapped());
+        assert_eq!(&c[..], b"active");
+        assert!(bitcask.get_ref(b"missing").unwrap().is_none());
+
+        let mut buf = Vec::new();
+        assert!(bitcask.get_into(b"b", &mut buf).unwrap());
+        assert_eq!(buf, b"1");
+    }
+
+    #[cfg(all(feature = "mmap", feature = "lz4"))]
+    #[test]
+    fn copies_compressed_values_out_of_their_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path())
+            .set_mmap_reads(true)
+            .set_compression(Compression::Lz4)
+            .set_compression_min_size(16)
+            .build();
+        let value = b"kving".repeat(100);
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            bitcask.put(b"a", &value).unwrap();
+            bitcask.close().unwrap();
+        }
+        let bitcask = Bitcask::with_config(config).unwrap();
+        let a = bitcask.get_ref(b"a").unwrap().unwrap();
+        assert!(!a.is_mapped());
+        assert_eq!(&a[..], &value[..]);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn lent_values_outlive_the_merge_of_their_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, bitcask) = mapped(dir.path());
+        let path = first_file_path(&config);
+        let a = bitcask.get_ref(b"a").unwrap().unwrap();
+        bitcask.put(b"a", b"2").unwrap();
+        bitcask.merge(&MergeControl::new(0)).unwrap();
+
+        assert!(!path.exists());
+        assert!(a.is_mapped());
+        assert_eq!(&a[..], b"1");
+        assert_eq!(bitcask.get(b"a").unwrap().as_deref(), Some(&b"2"[..]));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_reads_check_the_crc() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, bitcask) = mapped(dir.path());
+        let record_size = RecordData::HEADER_SIZE + 2;
+        // The file is mapped on its first read, so it sees the corrupted value
+        let path = first_file_path(&config);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[2 * record_size as usize - 1] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert_eq!(bitcask.get_ref(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+        assert!(bitcask.get_ref(b"b").is_err());
+    }
+
+    #[cfg(all(feature = "lz4", feature = "encryption"))]
+    #[test]
+    fn value_len_is_the_decoded_size_across_reopen_and_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path())
+            .set_compression(Compression::Lz4)
+            .set_compression_min_size(16)
+            .set_encryption_key([7; 32])
+            .build();
+        let value = b"kving".repeat(100);
+        let check = |bitcask: &Bitcask| {
+            assert_eq!(bitcask.value_len(b"a").unwrap(), Some(value.len() as u64));
+            let mut buf = Vec::new();
+            assert!(bitcask.get_into(b"a", &mut buf).unwrap());
+            assert_eq!(buf, value);
+        };
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            bitcask.put(b"a", &value).unwrap();
+            check(&bitcask);
+            bitcask.close().unwrap();
+        }
+        {
+            let bitcask = Bitcask::with_config(config.clone()).unwrap();
+            check(&bitcask);
+            bitcask.merge(&MergeControl::new(0)).unwrap();
+            check(&bitcask);
+            bitcask.close().unwrap();
+        }
+        check(&Bitcask::with_config(config).unwrap());
+    }
+
+    /// Config writing each 100-byte value to its own data file
+    fn garbage_config(dir: &Path, policy: MergePolicy) -> Config {
+        config(dir)
+            .set_max_file_size(100)
+            .set_max_historical_files(100)
+            .set_merge_policy(policy)
+            .build()
+    }
+
+    #[test]
+    fn selects_files_by_dead_ratio_and_dead_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let by_ratio = MergePolicy {
+            max_dead_ratio: Some(0.5),
+            ..MergePolicy::default()
+        };
+        let bitcask = Bitcask::with_config(garbage_config(dir.path(), by_ratio.clone())).unwrap();
+        for i in 0..4u8 {
+            bitcask.put(&[i], &[b'v'; 100]).unwrap();
+        }
+        bitcask.put(&[0], &[b'w'; 100]).unwrap();
+        bitcask.put(&[1], &[b'w'; 100]).unwrap();
+        bitcask.delete(&[2]).unwrap();
+
+        // Overwrites and deletes kill the whole first three files
+        let ids = bitcask.historical_file_ids().unwrap();
+        assert_eq!(ids.len(), 5);
+        let garbage = bitcask.file_garbage(&ids).unwrap();
+        let record_size = garbage[0].1;
+        let dead_bytes = garbage.iter().map(|&(_, _, dead)| dead).collect::<Vec<_>>();
+        assert_eq!(dead_bytes, [record_size, record_size, record_size, 0, 0]);
+        assert!(bitcask.can_merge().unwrap());
+        assert_eq!(bitcask.select_merge_files(&ids, false).unwrap(), ids[..3]);
+        drop(bitcask);
+
+        // The accounting is rebuilt on load
+        let bitcask = Bitcask::with_config(garbage_config(dir.path(), by_ratio)).unwrap();
+        assert_eq!(bitcask.select_merge_files(&ids, false).unwrap(), ids[..3]);
+        drop(bitcask);
+
+        let by_bytes = |max_dead_bytes| MergePolicy {
+            max_dead_bytes: Some(max_dead_bytes),
+            ..MergePolicy::default()
+        };
+        let bitcask =
+            Bitcask::with_config(garbage_config(dir.path(), by_bytes(record_size))).unwrap();
+        assert_eq!(bitcask.select_merge_files(&ids, false).unwrap(), ids[..1]);
+        drop(bitcask);
+
+        let bitcask =
+            Bitcask::with_config(garbage_config(dir.path(), by_bytes(4 * record_size))).unwrap();
+        assert!(!bitcask.can_merge().unwrap());
+        drop(bitcask);
+
+        // Without thresholds only an explicit merge picks files, and then all of them
+        let bitcask =
+            Bitcask::with_config(garbage_config(dir.path(), MergePolicy::default())).unwrap();
+        assert!(!bitcask.can_merge().unwrap());
+        assert_eq!(bitcask.select_merge_files(&ids, true).unwrap(), ids);
+    }
+
+    #[test]
+    fn selective_merge_keeps_tombstones_of_older_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // Every put rotates to a new file first
+        let config = config(dir.path()).set_max_file_size(1).build();
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        bitcask.put(b"k", b"v").unwrap();
+        bitcask.put(b"a", b"1").unwrap();
+        bitcask.delete(b"k").unwrap();
+        bitcask.put(b"b", b"1").unwrap();
+
+        let ids = bitcask.historical_file_ids().unwrap();
+        assert_eq!(ids.len(), 2);
+        bitcask
+            .merge_files(&ids[1..], &MergeControl::new(0))
+            .unwrap();
+        assert_eq!(bitcask.get(b"k").unwrap(), None);
+        drop(bitcask);
+
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        assert_eq!(bitcask.get(b"k").unwrap(), None);
+        assert_eq!(bitcask.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+
+        // Once the put is merged as well, the tombstone is dropped
+        let ids = bitcask.historical_file_ids().unwrap();
+        bitcask.merge_files(&ids, &MergeControl::new(0)).unwrap();
+        drop(bitcask);
+        let bitcask = Bitcask::with_config(config).unwrap();
+        assert_eq!(bitcask.get(b"k").unwrap(), None);
+        let historical_bytes = bitcask
+            .file_garbage(&bitcask.historical_file_ids().unwrap())
+            .unwrap()
+            .iter()
+            .map(|&(_, total_bytes, _)| total_bytes)
+            .sum::<u64>();
+        assert_eq!(historical_bytes, 2 * (RecordData::HEADER_SIZE + 2));
+    }
+
+    #[test]
+    fn merges_the_file_left_active_by_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = garbage_config(dir.path(), MergePolicy::default());
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        bitcask.put(b"a", &[1; 100]).unwrap();
+        bitcask.clear().unwrap();
+        let cleared_id = bitcask.active_file_id.load(Ordering::Relaxed);
+        let cleared_path = config
+            .database_path()
+            .join(Bitcask::get_file_name(&config, cleared_id));
+        for i in 0..5u8 {
+            bitcask.put(b"a", &[i; 100]).unwrap();
+        }
+        assert!(bitcask.historical_file_ids().unwrap().contains(&cleared_id));
+
+        bitcask.merge(&MergeControl::new(0)).unwrap();
+        assert!(!cleared_path.exists());
+        let stats = bitcask.stats().unwrap();
+        assert!(stats.files.iter().all(|file| file.file_id != cleared_id));
+        assert_eq!(bitcask.get(b"a").unwrap(), Some(vec![4; 100]));
+    }
+
+    #[test]
+    fn merge_outputs_take_ids_as_they_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path())
+            .set_max_file_size(100)
+            .set_max_historical_files(100)
+            .build();
+        let bitcask = Bitcask::with_config(config.clone()).unwrap();
+        for i in 0..6u8 {
+            bitcask.put(&[i], &[b'v'; 100]).unwrap();
+        }
+        let active_before = bitcask.active_file_id.load(Ordering::Relaxed);
+        bitcask.merge(&MergeControl::new(0)).unwrap();
+
+        // The five outputs each take one id, the active file moving past every one of
+        // them. Only the first active file was written to and remains.
+        let outputs = [1, 3, 5, 7, 9].map(|n| active_before + n);
+        let mut expected = vec![active_before];
+        expected.extend(outputs);
+        expected.push(active_before + 10);
+        assert_eq!(Bitcask::get_file_ids(&config).unwrap(), expected);
+        assert_eq!(
+            bitcask.active_file_id.load(Ordering::Relaxed),
+            active_before + 10
+        );
+        assert_eq!(
+            bitcask.next_file_id.load(Ordering::Relaxed),
+            active_before + 11
+        );
+        drop(bitcask);
+
+        let bitcask = Bitcask::with_config(config).unwrap();
+        for i in 0..6u8 {
+            assert_eq!(
+                bitcask.get(&[i]).unwrap().as_deref(),
+                Some(&[b'v'; 100][..])
+            );
+        }
+    }
+
+    #[test]
+    fn merge_keeps_writes_made_while_it_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path())
+            .set_max_file_size(1024)
+            .set_max_historical_files(1000)
+            .set_merge_rate(32 * 1024)
+            .build();
+        let value = |round: &str| format!("{:0>100}", round);
+        let kving = crate::Kving::with_config(config.clone()).unwrap();
+        kving.merge().unwrap();
+        for round in ["first", "second"] {
+            for i in 0..100 {
+                kving
+                    .put_string(format!("key-{}", i), value(round))
+                    .unwrap();
+            }
+        }
+
+        std::thread::scope(|scope| {
+            let merge = scope.spawn(|| kving.merge());
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            while kving
+                .merge_progress()
+                .is_none_or(|progress| progress.bytes_read == 0)
+            {
+                assert!(std::time::Instant::now() < deadline, "no merge progress");
+                std::thread::yield_now();
+            }
+            for i in 0..50 {
+                kving
+                    .put_string(format!("key-{}", i), value("during"))
+                    .unwrap();
+            }
+            for i in 50..60 {
+                kving.delete(format!("key-{}", i)).unwrap();
+            }
+            assert!(
+                kving.merge_progress().is_some(),
+                "merge done before the writes"
+            );
+            merge.join().unwrap().unwrap();
+        });
+
+        let check = |kving: &crate::Kving| {
+            for i in 0..100 {
+                let expected = match i {
+                    0..50 => Some(value("during")),
+                    50..60 => None,
+                    _ => Some(value("second")),
+                };
+                let key = format!("key-{}", i);
+                assert_eq!(kving.get_string(&key), expected, "{}", key);
+            }
+        };
+        check(&kving);
+        drop(kving);
+        check(&crate::Kving::with_config(config).unwrap());
     }
 }
@@ -0,0 +1,277 @@
+use crate::kving::config::{Compression, Config};
+use byteorder::{BE, ReadBytesExt};
+
+/// Bits of the record flags holding the codec
+pub const CODEC_MASK: u8 = 0b0000_0011;
+pub const CODEC_NONE: u8 = 0;
+pub const CODEC_LZ4: u8 = 1;
+pub const CODEC_ZSTD: u8 = 2;
+pub const CODEC_ZSTD_DICT: u8 = 3;
+
+/// Size of the uncompressed length prefix of a compressed value
+const LEN_PREFIX_SIZE: usize = 8;
+
+/// Compresses and decompresses record values.
+///
+/// A compressed value is stored as `uncompressed_len(8) + compressed bytes`, and the codec
+/// is recorded in the record flags, so records written with any codec stay readable
+/// whatever the current configuration is.
+pub struct Codec {
+    compression: Compression,
+    min_size: u64,
+    #[cfg(feature = "zstd")]
+    dictionary: Option<(
+        zstd::dict::EncoderDictionary<'static>,
+        zstd::dict::DecoderDictionary<'static>,
+    )>,
+}
+
+impl Codec {
+    /// Create a codec from the compression policy of the config
+    pub fn with_config(config: &Config) -> crate::Result<Self> {
+        #[cfg(feature = "zstd")]
+        let dictionary = config.compression_dictionary().map(|dict| {
+            let level = match config.compression() {
+                Compression::Zstd(level) => level,
+                _ => zstd::DEFAULT_COMPRESSION_LEVEL,
+            };
+            (
+                zstd::dict::EncoderDictionary::copy(dict, level),
+                zstd::dict::DecoderDictionary::copy(dict),
+            )
+        });
+
+        match config.compression() {
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => return Err(Self::unsupported(CODEC_LZ4)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd(_) => return Err(Self::unsupported(CODEC_ZSTD)),
+            _ => {}
+        }
+
+        Ok(Self {
+            compression: config.compression(),
+            min_size: config.compression_min_size(),
+            #[cfg(feature = "zstd")]
+            dictionary,
+        })
+    }
+
+    /// Error for a codec whose cargo feature is disabled
+    #[allow(unused)]
+    fn unsupported(codec: u8) -> crate::Error {
+        let name = match codec {
+            CODEC_LZ4 => "lz4",
+            CODEC_ZSTD | CODEC_ZSTD_DICT => "zstd",
+            _ => "unknown",
+        };
+        crate::Error::InvalidData(format!("Codec {} is not enabled, see cargo features", name))
+    }
+
+    /// Encode a value with the configured codec, returning the codec flags and stored bytes.
+    /// Values below the size threshold, or that don't shrink, are stored raw.
+    pub fn encode(&self, value: &[u8]) -> crate::Result<(u8, Vec<u8>)> {
+        if (value.len() as u64) < self.min_size {
+            return Ok((CODEC_NONE, value.to_vec()));
+        }
+        let (codec, compressed) = match self.compress(value)? {
+            Some(compressed) => compressed,
+            None => return Ok((CODEC_NONE, value.to_vec())),
+        };
+
+        if compressed.len() + LEN_PREFIX_SIZE >= value.len() {
+            return Ok((CODEC_NONE, value.to_vec()));
+        }
+        let mut stored = Vec::with_capacity(LEN_PREFIX_SIZE + compressed.len());
+        stored.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        stored.extend_from_slice(&compressed);
+        Ok((codec, stored))
+    }
+
+    /// Compress a value with the configured codec, `None` if compression is disabled
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, value: &[u8]) -> crate::Result<Option<(u8, Vec<u8>)>> {
+        match self.compression {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(Some((CODEC_LZ4, lz4_flex::block::compress(value)))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => match &self.dictionary {
+                Some((dict, _)) => Ok(Some((
+                    CODEC_ZSTD_DICT,
+                    zstd::bulk::Compressor::with_prepared_dictionary(dict)?.compress(value)?,
+                ))),
+                None => Ok(Some((CODEC_ZSTD, zstd::bulk::compress(value, level)?))),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Decode stored bytes written with the codec in `flags`
+    pub fn decode(&self, flags: u8, stored: Vec<u8>) -> crate::Result<Vec<u8>> {
+        let codec = flags & CODEC_MASK;
+        if codec == CODEC_NONE {
+            return Ok(stored);
+        }
+
+        let len = Self::decoded_len(flags, &stored) as usize;
+        let compressed = &stored[LEN_PREFIX_SIZE.min(stored.len())..];
+        let value = self.decompress(codec, compressed, len)?;
+
+        if value.len() != len {
+            return Err(crate::Error::CorruptedData);
+        }
+        Ok(value)
+    }
+
+    /// Decompress bytes written with `codec` into a value of `len` bytes
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn decompress(&self, codec: u8, compressed: &[u8], len: usize) -> crate::Result<Vec<u8>> {
+        match codec {
+            #[cfg(feature = "lz4")]
+            CODEC_LZ4 => lz4_flex::block::decompress(compressed, len).map_err(|e| {
+                crate::Error::InvalidData(format!("Failed to decompress value: {}", e))
+            }),
+            #[cfg(feature = "zstd")]
+            CODEC_ZSTD => Ok(zstd::bulk::decompress(compressed, len)?),
+            #[cfg(feature = "zstd")]
+            CODEC_ZSTD_DICT => match &self.dictionary {
+                Some((_, dict)) => Ok(zstd::bulk::Decompressor::with_prepared_dictionary(dict)?
+                    .decompress(compressed, len)?),
+                None => Err(crate::Error::InvalidData(
+                    "Value was compressed with a dictionary, but none is configured".to_string(),
+                )),
+            },
+            _ => Err(Self::unsupported(codec)),
+        }
+    }
+
+    /// Get the uncompressed length of stored bytes without decoding them
+    pub fn decoded_len(flags: u8, stored: &[u8]) -> u64 {
+        if flags & CODEC_MASK == CODEC_NONE {
+            return stored.len() as u64;
+        }
+        let mut prefix = stored;
+        prefix.read_u64::<BE>().unwrap_or(0)
+    }
+}
+
+/// Trains a Zstandard dictionary from sample values, for use with
+/// [`Builder::set_compression_dictionary`](crate::Builder::set_compression_dictionary).
+///
+/// # Arguments
+///
+/// * `samples` - Representative values, ideally a few thousand
+/// * `max_size` - The maximum dictionary size in bytes, around 100 KiB is typical
+#[cfg(feature = "zstd")]
+pub fn train_compression_dictionary(
+    samples: &[Vec<u8>],
+    max_size: usize,
+) -> crate::Result<Vec<u8>> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(compression: Compression) -> Codec {
+        let config = Config::builder()
+            .set_compression(compression)
+            .set_compression_min_size(16)
+            .build();
+        Codec::with_config(&config).unwrap()
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn json() -> Vec<u8> {
+        br#"{"id":1,"name":"kving","tags":["a","b","c"]}"#.repeat(20)
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn stores_small_and_incompressible_values_raw() {
+        let codec = codec(Compression::Zstd(3));
+        assert_eq!(
+            codec.encode(b"short").unwrap(),
+            (CODEC_NONE, b"short".to_vec())
+        );
+
+        // Distinct bytes don't shrink
+        let noise: Vec<u8> = (0..=255).collect();
+        let (flags, stored) = codec.encode(&noise).unwrap();
+        assert_eq!(flags, CODEC_NONE);
+        assert_eq!(stored, noise);
+        assert_eq!(Codec::decoded_len(flags, &stored), 256);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn round_trips_lz4() {
+        let codec = codec(Compression::Lz4);
+        let (flags, stored) = codec.encode(&json()).unwrap();
+        assert_eq!(flags, CODEC_LZ4);
+        assert!(stored.len() < json().len());
+        assert_eq!(Codec::decoded_len(flags, &stored), json().len() as u64);
+        assert_eq!(codec.decode(flags, stored).unwrap(), json());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn round_trips_zstd_with_and_without_dictionary() {
+        let codec = codec(Compression::Zstd(3));
+        let (flags, stored) = codec.encode(&json()).unwrap();
+        assert_eq!(flags, CODEC_ZSTD);
+        assert_eq!(codec.decode(flags, stored).unwrap(), json());
+
+        let config = Config::builder()
+            .set_compression(Compression::Zstd(3))
+            .set_compression_min_size(16)
+            .set_compression_dictionary(br#"{"id":,"name":"","tags":[]}"#.to_vec())
+            .build();
+        let with_dictionary = Codec::with_config(&config).unwrap();
+        let (flags, stored) = with_dictionary.encode(&json()).unwrap();
+        assert_eq!(flags, CODEC_ZSTD_DICT);
+        assert_eq!(
+            with_dictionary.decode(flags, stored.clone()).unwrap(),
+            json()
+        );
+        // Without the dictionary the value can't be read back
+        assert!(matches!(
+            codec.decode(flags, stored),
+            Err(crate::Error::InvalidData(_))
+        ));
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn rejects_disabled_codecs() {
+        let config = Config::builder().set_compression(Compression::Lz4).build();
+        assert!(Codec::with_config(&config).is_err());
+        let stored = [0u8; LEN_PREFIX_SIZE + 4].to_vec();
+        assert!(codec(Compression::None).decode(CODEC_LZ4, stored).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn decodes_whatever_the_current_codec() {
+        let (flags, stored) = codec(Compression::Lz4).encode(&json()).unwrap();
+        assert_eq!(
+            codec(Compression::None).decode(flags, stored).unwrap(),
+            json()
+        );
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn rejects_corrupted_values() {
+        let codec = codec(Compression::Lz4);
+        let (flags, mut stored) = codec.encode(&json()).unwrap();
+        // Claim one byte more than the compressed data holds
+        let len = json().len() as u64 + 1;
+        stored[..LEN_PREFIX_SIZE].copy_from_slice(&len.to_be_bytes());
+        assert!(codec.decode(flags, stored.clone()).is_err());
+
+        stored.truncate(LEN_PREFIX_SIZE + 4);
+        assert!(codec.decode(flags, stored).is_err());
+    }
+}
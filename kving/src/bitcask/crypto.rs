@@ -0,0 +1,71 @@
+use crate::kving::config::Config;
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{AeadCore, AeadInPlace, KeyInit, XChaCha20Poly1305, XNonce, aead::OsRng};
+
+/// Bit of the record flags set when the key and value of a record are encrypted
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0100;
+
+/// Size of the nonce stored in the header of an encrypted record
+pub const NONCE_SIZE: u64 = 24;
+
+/// Size of the authentication tag appended to the value of an encrypted record
+pub const TAG_SIZE: u64 = 16;
+
+/// Encrypts and decrypts record payloads with XChaCha20-Poly1305.
+///
+/// The key and value of a record are sealed together under a random nonce, with the
+/// record header as associated data, so neither can be altered or swapped undetected.
+pub struct Cipher {
+    #[cfg(feature = "encryption")]
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Create a cipher from the encryption key of the config, `None` if encryption is disabled
+    pub fn with_config(config: &Config) -> crate::Result<Option<Self>> {
+        config.encryption_key().map(Self::new).transpose()
+    }
+
+    /// Create a cipher for a 256-bit key
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub fn new(key: &[u8; 32]) -> crate::Result<Self> {
+        #[cfg(feature = "encryption")]
+        return Ok(Self {
+            aead: XChaCha20Poly1305::new(key.into()),
+        });
+
+        #[cfg(not(feature = "encryption"))]
+        Err(crate::Error::InvalidData(
+            "Encryption is not enabled, see cargo features".to_string(),
+        ))
+    }
+
+    /// Encrypt `payload` in place, appending the tag, and return the nonce
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables, clippy::ptr_arg))]
+    pub fn encrypt(&self, header: &[u8], payload: &mut Vec<u8>) -> crate::Result<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            self.aead
+                .encrypt_in_place(&nonce, header, payload)
+                .map_err(|_| crate::Error::InvalidData("Failed to encrypt record".to_string()))?;
+            Ok(nonce.to_vec())
+        }
+
+        #[cfg(not(feature = "encryption"))]
+        Err(crate::Error::DecryptionFailed)
+    }
+
+    /// Decrypt `payload` in place, removing the tag
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables, clippy::ptr_arg))]
+    pub fn decrypt(&self, nonce: &[u8], header: &[u8], payload: &mut Vec<u8>) -> crate::Result<()> {
+        #[cfg(feature = "encryption")]
+        return self
+            .aead
+            .decrypt_in_place(XNonce::from_slice(nonce), header, payload)
+            .map_err(|_| crate::Error::DecryptionFailed);
+
+        #[cfg(not(feature = "encryption"))]
+        Err(crate::Error::DecryptionFailed)
+    }
+}
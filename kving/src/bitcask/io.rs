@@ -0,0 +1,508 @@
+use crate::kving::config::{Config, IoBackend};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+
+/// Bytes read ahead by each submission while loading a data file
+pub(crate) const LOAD_READ_AHEAD: usize = 1024 * 1024;
+
+/// A reader the record parsers can seek in
+pub(crate) trait ReadSeek: Read + Seek {}
+
+impl<R: Read + Seek> ReadSeek for R {}
+
+/// Performs the appends and reads of the data files with the configured backend
+pub(crate) struct DataIo {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    ring: Option<uring::Ring>,
+}
+
+impl DataIo {
+    /// Set up the backend of `config`, standard I/O if io_uring isn't available
+    pub(crate) fn with_config(config: &Config) -> Self {
+        match config.io_backend() {
+            IoBackend::Std => Self::std(),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::IoUring => Self {
+                ring: uring::Ring::new().ok(),
+            },
+            #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+            IoBackend::IoUring => Self::std(),
+        }
+    }
+
+    fn std() -> Self {
+        Self {
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring: None,
+        }
+    }
+
+    /// The ring to use, none once it failed since standard I/O takes over then.
+    /// Data files are opened in append mode, so appends go on at their end.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn ring(&self) -> Option<&uring::Ring> {
+        self.ring.as_ref().filter(|ring| !ring.is_broken())
+    }
+
+    /// Check if concurrent puts should be grouped into a single append
+    pub(crate) fn groups_appends(&self) -> bool {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if self.ring().is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Append `buf` to `file`, whose end is at `offset`
+    pub(crate) fn append(
+        &self,
+        file: &mut BufWriter<File>,
+        offset: u64,
+        buf: &[u8],
+    ) -> std::io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ring) = self.ring() {
+            file.flush()?;
+            return ring.write_all_at(file.get_ref(), buf, offset);
+        }
+        let _ = offset;
+        file.write_all(buf)?;
+        file.flush()
+    }
+
+    /// Flush `file` and wait for its data to reach the disk
+    pub(crate) fn sync(&self, file: &mut BufWriter<File>) -> std::io::Result<()> {
+        file.flush()?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ring) = self.ring() {
+            return ring.sync(file.get_ref());
+        }
+        file.get_ref().sync_all()
+    }
+
+    /// Call `f` with a reader of `file` that reads up to `read_ahead` bytes at once
+    pub(crate) fn with_reader<T>(
+        &self,
+        file: &mut BufReader<File>,
+        read_ahead: usize,
+        f: impl FnOnce(&mut dyn ReadSeek) -> T,
+    ) -> T {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(ring) = self.ring() {
+            return f(&mut uring::RingReader::new(
+                ring,
+                file.get_ref(),
+                read_ahead,
+            ));
+        }
+        let _ = read_ahead;
+        f(file)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring {
+    use io_uring::{IoUring, opcode, squeue, types};
+    use std::fs::File;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    /// Submission queue size, and the most reads one read-ahead submits
+    const RING_ENTRIES: u32 = 32;
+
+    /// Size of each read of a read-ahead
+    const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+    pub(super) struct Ring {
+        ring: Mutex<RingState>,
+        /// Set once a submission failed, the ring is no longer used
+        broken: AtomicBool,
+    }
+
+    struct RingState {
+        ring: IoUring,
+        /// `user_data` of the next entry. Each submission takes a new range, so
+        /// completions left over by an earlier submission are told apart.
+        next_user_data: u64,
+    }
+
+    impl Ring {
+        pub(super) fn new() -> std::io::Result<Self> {
+            Ok(Self {
+                ring: Mutex::new(RingState {
+                    ring: IoUring::new(RING_ENTRIES)?,
+                    next_user_data: 0,
+                }),
+                broken: AtomicBool::new(false),
+            })
+        }
+
+        /// Check if a submission failed, in which case standard I/O is used instead
+        pub(super) fn is_broken(&self) -> bool {
+            self.broken.load(Ordering::Relaxed)
+        }
+
+        /// Submit `entries` at once and wait for all of them.
+        /// Returns the result of each entry, in order.
+        ///
+        /// On error, the entries the kernel took are waited for before returning and the
+        /// ring is marked broken, so the others are never submitted.
+        ///
+        /// # Safety
+        /// The buffers of the entries must stay valid until this returns.
+        unsafe fn submit(&self, entries: &[squeue::Entry]) -> std::io::Result<Vec<i32>> {
+            let mut state = self
+                .ring
+                .lock()
+                .map_err(|_| std::io::Error::other("Failed to lock io_uring"))?;
+            if self.is_broken() {
+                return Err(std::io::Error::other("io_uring failed before"));
+            }
+            if entries.len() > RING_ENTRIES as usize {
+                return Err(std::io::Error::other("Too many io_uring entries at once"));
+            }
+            let base = state.next_user_data;
+            state.next_user_data = base.wrapping_add(entries.len() as u64);
+            let ring = &mut state.ring;
+
+            let mut pushed = 0;
+            let mut error = None;
+            for (index, entry) in entries.iter().enumerate() {
+                let entry = entry.clone().user_data(base.wrapping_add(index as u64));
+                // SAFETY: the caller keeps the buffers valid until the completions are reaped
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    error = Some(std::io::Error::other(
+                        "The io_uring submission queue is full",
+                    ));
+                    break;
+                }
+                pushed += 1;
+            }
+
+            let mut results = vec![0; entries.len()];
+            let mut completed = 0;
+            while completed < pushed {
+                if error.is_none() {
+                    match ring.submit_and_wait(pushed - completed) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => error = Some(e),
+                    }
+                } else {
+                    // Entries the kernel didn't take are never submitted once the ring is
+                    // broken, only those it took may still use the buffers
+                    if completed + ring.submission().len() >= pushed {
+                        break;
+                    }
+                    // Any system call lets the kernel post pending completions
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                for cqe in ring.completion() {
+                    let index = cqe.user_data().wrapping_sub(base) as usize;
+                    if let Some(result) = results.get_mut(index) {
+                        *result = cqe.result();
+                        completed += 1;
+                    }
+                }
+            }
+
+            match error {
+                Some(e) => {
+                    self.broken.store(true, Ordering::Relaxed);
+                    Err(e)
+                }
+                None => Ok(results),
+            }
+        }
+
+        /// Submit a no-op whose completion is left for the next submission to skip
+        #[cfg(test)]
+        pub(super) fn leave_stale_completion(&self) {
+            let mut state = self.ring.lock().unwrap();
+            let user_data = state.next_user_data;
+            state.next_user_data += 1;
+            let entry = opcode::Nop::new().build().user_data(user_data);
+            // SAFETY: a no-op uses no buffer
+            unsafe { state.ring.submission().push(&entry) }.unwrap();
+            state.ring.submit_and_wait(1).unwrap();
+        }
+
+        #[cfg(test)]
+        pub(super) fn mark_broken(&self) {
+            self.broken.store(true, Ordering::Relaxed);
+        }
+
+        /// Write all of `buf` at `offset`, resubmitting the rest after a short write
+        pub(super) fn write_all_at(
+            &self,
+            file: &File,
+            mut buf: &[u8],
+            mut offset: u64,
+        ) -> std::io::Result<()> {
+            let fd = types::Fd(file.as_raw_fd());
+            while !buf.is_empty() {
+                let entry = opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
+                    .offset(offset)
+                    .build();
+                // SAFETY: `buf` outlives the submission
+                let written = completed(unsafe { self.submit(&[entry]) }?[0])?;
+                if written == 0 {
+                    return Err(ErrorKind::WriteZero.into());
+                }
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+            Ok(())
+        }
+
+        /// Wait for the data of `file` to reach the disk
+        pub(super) fn sync(&self, file: &File) -> std::io::Result<()> {
+            let entry = opcode::Fsync::new(types::Fd(file.as_raw_fd())).build();
+            // SAFETY: no buffer is involved
+            completed(unsafe { self.submit(&[entry]) }?[0]).map(|_| ())
+        }
+
+        /// Read up to `buf.len()` bytes at `offset` with reads of `chunk_size` bytes
+        /// submitted at once. Returns the number of bytes read, less than requested only
+        /// at the end of the file.
+        fn read_at(
+            &self,
+            file: &File,
+            buf: &mut [u8],
+            offset: u64,
+            chunk_size: usize,
+        ) -> std::io::Result<usize> {
+            let fd = types::Fd(file.as_raw_fd());
+            let chunks = (0..buf.len())
+                .step_by(chunk_size)
+                .map(|start| start..buf.len().min(start + chunk_size))
+                .collect::<Vec<_>>();
+            let entries = chunks
+                .iter()
+                .map(|chunk| {
+                    let ptr = buf[chunk.clone()].as_mut_ptr();
+                    opcode::Read::new(fd, ptr, chunk.len() as u32)
+                        .offset(offset + chunk.start as u64)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            // SAFETY: `buf` outlives the submission, and the chunks don't overlap
+            let results = unsafe { self.submit(&entries) }?;
+
+            for (result, chunk) in results.into_iter().zip(chunks) {
+                let read = completed(result)?;
+                if read == 0 {
+                    return Ok(chunk.start);
+                }
+                if read < chunk.len() {
+                    // Short reads are only final at the end of the file
+                    let end = chunk.start + read;
+                    let rest =
+                        self.read_at(file, &mut buf[end..], offset + end as u64, chunk_size)?;
+                    return Ok(end + rest);
+                }
+            }
+            Ok(buf.len())
+        }
+    }
+
+    fn completed(result: i32) -> std::io::Result<usize> {
+        if result < 0 {
+            Err(std::io::Error::from_raw_os_error(-result))
+        } else {
+            Ok(result as usize)
+        }
+    }
+
+    /// Positional reader of a data file through the ring, keeping what it read ahead
+    pub(super) struct RingReader<'a> {
+        ring: &'a Ring,
+        file: &'a File,
+        read_ahead: usize,
+        /// File offset of the first byte of `buf`
+        base: u64,
+        buf: Vec<u8>,
+        pos: u64,
+    }
+
+    impl<'a> RingReader<'a> {
+        pub(super) fn new(ring: &'a Ring, file: &'a File, read_ahead: usize) -> Self {
+            Self {
+                ring,
+                file,
+                read_ahead: read_ahead.max(1),
+                base: 0,
+                buf: Vec::new(),
+                pos: 0,
+            }
+        }
+
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            let buffered = self.base..self.base + self.buf.len() as u64;
+            if !buffered.contains(&self.pos) {
+                self.buf.resize(self.read_ahead, 0);
+                let chunk_size =
+                    READ_CHUNK_SIZE.max(self.read_ahead.div_ceil(RING_ENTRIES as usize));
+                let read = self
+                    .ring
+                    .read_at(self.file, &mut self.buf, self.pos, chunk_size)?;
+                self.buf.truncate(read);
+                self.base = self.pos;
+            }
+            Ok(&self.buf[(self.pos - self.base) as usize..])
+        }
+    }
+
+    impl Read for RingReader<'_> {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            let available = self.fill_buf()?;
+            let len = available.len().min(out.len());
+            out[..len].copy_from_slice(&available[..len]);
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for RingReader<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::Current(delta) => {
+                    self.pos.checked_add_signed(delta).ok_or_else(|| {
+                        std::io::Error::new(ErrorKind::InvalidInput, "Seek before the start")
+                    })?
+                }
+                SeekFrom::End(_) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Unsupported,
+                        "Seeking from the end is not supported",
+                    ));
+                }
+            };
+            Ok(self.pos)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::SeekFrom;
+
+    fn data_io(backend: IoBackend) -> DataIo {
+        DataIo::with_config(&Config::builder().set_io_backend(backend).build())
+    }
+
+    #[test]
+    fn std_backend_appends_one_put_at_a_time() {
+        assert!(!data_io(IoBackend::Std).groups_appends());
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    #[test]
+    fn io_uring_falls_back_to_std_without_the_feature() {
+        assert!(!data_io(IoBackend::IoUring).groups_appends());
+    }
+
+    #[test]
+    fn appends_and_reads_back_with_either_backend() {
+        for backend in [IoBackend::Std, IoBackend::IoUring] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("data");
+            let io = data_io(backend);
+
+            let mut writer = BufWriter::new(File::create(&path).unwrap());
+            let first = vec![1u8; 300];
+            let second = vec![2u8; 500];
+            io.append(&mut writer, 0, &first).unwrap();
+            io.append(&mut writer, first.len() as u64, &second).unwrap();
+            io.sync(&mut writer).unwrap();
+
+            let mut reader = BufReader::new(File::open(&path).unwrap());
+            // A read-ahead smaller than the file makes the reader refill its buffer
+            let (all, tail) = io.with_reader(&mut reader, 128, |reader| {
+                let mut all = Vec::new();
+                reader.read_to_end(&mut all).unwrap();
+                reader.seek(SeekFrom::Start(250)).unwrap();
+                let mut tail = [0u8; 100];
+                reader.read_exact(&mut tail).unwrap();
+                (all, tail)
+            });
+            assert_eq!(all.len(), 800, "{backend:?}");
+            assert_eq!(&all[..300], &first[..], "{backend:?}");
+            assert_eq!(&all[300..], &second[..], "{backend:?}");
+            assert_eq!(&tail[..50], &[1; 50], "{backend:?}");
+            assert_eq!(&tail[50..], &[2; 50], "{backend:?}");
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn ring_reader_rejects_seeks_it_cannot_serve() {
+        // The kernel may not allow setting up a ring, in which case the backend is `Std`
+        let Ok(ring) = uring::Ring::new() else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = File::open(&path).unwrap();
+        let mut reader = uring::RingReader::new(&ring, &file, 4);
+
+        assert!(data_io(IoBackend::IoUring).groups_appends());
+        assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+        assert!(reader.seek(SeekFrom::End(0)).is_err());
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "6789");
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn ring_skips_completions_of_earlier_submissions() {
+        let Ok(ring) = uring::Ring::new() else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = File::open(&path).unwrap();
+
+        // The no-op completes with 0, which would pass for an empty read
+        ring.leave_stale_completion();
+        let mut reader = uring::RingReader::new(&ring, &file, 4);
+        let mut all = String::new();
+        reader.read_to_string(&mut all).unwrap();
+        assert_eq!(all, "0123456789");
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[test]
+    fn broken_ring_falls_back_to_std() {
+        let Ok(ring) = uring::Ring::new() else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let io = DataIo { ring: Some(ring) };
+        let mut writer = BufWriter::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap(),
+        );
+        io.append(&mut writer, 0, b"first ").unwrap();
+
+        io.ring.as_ref().unwrap().mark_broken();
+        assert!(!io.groups_appends());
+        // The offset is stale on purpose, appends go on at the end of the file
+        io.append(&mut writer, 0, b"second").unwrap();
+        io.sync(&mut writer).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"first second");
+    }
+}
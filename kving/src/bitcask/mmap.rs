@@ -0,0 +1,82 @@
+use crate::kving::config::Config;
+#[cfg(feature = "mmap")]
+use lru::LruCache;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+#[cfg(feature = "mmap")]
+use std::num::NonZeroUsize;
+#[cfg(feature = "mmap")]
+use std::sync::{Arc, Mutex};
+
+/// Memory maps of the immutable data files, at most `max_file_handle_caches` of them.
+///
+/// A file is mapped on its first read once it is no longer the active file. Values lent
+/// by `get_ref` hold their mapping, so a file can be forgotten and deleted while they are
+/// alive: on Unix the mapping stays readable until the last of them is dropped.
+pub struct MappedFiles {
+    #[cfg(feature = "mmap")]
+    maps: Mutex<LruCache<u64, Arc<Mmap>>>,
+}
+
+impl MappedFiles {
+    /// Create the maps if the config enables memory-mapped reads, `None` otherwise
+    pub fn with_config(config: &Config) -> crate::Result<Option<Self>> {
+        if !config.mmap_reads() {
+            return Ok(None);
+        }
+
+        #[cfg(feature = "mmap")]
+        return Ok(Some(Self {
+            maps: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.max_file_handle_caches() as usize)
+                    .expect("Failed to new lru cap"),
+            )),
+        }));
+
+        #[cfg(not(feature = "mmap"))]
+        Err(crate::Error::InvalidData(
+            "Memory-mapped reads are not enabled, see cargo features".to_string(),
+        ))
+    }
+
+    /// Get the map of the immutable data file at `path`, mapping it if needed
+    #[cfg(feature = "mmap")]
+    pub fn get(&self, file_id: u64, path: std::path::PathBuf) -> crate::Result<Arc<Mmap>> {
+        let mut maps = self.lock()?;
+        let map = maps.try_get_or_insert(file_id, || {
+            let file = std::fs::File::open(path)?;
+            // SAFETY: immutable data files are never written again, only deleted, which
+            // leaves existing maps readable
+            let map = unsafe { Mmap::map(&file) }?;
+            Ok::<_, crate::Error>(Arc::new(map))
+        })?;
+        Ok(Arc::clone(map))
+    }
+
+    /// Drop the maps of data files about to be deleted
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    pub fn forget(&self, file_ids: &[u64]) -> crate::Result<()> {
+        #[cfg(feature = "mmap")]
+        {
+            let mut maps = self.lock()?;
+            for file_id in file_ids {
+                maps.pop(file_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every map
+    pub fn clear(&self) -> crate::Result<()> {
+        #[cfg(feature = "mmap")]
+        self.lock()?.clear();
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    fn lock(&self) -> crate::Result<std::sync::MutexGuard<'_, LruCache<u64, Arc<Mmap>>>> {
+        self.maps
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock mapped files".to_string()))
+    }
+}
@@ -0,0 +1,1123 @@
+use crate::kving::config::Config;
+use crate::kving::kv_store::{BatchOp, KvStore};
+use crate::kving::lock::DirLock;
+use crate::kving::merge::MergeControl;
+use crate::kving::metrics;
+use crate::kving::stats::Stats;
+use crate::kving::verify::{CorruptRange, CorruptionKind, Throttle, VerifyReport};
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Size of a page, nodes occupy one or more contiguous pages
+const PAGE_SIZE: u64 = 4096;
+
+/// Target encoded size of a node, nodes above it are split
+const MAX_NODE_SIZE: usize = PAGE_SIZE as usize;
+
+/// Nodes below this size are merged with a sibling
+const MIN_NODE_SIZE: usize = MAX_NODE_SIZE / 4;
+
+/// Node header size: `crc(4) + payload_len(4)`, the payload starts with `type(1) + count(4)`
+const NODE_HEADER_SIZE: usize = 4 + 4 + 1 + 4;
+
+const LEAF_NODE: u8 = 1;
+const BRANCH_NODE: u8 = 2;
+
+/// Location of a node or of the free list in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ptr {
+    page: u64,
+    pages: u32,
+}
+
+impl Ptr {
+    fn offset(&self) -> u64 {
+        self.page * PAGE_SIZE
+    }
+
+    fn len(&self) -> usize {
+        self.pages as usize * PAGE_SIZE as usize
+    }
+}
+
+/// A decoded tree node
+#[derive(Debug, Clone)]
+enum Node {
+    /// Sorted key/value pairs
+    Leaf(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Children with the lower bound of their keys. The bound of the first child is
+    /// not used for lookups, every key smaller than the second bound belongs to it.
+    Branch(Vec<(Vec<u8>, Ptr)>),
+}
+
+impl Node {
+    fn leaf_entry_size(key: &[u8], value: &[u8]) -> usize {
+        4 + 4 + key.len() + value.len()
+    }
+
+    fn branch_entry_size(key: &[u8]) -> usize {
+        4 + key.len() + 8 + 4
+    }
+
+    /// Get the encoded size of the node
+    fn size(&self) -> usize {
+        NODE_HEADER_SIZE
+            + match self {
+                Node::Leaf(entries) => entries
+                    .iter()
+                    .map(|(k, v)| Self::leaf_entry_size(k, v))
+                    .sum::<usize>(),
+                Node::Branch(children) => children
+                    .iter()
+                    .map(|(k, _)| Self::branch_entry_size(k))
+                    .sum::<usize>(),
+            }
+    }
+
+    /// Get the lower bound of the keys of this node
+    fn first_key(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf(entries) => entries.first().map(|e| e.0.clone()),
+            Node::Branch(children) => children.first().map(|c| c.0.clone()),
+        }
+        .unwrap_or_default()
+    }
+
+    /// Split the node into nodes of at most `MAX_NODE_SIZE` bytes.
+    /// An entry larger than that gets a node of its own, an empty node yields nothing.
+    fn split(self) -> Vec<Node> {
+        fn pack<T>(entries: Vec<T>, size: impl Fn(&T) -> usize) -> Vec<Vec<T>> {
+            let mut parts = Vec::new();
+            let mut part = Vec::new();
+            let mut part_size = NODE_HEADER_SIZE;
+            for entry in entries {
+                let entry_size = size(&entry);
+                if !part.is_empty() && part_size + entry_size > MAX_NODE_SIZE {
+                    parts.push(std::mem::take(&mut part));
+                    part_size = NODE_HEADER_SIZE;
+                }
+                part_size += entry_size;
+                part.push(entry);
+            }
+            if !part.is_empty() {
+                parts.push(part);
+            }
+            parts
+        }
+
+        match self {
+            Node::Leaf(entries) => pack(entries, |(k, v)| Self::leaf_entry_size(k, v))
+                .into_iter()
+                .map(Node::Leaf)
+                .collect(),
+            Node::Branch(children) => pack(children, |(k, _)| Self::branch_entry_size(k))
+                .into_iter()
+                .map(Node::Branch)
+                .collect(),
+        }
+    }
+
+    /// Append the entries of the right sibling, whose lower bound is `right_key`
+    fn concat(self, right: Node, right_key: Vec<u8>) -> Node {
+        match (self, right) {
+            (Node::Leaf(mut left), Node::Leaf(right)) => {
+                left.extend(right);
+                Node::Leaf(left)
+            }
+            (Node::Branch(mut left), Node::Branch(mut right)) => {
+                if let Some(first) = right.first_mut() {
+                    first.0 = right_key;
+                }
+                left.extend(right);
+                Node::Branch(left)
+            }
+            _ => unreachable!("siblings are always at the same depth"),
+        }
+    }
+
+    /// Encode the node, padded to whole pages
+    fn encode(&self) -> crate::Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(self.size());
+        match self {
+            Node::Leaf(entries) => {
+                payload.write_u8(LEAF_NODE)?;
+                payload.write_u32::<BE>(entries.len() as u32)?;
+                for (key, value) in entries {
+                    payload.write_u32::<BE>(key.len() as u32)?;
+                    payload.write_u32::<BE>(value.len() as u32)?;
+                    payload.write_all(key)?;
+                    payload.write_all(value)?;
+                }
+            }
+            Node::Branch(children) => {
+                payload.write_u8(BRANCH_NODE)?;
+                payload.write_u32::<BE>(children.len() as u32)?;
+                for (key, ptr) in children {
+                    payload.write_u32::<BE>(key.len() as u32)?;
+                    payload.write_all(key)?;
+                    payload.write_u64::<BE>(ptr.page)?;
+                    payload.write_u32::<BE>(ptr.pages)?;
+                }
+            }
+        }
+        Ok(seal(payload))
+    }
+
+    /// Decode a node written by `encode`
+    fn decode(buf: &[u8]) -> crate::Result<Node> {
+        let mut payload = unseal(buf)?;
+        let node_type = payload.read_u8()?;
+        let count = payload.read_u32::<BE>()? as usize;
+        match node_type {
+            LEAF_NODE => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_len = payload.read_u32::<BE>()? as usize;
+                    let value_len = payload.read_u32::<BE>()? as usize;
+                    let key = read_bytes(&mut payload, key_len)?;
+                    let value = read_bytes(&mut payload, value_len)?;
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf(entries))
+            }
+            BRANCH_NODE => {
+                let mut children = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key_len = payload.read_u32::<BE>()? as usize;
+                    let key = read_bytes(&mut payload, key_len)?;
+                    let page = payload.read_u64::<BE>()?;
+                    let pages = payload.read_u32::<BE>()?;
+                    children.push((key, Ptr { page, pages }));
+                }
+                Ok(Node::Branch(children))
+            }
+            _ => Err(crate::Error::CorruptedData),
+        }
+    }
+}
+
+/// Prefix a payload with `crc(4) + len(4)` and pad it to whole pages
+fn seal(payload: Vec<u8>) -> Vec<u8> {
+    let mut hasher = Hasher::new();
+    hasher.update(&payload);
+    let total = 8 + payload.len();
+    // The capacity may exceed what was asked for, pad to the page boundary itself
+    let padded = total.div_ceil(PAGE_SIZE as usize) * PAGE_SIZE as usize;
+    let mut buf = Vec::with_capacity(padded);
+    buf.extend_from_slice(&hasher.finalize().to_be_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    buf.resize(padded, 0);
+    buf
+}
+
+/// Verify and strip the header added by `seal`
+fn unseal(mut buf: &[u8]) -> crate::Result<&[u8]> {
+    let stored_crc = buf.read_u32::<BE>()?;
+    let len = buf.read_u32::<BE>()? as usize;
+    if len > buf.len() {
+        return Err(crate::Error::CorruptedData);
+    }
+    let payload = &buf[..len];
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    if hasher.finalize() != stored_crc {
+        metrics::crc_failure("btree");
+        return Err(crate::Error::CorruptedData);
+    }
+    Ok(payload)
+}
+
+/// Read `len` bytes, failing instead of allocating if the buffer is shorter
+fn read_bytes(buf: &mut &[u8], len: usize) -> crate::Result<Vec<u8>> {
+    if len > buf.len() {
+        return Err(crate::Error::CorruptedData);
+    }
+    let mut bytes = vec![0; len];
+    buf.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Number of pages needed for `len` bytes
+fn pages_for(len: usize) -> u32 {
+    (len as u64).div_ceil(PAGE_SIZE) as u32
+}
+
+/// Root of a committed version of the tree, stored in one of the two meta pages
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    txid: u64,
+    root: Option<Ptr>,
+    freelist: Option<Ptr>,
+    /// Number of pages in use, pages past it are garbage of an interrupted transaction
+    page_count: u64,
+}
+
+impl Meta {
+    const MAGIC: u64 = 0x6B76_696E_6762_7472;
+
+    /// Number of meta pages at the start of the file
+    const PAGES: u64 = 2;
+
+    fn encode(&self) -> crate::Result<Vec<u8>> {
+        fn write_ptr(buf: &mut Vec<u8>, ptr: Option<Ptr>) -> crate::Result<()> {
+            let ptr = ptr.unwrap_or(Ptr { page: 0, pages: 0 });
+            buf.write_u64::<BE>(ptr.page)?;
+            buf.write_u32::<BE>(ptr.pages)?;
+            Ok(())
+        }
+
+        let mut payload = Vec::new();
+        payload.write_u64::<BE>(Self::MAGIC)?;
+        payload.write_u64::<BE>(self.txid)?;
+        write_ptr(&mut payload, self.root)?;
+        write_ptr(&mut payload, self.freelist)?;
+        payload.write_u64::<BE>(self.page_count)?;
+        Ok(seal(payload))
+    }
+
+    fn decode(buf: &[u8]) -> crate::Result<Meta> {
+        fn read_ptr(buf: &mut &[u8]) -> crate::Result<Option<Ptr>> {
+            let page = buf.read_u64::<BE>()?;
+            let pages = buf.read_u32::<BE>()?;
+            // Page 0 holds a meta page, so it never addresses a node
+            Ok((page != 0).then_some(Ptr { page, pages }))
+        }
+
+        let mut payload = unseal(buf)?;
+        if payload.read_u64::<BE>()? != Self::MAGIC {
+            return Err(crate::Error::CorruptedData);
+        }
+        Ok(Meta {
+            txid: payload.read_u64::<BE>()?,
+            root: read_ptr(&mut payload)?,
+            freelist: read_ptr(&mut payload)?,
+            page_count: payload.read_u64::<BE>()?,
+        })
+    }
+}
+
+/// Committed state of the tree
+struct State {
+    meta: Meta,
+    /// Free extents that can be allocated: first page -> page count
+    free: BTreeMap<u64, u32>,
+    /// Number of verifications walking a committed version of the tree
+    pins: usize,
+    /// Extents freed while a verification was running. They are kept out of `free` until
+    /// the last one ends, but already stored in the free list on disk.
+    deferred: Vec<Ptr>,
+    /// Number of clears, a verification overtaken by one walked a discarded version
+    clears: u64,
+}
+
+/// A write transaction working on a copy of the committed state
+struct Tx<'a> {
+    tree: &'a BTree,
+    meta: Meta,
+    free: BTreeMap<u64, u32>,
+    /// Pages allocated by this transaction, they can be reused right away once freed
+    fresh: HashSet<u64>,
+    /// Extents of the committed version that become free once this transaction commits
+    pending_free: Vec<Ptr>,
+}
+
+impl Tx<'_> {
+    /// Allocate an extent of `pages` pages, first fit from the free list
+    fn allocate(&mut self, pages: u32) -> Ptr {
+        let found = self
+            .free
+            .iter()
+            .find(|&(_, &len)| len >= pages)
+            .map(|(&page, &len)| (page, len));
+        let page = match found {
+            Some((page, len)) => {
+                self.free.remove(&page);
+                if len > pages {
+                    self.free.insert(page + pages as u64, len - pages);
+                }
+                page
+            }
+            None => {
+                let page = self.meta.page_count;
+                self.meta.page_count += pages as u64;
+                page
+            }
+        };
+        self.fresh.insert(page);
+        Ptr { page, pages }
+    }
+
+    /// Release an extent that is no longer referenced by this transaction
+    fn release(&mut self, ptr: Ptr) {
+        self.tree.forget_node(ptr);
+        if self.fresh.remove(&ptr.page) {
+            insert_free(&mut self.free, ptr);
+        } else {
+            self.pending_free.push(ptr);
+        }
+    }
+
+    /// Write a node to a newly allocated extent
+    fn write_node(&mut self, node: Node) -> crate::Result<Ptr> {
+        let buf = node.encode()?;
+        let ptr = self.allocate(pages_for(buf.len()));
+        self.tree.write_at(ptr.offset(), &buf)?;
+        if let Node::Branch(_) = node {
+            self.tree.cache_node(ptr, Arc::new(node));
+        }
+        Ok(ptr)
+    }
+
+    /// Apply an operation to the whole tree, replacing the root
+    fn apply(&mut self, op: &BatchOp) -> crate::Result<()> {
+        let nodes = match self.meta.root {
+            Some(root) => match self.modify(root, op)? {
+                Some(nodes) => {
+                    self.release(root);
+                    nodes
+                }
+                None => return Ok(()),
+            },
+            None => match op {
+                BatchOp::Put(key, value) => vec![Node::Leaf(vec![(key.clone(), value.clone())])],
+                BatchOp::Delete(_) => return Ok(()),
+            },
+        };
+        self.meta.root = self.build_root(nodes)?;
+        Ok(())
+    }
+
+    /// Write the top level nodes, growing or shrinking the tree height as needed
+    fn build_root(&mut self, mut nodes: Vec<Node>) -> crate::Result<Option<Ptr>> {
+        loop {
+            match nodes.len() {
+                0 => return Ok(None),
+                1 => match nodes.pop().unwrap() {
+                    // A branch with a single child is replaced by the child
+                    Node::Branch(mut children) if children.len() == 1 => {
+                        let (_, child) = children.pop().unwrap();
+                        return Ok(Some(child));
+                    }
+                    node => return Ok(Some(self.write_node(node)?)),
+                },
+                _ => {
+                    let mut children = Vec::with_capacity(nodes.len());
+                    for node in nodes {
+                        let key = node.first_key();
+                        children.push((key, self.write_node(node)?));
+                    }
+                    nodes = Node::Branch(children).split();
+                }
+            }
+        }
+    }
+
+    /// Apply an operation to the subtree at `ptr`, returning the nodes replacing it
+    /// (not written yet), or `None` if the subtree is unchanged
+    fn modify(&mut self, ptr: Ptr, op: &BatchOp) -> crate::Result<Option<Vec<Node>>> {
+        let key = match op {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) => key,
+        };
+        match Node::clone(&*self.tree.read_node(ptr)?) {
+            Node::Leaf(mut entries) => {
+                let idx = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key));
+                match (op, idx) {
+                    (BatchOp::Put(_, value), Ok(idx)) => entries[idx].1 = value.clone(),
+                    (BatchOp::Put(_, value), Err(idx)) => {
+                        entries.insert(idx, (key.clone(), value.clone()))
+                    }
+                    (BatchOp::Delete(_), Ok(idx)) => {
+                        entries.remove(idx);
+                    }
+                    (BatchOp::Delete(_), Err(_)) => return Ok(None),
+                }
+                Ok(Some(Node::Leaf(entries).split()))
+            }
+            Node::Branch(mut children) => {
+                let idx = child_index(&children, key);
+                let child = children[idx].1;
+                let Some(mut nodes) = self.modify(child, op)? else {
+                    return Ok(None);
+                };
+                self.release(child);
+
+                // Merge an underfull child with a sibling
+                let mut first = idx;
+                let mut last = idx;
+                if nodes.len() == 1 && nodes[0].size() < MIN_NODE_SIZE && children.len() > 1 {
+                    let node = nodes.pop().unwrap();
+                    let merged = if idx + 1 < children.len() {
+                        let (right_key, right_ptr) = children[idx + 1].clone();
+                        let right = Node::clone(&*self.tree.read_node(right_ptr)?);
+                        self.release(right_ptr);
+                        last = idx + 1;
+                        node.concat(right, right_key)
+                    } else {
+                        let left_ptr = children[idx - 1].1;
+                        let left = Node::clone(&*self.tree.read_node(left_ptr)?);
+                        self.release(left_ptr);
+                        first = idx - 1;
+                        left.concat(node, children[idx].0.clone())
+                    };
+                    nodes = merged.split();
+                }
+
+                let lower_bound = children[first].0.clone();
+                let mut replacement = Vec::with_capacity(nodes.len());
+                for (i, node) in nodes.into_iter().enumerate() {
+                    let key = if i == 0 {
+                        lower_bound.clone()
+                    } else {
+                        node.first_key()
+                    };
+                    replacement.push((key, self.write_node(node)?));
+                }
+                children.splice(first..=last, replacement);
+                Ok(Some(Node::Branch(children).split()))
+            }
+        }
+    }
+
+    /// Persist the free list and switch the root by writing the other meta page
+    fn commit(mut self, state: &mut State) -> crate::Result<()> {
+        // Extents of the committed version, including its free list, must not be
+        // overwritten before the new meta page is durable, so the new free list is
+        // allocated before they are added. Allocating never adds an entry to the list,
+        // so an extent sized for all entries is large enough.
+        let entries = self.free.len() + self.pending_free.len() + state.deferred.len() + 1;
+        let ptr = self.allocate(pages_for(8 + 4 + entries * 12));
+        let mut freed = std::mem::take(&mut self.pending_free);
+        freed.extend(self.meta.freelist.take());
+        // A running verification may still read them
+        if state.pins > 0 {
+            state.deferred.extend(freed);
+        } else {
+            for ptr in freed {
+                insert_free(&mut self.free, ptr);
+            }
+        }
+        // Give free pages at the end of the file back
+        if let Some((&page, &pages)) = self.free.iter().next_back()
+            && page + pages as u64 == self.meta.page_count
+        {
+            self.free.remove(&page);
+            self.meta.page_count = page;
+        }
+
+        let mut stored = self.free.clone();
+        for ptr in &state.deferred {
+            insert_free(&mut stored, *ptr);
+        }
+        let mut payload = Vec::with_capacity(4 + stored.len() * 12);
+        payload.write_u32::<BE>(stored.len() as u32)?;
+        for (&page, &pages) in &stored {
+            payload.write_u64::<BE>(page)?;
+            payload.write_u32::<BE>(pages)?;
+        }
+        let buf = seal(payload);
+        self.meta.freelist = Some(ptr);
+        self.tree.write_at(ptr.offset(), &buf)?;
+        self.tree.sync_data()?;
+
+        // The new version only becomes visible once its meta page is durable
+        self.meta.txid += 1;
+        let slot = self.meta.txid % Meta::PAGES;
+        self.tree.write_at(slot * PAGE_SIZE, &self.meta.encode()?)?;
+        self.tree.sync_data()?;
+        self.tree.truncate(self.meta.page_count)?;
+
+        state.meta = self.meta;
+        state.free = self.free;
+        Ok(())
+    }
+}
+
+/// Add an extent to a free list, coalescing it with its neighbours
+fn insert_free(free: &mut BTreeMap<u64, u32>, ptr: Ptr) {
+    let mut page = ptr.page;
+    let mut pages = ptr.pages;
+    if let Some((&prev, &prev_pages)) = free.range(..page).next_back()
+        && prev + prev_pages as u64 == page
+    {
+        free.remove(&prev);
+        page = prev;
+        pages += prev_pages;
+    }
+    if let Some(&next_pages) = free.get(&(page + pages as u64)) {
+        free.remove(&(page + pages as u64));
+        pages += next_pages;
+    }
+    free.insert(page, pages);
+}
+
+/// Index of the child of a branch whose subtree may hold `key`
+fn child_index(children: &[(Vec<u8>, Ptr)], key: &[u8]) -> usize {
+    children
+        .partition_point(|(k, _)| k.as_slice() <= key)
+        .saturating_sub(1)
+}
+
+/// A copy-on-write B+tree storage engine in a single file.
+///
+/// Every write transaction copies the path from the modified leaves to the root into
+/// free pages, then atomically switches to the new root by writing one of two meta pages.
+/// A crash at any point leaves the previous version intact. Branch nodes are cached in
+/// memory, so a point read takes a single file access for the leaf.
+pub struct BTree {
+    file: Mutex<File>,
+    state: RwLock<State>,
+    /// Decoded branch nodes by page. Pages are immutable until freed.
+    branch_cache: Mutex<HashMap<u64, Arc<Node>>>,
+    /// Released last, once the file is closed
+    _lock: DirLock,
+}
+
+impl BTree {
+    /// Open B+tree storage engine
+    pub fn with_config(config: Config) -> crate::Result<Self> {
+        if config.encryption_key().is_some() {
+            return Err(crate::Error::InvalidData(
+                "Encryption is not supported by the B+tree engine".to_string(),
+            ));
+        }
+        if config.read_only() {
+            return Err(crate::Error::InvalidData(
+                "Read-only mode is not supported by the B+tree engine".to_string(),
+            ));
+        }
+        let lock = DirLock::acquire(&config)?;
+        let path = config
+            .database_path()
+            .join(format!("data.{}", config.store_model().extension()));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        if file.metadata()?.len() < Meta::PAGES * PAGE_SIZE {
+            let meta = Meta {
+                txid: 0,
+                root: None,
+                freelist: None,
+                page_count: Meta::PAGES,
+            };
+            file.write_all(&meta.encode()?)?;
+            file.write_all(&meta.encode()?)?;
+            file.sync_all()?;
+        }
+
+        // Use the newest valid meta page
+        let mut buf = vec![0; PAGE_SIZE as usize];
+        let mut meta: Option<Meta> = None;
+        for slot in 0..Meta::PAGES {
+            file.seek(SeekFrom::Start(slot * PAGE_SIZE))?;
+            file.read_exact(&mut buf)?;
+            if let Ok(candidate) = Meta::decode(&buf)
+                && meta.is_none_or(|m| candidate.txid > m.txid)
+            {
+                meta = Some(candidate);
+            }
+        }
+        let meta = meta.ok_or(crate::Error::CorruptedData)?;
+
+        let tree = BTree {
+            file: Mutex::new(file),
+            state: RwLock::new(State {
+                meta,
+                free: BTreeMap::new(),
+                pins: 0,
+                deferred: Vec::new(),
+                clears: 0,
+            }),
+            branch_cache: Mutex::new(HashMap::new()),
+            _lock: lock,
+        };
+        if let Some(ptr) = meta.freelist {
+            let free = tree.read_freelist(ptr)?;
+            tree.state
+                .write()
+                .expect("Failed to write btree state")
+                .free = free;
+        }
+        Ok(tree)
+    }
+
+    fn lock_file(&self) -> crate::Result<std::sync::MutexGuard<'_, File>> {
+        self.file
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock btree file".to_string()))
+    }
+
+    fn read_at(&self, ptr: Ptr) -> crate::Result<Vec<u8>> {
+        let mut buf = vec![0; ptr.len()];
+        let mut file = self.lock_file()?;
+        file.seek(SeekFrom::Start(ptr.offset()))?;
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> crate::Result<()> {
+        let mut file = self.lock_file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Drop pages past `page_count` if the file is longer
+    fn truncate(&self, page_count: u64) -> crate::Result<()> {
+        let file = self.lock_file()?;
+        if file.metadata()?.len() > page_count * PAGE_SIZE {
+            file.set_len(page_count * PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&self) -> crate::Result<()> {
+        self.lock_file()?.sync_data()?;
+        Ok(())
+    }
+
+    /// Read the free list extent
+    fn read_freelist(&self, ptr: Ptr) -> crate::Result<BTreeMap<u64, u32>> {
+        let buf = self.read_at(ptr)?;
+        let mut payload = unseal(&buf)?;
+        let count = payload.read_u32::<BE>()?;
+        let mut free = BTreeMap::new();
+        for _ in 0..count {
+            let page = payload.read_u64::<BE>()?;
+            let pages = payload.read_u32::<BE>()?;
+            free.insert(page, pages);
+        }
+        Ok(free)
+    }
+
+    /// Read a node, branch nodes are served from the cache
+    fn read_node(&self, ptr: Ptr) -> crate::Result<Arc<Node>> {
+        if let Some(node) = self.cached_node(ptr) {
+            return Ok(node);
+        }
+        let node = Arc::new(Node::decode(&self.read_at(ptr)?)?);
+        if let Node::Branch(_) = *node {
+            self.cache_node(ptr, Arc::clone(&node));
+        }
+        Ok(node)
+    }
+
+    fn cached_node(&self, ptr: Ptr) -> Option<Arc<Node>> {
+        self.branch_cache
+            .lock()
+            .expect("Failed to lock branch cache")
+            .get(&ptr.page)
+            .cloned()
+    }
+
+    fn cache_node(&self, ptr: Ptr, node: Arc<Node>) {
+        self.branch_cache
+            .lock()
+            .expect("Failed to lock branch cache")
+            .insert(ptr.page, node);
+    }
+
+    fn forget_node(&self, ptr: Ptr) {
+        self.branch_cache
+            .lock()
+            .expect("Failed to lock branch cache")
+            .remove(&ptr.page);
+    }
+
+    /// Run the operations in one transaction
+    fn write_ops(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.write_state()?;
+        let mut tx = Tx {
+            tree: self,
+            meta: state.meta,
+            free: state.free.clone(),
+            fresh: HashSet::new(),
+            pending_free: Vec::new(),
+        };
+        for op in ops {
+            tx.apply(op)?;
+        }
+        tx.commit(&mut state)
+    }
+
+    fn write_state(&self) -> crate::Result<std::sync::RwLockWriteGuard<'_, State>> {
+        self.state
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write btree state".to_string()))
+    }
+
+    /// Take a pin on the committed version: the extents it uses aren't reused until the
+    /// pin is released. Returns the root and the number of clears so far.
+    fn pin(&self) -> crate::Result<(Option<Ptr>, u64)> {
+        let mut state = self.write_state()?;
+        state.pins += 1;
+        Ok((state.meta.root, state.clears))
+    }
+
+    /// Release a pin taken by `pin`, the last one makes the deferred extents free
+    fn unpin(&self) -> crate::Result<u64> {
+        let mut state = self.write_state()?;
+        state.pins -= 1;
+        if state.pins == 0 {
+            for ptr in std::mem::take(&mut state.deferred) {
+                insert_free(&mut state.free, ptr);
+            }
+        }
+        Ok(state.clears)
+    }
+
+    /// Read back a node and its children from the file, bypassing the cache.
+    /// Returns `false` if the verification was stopped.
+    fn verify_node(
+        &self,
+        ptr: Ptr,
+        report: &mut VerifyReport,
+        throttle: &mut Throttle,
+    ) -> crate::Result<bool> {
+        let node = match self.read_at(ptr).and_then(|buf| Node::decode(&buf)) {
+            Ok(node) => node,
+            Err(_) => {
+                report.add_corrupt_range(CorruptRange {
+                    file_id: 0,
+                    offset: ptr.offset(),
+                    len: ptr.len() as u64,
+                    kind: CorruptionKind::Checksum,
+                });
+                return Ok(true);
+            }
+        };
+        if !throttle.consume(ptr.len() as u64) {
+            return Ok(false);
+        }
+
+        match node {
+            Node::Leaf(entries) => report.records_checked += entries.len() as u64,
+            Node::Branch(children) => {
+                for (_, child) in children {
+                    if !self.verify_node(child, report, throttle)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Visit all entries in key order
+    fn for_each<F>(&self, ptr: Ptr, f: &mut F) -> crate::Result<()>
+    where
+        F: FnMut(&[u8], &[u8]),
+    {
+        match &*self.read_node(ptr)? {
+            Node::Leaf(entries) => entries.iter().for_each(|(k, v)| f(k, v)),
+            Node::Branch(children) => {
+                for (_, child) in children {
+                    self.for_each(*child, f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl KvStore for BTree {
+    fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        let state = self
+            .state
+            .read()
+            .map_err(|_| crate::Error::PoisonError("Failed to read btree state".to_string()))?;
+        let mut ptr = match state.meta.root {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        loop {
+            match &*self.read_node(ptr)? {
+                Node::Branch(children) => ptr = children[child_index(children, key)].1,
+                Node::Leaf(entries) => {
+                    let idx = entries.binary_search_by(|(k, _)| k.as_slice().cmp(key));
+                    return Ok(idx.ok().map(|idx| entries[idx].1.clone()));
+                }
+            }
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        self.write_ops(&[BatchOp::Put(key.to_vec(), value.to_vec())])
+    }
+
+    fn delete(&self, key: &[u8]) -> crate::Result<()> {
+        self.write_ops(&[BatchOp::Delete(key.to_vec())])
+    }
+
+    fn contains(&self, key: &[u8]) -> crate::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        self.write_ops(ops)
+    }
+
+    /// Keys are returned in ascending order
+    fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
+        let state = self
+            .state
+            .read()
+            .map_err(|_| crate::Error::PoisonError("Failed to read btree state".to_string()))?;
+        let mut keys = Vec::new();
+        if let Some(root) = state.meta.root {
+            self.for_each(root, &mut |k, _| keys.push(k.to_vec()))?;
+        }
+        Ok(keys)
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        let mut state = self.write_state()?;
+        let meta = Meta {
+            txid: state.meta.txid + 1,
+            root: None,
+            freelist: None,
+            page_count: Meta::PAGES,
+        };
+        self.write_at((meta.txid % Meta::PAGES) * PAGE_SIZE, &meta.encode()?)?;
+        self.sync_data()?;
+        self.truncate(Meta::PAGES)?;
+        self.branch_cache
+            .lock()
+            .expect("Failed to lock branch cache")
+            .clear();
+        state.meta = meta;
+        state.free = BTreeMap::new();
+        state.deferred.clear();
+        state.clears += 1;
+        Ok(())
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        self.lock_file()?.sync_all()?;
+        Ok(())
+    }
+
+    fn can_merge(&self) -> crate::Result<bool> {
+        Ok(false)
+    }
+
+    fn merge(&self, _control: &MergeControl) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// Walks the version of the tree committed when it starts, without blocking writers:
+    /// the extents they free meanwhile are only reused once the walk is over.
+    fn verify(
+        &self,
+        max_bytes_per_sec: Option<u64>,
+        stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        let (root, clears) = self.pin()?;
+        let mut report = VerifyReport {
+            files_checked: 1,
+            ..VerifyReport::default()
+        };
+        let mut throttle = Throttle::new(max_bytes_per_sec, stop);
+        let walked = match root {
+            Some(root) => self.verify_node(root, &mut report, &mut throttle),
+            None => Ok(true),
+        };
+        let cleared = self.unpin()? != clears;
+        report.interrupted = !walked?;
+        // The file was truncated and rewritten under the walk, what it read means nothing
+        if cleared {
+            report.interrupted = true;
+            report.corrupt_ranges.clear();
+        }
+        Ok(report)
+    }
+
+    fn catch_up(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> crate::Result<Stats> {
+        Ok(Stats {
+            key_count: self.list_keys()?.len() as u64,
+            ..Stats::default()
+        })
+    }
+
+    fn close(&self) -> crate::Result<()> {
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kving::config::StoreModel;
+    use std::path::Path;
+
+    fn config(dir: &Path) -> Config {
+        Config::builder()
+            .set_data_dir(dir.to_path_buf())
+            .set_store_model(StoreModel::BTree)
+            .build()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key-{:05}", i).into_bytes()
+    }
+
+    #[test]
+    fn seal_pads_to_whole_pages() {
+        let page = PAGE_SIZE as usize;
+        for len in [0, page - 8, page - 7, 3 * page] {
+            let payload = vec![7; len];
+            let sealed = seal(payload.clone());
+            assert_eq!(sealed.len(), (len + 8).div_ceil(page) * page);
+            assert_eq!(unseal(&sealed).unwrap(), payload.as_slice());
+        }
+    }
+
+    #[test]
+    fn unseal_rejects_corruption() {
+        let mut sealed = seal(b"payload".to_vec());
+        sealed[9] ^= 0xFF;
+        assert!(matches!(unseal(&sealed), Err(crate::Error::CorruptedData)));
+
+        let mut sealed = seal(b"payload".to_vec());
+        sealed[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(unseal(&sealed), Err(crate::Error::CorruptedData)));
+    }
+
+    #[test]
+    fn round_trips_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let large = vec![b'x'; 3 * PAGE_SIZE as usize];
+        {
+            let tree = BTree::with_config(config(dir.path())).unwrap();
+            for i in 0..2000 {
+                tree.put(&key(i), b"value").unwrap();
+            }
+            // Shrinks the leaves below their minimum size, merging them
+            for i in (0..2000).filter(|i| i % 4 != 0) {
+                tree.delete(&key(i)).unwrap();
+            }
+            tree.put(b"large", &large).unwrap();
+            tree.write_batch(&[
+                BatchOp::Put(key(1), b"batch".to_vec()),
+                BatchOp::Delete(key(0)),
+            ])
+            .unwrap();
+        }
+
+        let tree = BTree::with_config(config(dir.path())).unwrap();
+        assert_eq!(tree.get(&key(0)).unwrap(), None);
+        assert_eq!(tree.get(&key(1)).unwrap().as_deref(), Some(&b"batch"[..]));
+        assert_eq!(tree.get(&key(4)).unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(tree.get(&key(5)).unwrap(), None);
+        assert_eq!(tree.get(b"large").unwrap(), Some(large));
+        let keys = tree.list_keys().unwrap();
+        assert_eq!(keys.len(), 501);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(tree.stats().unwrap().key_count, 501);
+    }
+
+    #[test]
+    fn verify_reports_a_corrupted_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = {
+            let tree = BTree::with_config(config(dir.path())).unwrap();
+            tree.put(b"key", b"value").unwrap();
+            tree.state.read().unwrap().meta.root.unwrap()
+        };
+        let path = config(dir.path())
+            .database_path()
+            .join(format!("data.{}", StoreModel::BTree.extension()));
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[root.offset() as usize + NODE_HEADER_SIZE] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        let tree = BTree::with_config(config(dir.path())).unwrap();
+        assert!(matches!(tree.get(b"key"), Err(crate::Error::CorruptedData)));
+        let report = tree.verify(None, &AtomicBool::new(false)).unwrap();
+        assert_eq!(
+            report.corrupt_ranges,
+            vec![CorruptRange {
+                file_id: 0,
+                offset: root.offset(),
+                len: root.len() as u64,
+                kind: CorruptionKind::Checksum,
+            }]
+        );
+    }
+
+    /// Run `f` while a slow verification of `tree` walks it, then return its report
+    fn during_verify(tree: &BTree, f: impl FnOnce()) -> VerifyReport {
+        std::thread::scope(|scope| {
+            let verify = scope.spawn(|| tree.verify(Some(16 * 1024), &AtomicBool::new(false)));
+            while tree.state.read().unwrap().pins == 0 {
+                std::thread::yield_now();
+            }
+            f();
+            assert!(!verify.is_finished());
+            verify.join().unwrap().unwrap()
+        })
+    }
+
+    #[test]
+    fn verify_walks_its_version_without_blocking_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::with_config(config(dir.path())).unwrap();
+        for i in 0..200 {
+            tree.put(&key(i), &[b'v'; 100]).unwrap();
+        }
+
+        let report = during_verify(&tree, || {
+            for i in 0..200 {
+                tree.delete(&key(i)).unwrap();
+            }
+            for i in 200..300 {
+                tree.put(&key(i), &[b'w'; 100]).unwrap();
+            }
+        });
+        assert!(report.is_ok());
+        assert!(!report.interrupted);
+        assert_eq!(report.records_checked, 200);
+
+        let state = tree.state.read().unwrap();
+        assert_eq!(state.pins, 0);
+        assert!(state.deferred.is_empty());
+        drop(state);
+        assert_eq!(tree.list_keys().unwrap().len(), 100);
+        let report = tree.verify(None, &AtomicBool::new(false)).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.records_checked, 100);
+    }
+
+    #[test]
+    fn verify_overtaken_by_clear_reports_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = BTree::with_config(config(dir.path())).unwrap();
+        for i in 0..200 {
+            tree.put(&key(i), &[b'v'; 100]).unwrap();
+        }
+
+        let report = during_verify(&tree, || {
+            tree.clear().unwrap();
+            tree.put(b"key", b"value").unwrap();
+        });
+        assert!(report.interrupted);
+        assert!(report.corrupt_ranges.is_empty());
+        assert_eq!(tree.get(b"key").unwrap().as_deref(), Some(&b"value"[..]));
+    }
+}
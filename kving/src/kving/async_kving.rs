@@ -0,0 +1,722 @@
+use crate::kving::batch::WriteBatch;
+use crate::kving::config::Config;
+use crate::kving::kving::Kving;
+use crate::kving::listener::{Change, ListenerHandle};
+use crate::kving::stats::Stats;
+use crate::kving::verify::VerifyReport;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+
+/// Default number of blocking operations an [`AsyncKving`] runs at the same time.
+pub const DEFAULT_MAX_BLOCKING_TASKS: usize = 16;
+
+/// Number of entries buffered by the streams of [`AsyncKving::keys`] and
+/// [`AsyncKving::entries`] before the producer waits for the consumer.
+const STREAM_BUFFER: usize = 64;
+
+/// Async wrapper around [`Kving`] for tokio applications.
+///
+/// Every call runs on tokio's blocking thread pool, so file I/O and lock waits never
+/// stall the async executor. At most `max_blocking_tasks` calls of one instance run at
+/// the same time, the others wait for a slot without occupying a thread.
+///
+/// Cloning is cheap, clones share the store and the slots.
+#[derive(Clone)]
+pub struct AsyncKving {
+    inner: Arc<Kving>,
+    permits: Arc<Semaphore>,
+}
+
+/// An owned [`Change`], delivered by the stream of [`AsyncKving::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// A value was stored under the key.
+    Put(String),
+    /// The key was deleted.
+    Delete(String),
+    /// All data was cleared.
+    Clear,
+}
+
+impl From<&Change<'_>> for ChangeEvent {
+    fn from(change: &Change<'_>) -> Self {
+        match change {
+            Change::Put(key) => ChangeEvent::Put(key.to_string()),
+            Change::Delete(key) => ChangeEvent::Delete(key.to_string()),
+            Change::Clear => ChangeEvent::Clear,
+        }
+    }
+}
+
+impl AsyncKving {
+    /// Opens a store with the specified configuration on the blocking thread pool,
+    /// allowing [`DEFAULT_MAX_BLOCKING_TASKS`] concurrent operations.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration settings for the KV store
+    ///
+    /// # Returns
+    /// * `Result<Self>` - New AsyncKving instance or error if initialization fails
+    pub async fn with_config(config: Config) -> crate::Result<Self> {
+        let kving = join(tokio::task::spawn_blocking(move || Kving::with_config(config)).await)?;
+        Ok(Self::new(kving, DEFAULT_MAX_BLOCKING_TASKS))
+    }
+
+    /// Wraps an open store.
+    ///
+    /// # Arguments
+    /// * `kving` - The store to wrap
+    /// * `max_blocking_tasks` - Maximum number of operations running at the same time, at least 1
+    ///
+    /// # Returns
+    /// * `Self` - New AsyncKving instance
+    pub fn new(kving: Kving, max_blocking_tasks: usize) -> Self {
+        Self {
+            inner: Arc::new(kving),
+            permits: Arc::new(Semaphore::new(max_blocking_tasks.max(1))),
+        }
+    }
+
+    /// Returns the wrapped store, for calls that are cheap or not mirrored here.
+    /// Its methods block the calling thread.
+    ///
+    /// # Returns
+    /// * `&Kving` - The wrapped store
+    pub fn blocking(&self) -> &Kving {
+        &self.inner
+    }
+
+    /// Retrieves a signed integer value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<isize>` - The signed integer value if found and valid, None otherwise
+    pub async fn get_isize<K>(&self, key: K) -> Option<isize>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_isize(key))).await.ok()?
+    }
+
+    /// Retrieves an unsigned integer value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<usize>` - The unsigned integer value if found and valid, None otherwise
+    pub async fn get_usize<K>(&self, key: K) -> Option<usize>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_usize(key))).await.ok()?
+    }
+
+    /// Retrieves a 32-bit floating point value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<f32>` - The float value if found and valid, None otherwise
+    pub async fn get_f32<K>(&self, key: K) -> Option<f32>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_f32(key))).await.ok()?
+    }
+
+    /// Retrieves a 64-bit floating point value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<f64>` - The double value if found and valid, None otherwise
+    pub async fn get_f64<K>(&self, key: K) -> Option<f64>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_f64(key))).await.ok()?
+    }
+
+    /// Retrieves a boolean value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<bool>` - The boolean value if found and valid, None otherwise
+    pub async fn get_bool<K>(&self, key: K) -> Option<bool>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_bool(key))).await.ok()?
+    }
+
+    /// Retrieves a string value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<String>` - The string value if found and valid UTF-8, None otherwise
+    pub async fn get_string<K>(&self, key: K) -> Option<String>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_string(key)))
+            .await
+            .ok()?
+    }
+
+    /// Retrieves a binary blob value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Option<Vec<u8>>` - The binary data if found, None otherwise
+    pub async fn get_blob<K>(&self, key: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| Ok(kving.get_blob(key))).await.ok()?
+    }
+
+    /// Stores a signed integer value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Signed integer value to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_isize<K>(&self, key: K, value: isize) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.put_isize(key, value)).await
+    }
+
+    /// Stores an unsigned integer value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Unsigned integer value to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_usize<K>(&self, key: K, value: usize) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.put_usize(key, value)).await
+    }
+
+    /// Stores a 32-bit floating point value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Float value to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_f32<K>(&self, key: K, value: f32) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.put_f32(key, value)).await
+    }
+
+    /// Stores a 64-bit floating point value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Double value to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_f64<K>(&self, key: K, value: f64) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.put_f64(key, value)).await
+    }
+
+    /// Stores a boolean value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Boolean value to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_bool<K>(&self, key: K, value: bool) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.put_bool(key, value)).await
+    }
+
+    /// Stores a string value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - String value to store (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_string<K, V>(&self, key: K, value: V) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        let value = value.as_ref().to_string();
+        self.run(move |kving| kving.put_string(key, value)).await
+    }
+
+    /// Stores a binary blob value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to store under (can be any type that implements AsRef<str>)
+    /// * `value` - Binary data to store
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn put_blob<K, V>(&self, key: K, value: V) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+        V: Into<Vec<u8>>,
+    {
+        let key = key.as_ref().to_string();
+        let value = value.into();
+        self.run(move |kving| kving.put_blob(key, &value)).await
+    }
+
+    /// Deletes the value associated with the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to delete (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn delete<K>(&self, key: K) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.delete(key)).await
+    }
+
+    /// Checks if the store contains a value for the given key.
+    ///
+    /// # Arguments
+    /// * `key` - Key to check (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Result<bool>` - True if key exists, false otherwise, or error
+    pub async fn contains<K>(&self, key: K) -> crate::Result<bool>
+    where
+        K: AsRef<str>,
+    {
+        let key = key.as_ref().to_string();
+        self.run(move |kving| kving.contains(key)).await
+    }
+
+    /// Returns a list of all keys of the default namespace as strings.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - List of keys or error
+    pub async fn list_keys(&self) -> crate::Result<Vec<String>> {
+        self.run(|kving| kving.list_keys()).await
+    }
+
+    /// Streams the keys of the default namespace.
+    ///
+    /// The keys are listed once, when the stream is created, and delivered as the
+    /// stream is polled. The stream holds one of the blocking slots until it ends or is
+    /// dropped.
+    ///
+    /// # Returns
+    /// * `KeyStream` - Stream of keys, ending with an error if they can't be listed
+    pub async fn keys(&self) -> KeyStream {
+        let receiver = self
+            .produce(|kving, sender| match kving.list_keys() {
+                Ok(keys) => {
+                    for key in keys {
+                        if sender.blocking_send(Ok(key)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(error) => {
+                    let _ = sender.blocking_send(Err(error));
+                }
+            })
+            .await;
+        KeyStream { receiver }
+    }
+
+    /// Streams the keys of the default namespace with their values.
+    ///
+    /// The keys are listed once, when the stream is created, and each value is read as
+    /// the consumer catches up, so keys deleted in the meantime are skipped. The stream
+    /// holds one of the blocking slots until it ends or is dropped.
+    ///
+    /// # Returns
+    /// * `EntryStream` - Stream of key and value pairs, ending with an error if the keys
+    ///   can't be listed
+    pub async fn entries(&self) -> EntryStream {
+        let receiver = self
+            .produce(|kving, sender| match kving.list_keys() {
+                Ok(keys) => {
+                    for key in keys {
+                        let Some(value) = kving.get_blob(&key) else {
+                            continue;
+                        };
+                        if sender.blocking_send(Ok((key, value))).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(error) => {
+                    let _ = sender.blocking_send(Err(error));
+                }
+            })
+            .await;
+        EntryStream { receiver }
+    }
+
+    /// Subscribes to the changes of keys starting with `prefix`, see
+    /// [`Kving::register_listener`] for the matching rules.
+    ///
+    /// Changes are queued without bound until the stream is polled, and the
+    /// subscription ends when the stream is dropped.
+    ///
+    /// # Arguments
+    /// * `prefix` - Key prefix to listen to (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `ChangeStream` - Stream of the matching changes
+    pub fn subscribe<P>(&self, prefix: P) -> ChangeStream
+    where
+        P: AsRef<str>,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let handle = self.inner.register_listener(prefix, move |change| {
+            let _ = sender.send(ChangeEvent::from(change));
+        });
+        ChangeStream {
+            receiver,
+            _handle: handle,
+        }
+    }
+
+    /// Applies a batch of operations atomically.
+    ///
+    /// # Arguments
+    /// * `batch` - Operations to apply
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        self.run(move |kving| kving.write_batch(batch)).await
+    }
+
+    /// Clear all data, including the data of every namespace.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn clear(&self) -> crate::Result<()> {
+        self.run(|kving| kving.clear()).await
+    }
+
+    /// Synchronizes all pending writes to persistent storage.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.run(|kving| kving.sync()).await
+    }
+
+    /// Closes the store and releases any resources, see [`Kving::close`].
+    /// Prefer this over dropping the last clone, which waits for the background workers
+    /// on the current thread.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn close(&self) -> crate::Result<()> {
+        self.run(|kving| kving.close()).await
+    }
+
+    /// Stops the background work, then syncs and closes the store, see [`Kving::shutdown`].
+    ///
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the background work to stop
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub async fn shutdown(&self, timeout: Duration) -> crate::Result<()> {
+        self.run(move |kving| kving.shutdown(timeout)).await
+    }
+
+    /// Returns operational statistics, see [`Kving::stats`].
+    ///
+    /// # Returns
+    /// * `Result<Stats>` - Statistics of the database, or error
+    pub async fn stats(&self) -> crate::Result<Stats> {
+        self.run(|kving| kving.stats()).await
+    }
+
+    /// Merges the data files now, waiting for a running merge to complete first.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator, `Error::MergeCancelled` if the merge
+    ///   was cancelled
+    pub async fn merge(&self) -> crate::Result<()> {
+        self.run(|kving| kving.merge()).await
+    }
+
+    /// Checks all stored data for corruption while the store stays online, see
+    /// [`Kving::verify`].
+    ///
+    /// # Returns
+    /// * `Result<VerifyReport>` - Report of the problems found, or error
+    pub async fn verify(&self) -> crate::Result<VerifyReport> {
+        self.run(|kving| kving.verify()).await
+    }
+
+    /// Runs `f` on the blocking thread pool once a slot is free.
+    /// The slot moves into the task, so it stays taken if the caller stops waiting.
+    async fn run<T, F>(&self, f: F) -> crate::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Kving) -> crate::Result<T> + Send + 'static,
+    {
+        let permit = self.acquire().await;
+        let kving = Arc::clone(&self.inner);
+        join(
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                f(&kving)
+            })
+            .await,
+        )
+    }
+
+    /// Starts `f` on the blocking thread pool once a slot is free, feeding a bounded channel.
+    async fn produce<T, F>(&self, f: F) -> mpsc::Receiver<crate::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Kving, mpsc::Sender<crate::Result<T>>) + Send + 'static,
+    {
+        let permit = self.acquire().await;
+        let kving = Arc::clone(&self.inner);
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&kving, sender)
+        });
+        receiver
+    }
+
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed")
+    }
+}
+
+/// Unwraps the result of a blocking task, resuming its panic if it panicked.
+fn join<T>(result: Result<crate::Result<T>, tokio::task::JoinError>) -> crate::Result<T> {
+    match result {
+        Ok(result) => result,
+        Err(error) => match error.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(error) => Err(crate::Error::IOError(std::io::Error::other(error))),
+        },
+    }
+}
+
+/// Stream of keys returned by [`AsyncKving::keys`].
+pub struct KeyStream {
+    receiver: mpsc::Receiver<crate::Result<String>>,
+}
+
+impl Stream for KeyStream {
+    type Item = crate::Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Stream of key and value pairs returned by [`AsyncKving::entries`].
+pub struct EntryStream {
+    receiver: mpsc::Receiver<crate::Result<(String, Vec<u8>)>>,
+}
+
+impl Stream for EntryStream {
+    type Item = crate::Result<(String, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Stream of changes returned by [`AsyncKving::subscribe`].
+/// Never ends on its own, dropping it unregisters the listener.
+pub struct ChangeStream {
+    receiver: mpsc::UnboundedReceiver<ChangeEvent>,
+    _handle: ListenerHandle,
+}
+
+impl Stream for ChangeStream {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn config(dir: &tempfile::TempDir) -> Config {
+        Config::builder()
+            .set_data_dir(dir.path().to_path_buf())
+            .build()
+    }
+
+    async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[test]
+    fn round_trips_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        block_on(async {
+            let kving = AsyncKving::with_config(config(&dir)).await.unwrap();
+            kving.put_string("a", "value").await.unwrap();
+            kving.put_blob("b", [1, 2, 3]).await.unwrap();
+            kving.put_usize("c", 7).await.unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put_bool("d", true).delete("c");
+            kving.write_batch(batch).await.unwrap();
+            assert_eq!(kving.get_string("a").await.as_deref(), Some("value"));
+            assert!(!kving.contains("c").await.unwrap());
+            kving.close().await.unwrap();
+        });
+
+        block_on(async {
+            let kving = AsyncKving::with_config(config(&dir)).await.unwrap();
+            assert_eq!(kving.get_blob("b").await, Some(vec![1, 2, 3]));
+            assert_eq!(kving.get_usize("c").await, None);
+            assert_eq!(kving.get_bool("d").await, Some(true));
+            let mut keys = kving.list_keys().await.unwrap();
+            keys.sort();
+            assert_eq!(keys, ["a", "b", "d"]);
+        });
+    }
+
+    #[test]
+    fn streams_keys_entries_and_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        block_on(async {
+            let kving = AsyncKving::with_config(config(&dir)).await.unwrap();
+            let mut changes = kving.subscribe("a");
+            kving.put_string("a1", "x").await.unwrap();
+            kving.put_string("b1", "y").await.unwrap();
+            kving.delete("a1").await.unwrap();
+            kving.put_string("a2", "z").await.unwrap();
+            assert_eq!(
+                next(&mut changes).await,
+                Some(ChangeEvent::Put("a1".into()))
+            );
+            assert_eq!(
+                next(&mut changes).await,
+                Some(ChangeEvent::Delete("a1".into()))
+            );
+            assert_eq!(
+                next(&mut changes).await,
+                Some(ChangeEvent::Put("a2".into()))
+            );
+
+            let mut keys = Vec::new();
+            let mut stream = kving.keys().await;
+            while let Some(key) = next(&mut stream).await {
+                keys.push(key.unwrap());
+            }
+            keys.sort();
+            assert_eq!(keys, ["a2", "b1"]);
+
+            let mut entries = Vec::new();
+            let mut stream = kving.entries().await;
+            while let Some(entry) = next(&mut stream).await {
+                entries.push(entry.unwrap());
+            }
+            entries.sort();
+            assert_eq!(
+                entries,
+                [
+                    ("a2".to_string(), b"z".to_vec()),
+                    ("b1".to_string(), b"y".to_vec())
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn limits_the_concurrent_blocking_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let kving = Kving::with_config(config(&dir)).unwrap();
+        for i in 0..2 * STREAM_BUFFER {
+            kving.put_string(format!("key-{}", i), "value").unwrap();
+        }
+        block_on(async {
+            let kving = AsyncKving::new(kving, 1);
+            // The producer fills the buffer and waits, keeping the only slot
+            let stream = kving.keys().await;
+            let other = kving.clone();
+            let get = tokio::spawn(async move { other.get_string("key-1").await });
+            for _ in 0..20 {
+                tokio::task::yield_now().await;
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!get.is_finished());
+
+            drop(stream);
+            assert_eq!(get.await.unwrap().as_deref(), Some("value"));
+        });
+    }
+}
@@ -0,0 +1,210 @@
+use crate::kving::kv_store::BatchOp;
+use crate::kving::namespace::Namespace;
+
+/// A set of writes applied atomically by [`Kving::write_batch`](crate::Kving::write_batch).
+///
+/// Either every operation of the batch becomes visible or none does, also across a
+/// crash. Operations may target several namespaces and are applied in insertion order.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of queued operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Check if the batch has no queued operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Get the queued operations.
+    pub(crate) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Returns a view that queues operations into `namespace`.
+    ///
+    /// # Arguments
+    /// * `namespace` - Namespace the operations of the view apply to
+    pub fn namespace(&mut self, namespace: &Namespace) -> NamespaceBatch<'_> {
+        NamespaceBatch {
+            ops: &mut self.ops,
+            prefix: namespace.prefix().to_vec(),
+        }
+    }
+
+    /// Returns a view that queues operations into the default namespace.
+    fn default_namespace(&mut self) -> NamespaceBatch<'_> {
+        NamespaceBatch {
+            ops: &mut self.ops,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Queues a signed integer value for the given key.
+    pub fn put_isize<K>(&mut self, key: K, value: isize) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_isize(key, value);
+        self
+    }
+
+    /// Queues an unsigned integer value for the given key.
+    pub fn put_usize<K>(&mut self, key: K, value: usize) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_usize(key, value);
+        self
+    }
+
+    /// Queues a 32-bit floating point value for the given key.
+    pub fn put_f32<K>(&mut self, key: K, value: f32) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_f32(key, value);
+        self
+    }
+
+    /// Queues a 64-bit floating point value for the given key.
+    pub fn put_f64<K>(&mut self, key: K, value: f64) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_f64(key, value);
+        self
+    }
+
+    /// Queues a boolean value for the given key.
+    pub fn put_bool<K>(&mut self, key: K, value: bool) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_bool(key, value);
+        self
+    }
+
+    /// Queues a string value for the given key.
+    pub fn put_string<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.default_namespace().put_string(key, value);
+        self
+    }
+
+    /// Queues a binary blob value for the given key.
+    pub fn put_blob<K>(&mut self, key: K, value: &[u8]) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().put_blob(key, value);
+        self
+    }
+
+    /// Queues the deletion of the given key.
+    pub fn delete<K>(&mut self, key: K) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.default_namespace().delete(key);
+        self
+    }
+}
+
+/// Queues operations of a [`WriteBatch`] into a single namespace.
+pub struct NamespaceBatch<'a> {
+    ops: &'a mut Vec<BatchOp>,
+    prefix: Vec<u8>,
+}
+
+impl NamespaceBatch<'_> {
+    /// Build the stored key for `key` in this namespace.
+    fn key(&self, key: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.prefix.len() + key.len());
+        buf.extend_from_slice(&self.prefix);
+        buf.extend_from_slice(key.as_bytes());
+        buf
+    }
+
+    /// Queues a signed integer value for the given key.
+    pub fn put_isize<K>(&mut self, key: K, value: isize) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Queues an unsigned integer value for the given key.
+    pub fn put_usize<K>(&mut self, key: K, value: usize) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Queues a 32-bit floating point value for the given key.
+    pub fn put_f32<K>(&mut self, key: K, value: f32) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Queues a 64-bit floating point value for the given key.
+    pub fn put_f64<K>(&mut self, key: K, value: f64) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Queues a boolean value for the given key.
+    pub fn put_bool<K>(&mut self, key: K, value: bool) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, if value { &[1] } else { &[0] })
+    }
+
+    /// Queues a string value for the given key.
+    pub fn put_string<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.put_blob(key, value.as_ref().as_bytes())
+    }
+
+    /// Queues a binary blob value for the given key.
+    pub fn put_blob<K>(&mut self, key: K, value: &[u8]) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        let key = self.key(key.as_ref());
+        self.ops.push(BatchOp::Put(key, value.to_vec()));
+        self
+    }
+
+    /// Queues the deletion of the given key.
+    pub fn delete<K>(&mut self, key: K) -> &mut Self
+    where
+        K: AsRef<str>,
+    {
+        let key = self.key(key.as_ref());
+        self.ops.push(BatchOp::Delete(key));
+        self
+    }
+}
@@ -1,9 +1,20 @@
+use crate::kving::diagnostics::{ErrorEvent, ErrorHandler};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 pub enum StoreModel {
     Bitcask,
-    // todo more model
+    /// Keeps all data in memory, nothing is persisted.
+    Memory,
+    /// Log-structured merge-tree, for datasets whose keys don't fit in memory.
+    /// `max_file_size` is the memtable and table size, `max_historical_files` the
+    /// number of level 0 tables that triggers a compaction.
+    Lsm,
+    /// Copy-on-write B+tree in a single file, for read-heavy workloads.
+    /// Keys are kept in order.
+    BTree,
 }
 
 impl StoreModel {
@@ -15,11 +26,14 @@ impl StoreModel {
     pub fn extension(&self) -> String {
         match self {
             StoreModel::Bitcask => String::from("bsk"),
+            StoreModel::Memory => String::from("mem"),
+            StoreModel::Lsm => String::from("sst"),
+            StoreModel::BTree => String::from("btree"),
         }
     }
 
     /// Creates a StoreModel instance from an integer index.
-    /// Currently only Bitcask is supported, so any index will return Bitcask.
+    /// Unknown indexes fall back to Bitcask.
     ///
     /// # Arguments
     ///
@@ -27,24 +41,130 @@ impl StoreModel {
     ///
     /// # Returns
     ///
-    /// The corresponding StoreModel variant (currently always Bitcask)
+    /// The corresponding StoreModel variant
     pub fn with_index(index: i32) -> StoreModel {
         match index {
             0 => Self::Bitcask,
+            1 => Self::Memory,
+            2 => Self::Lsm,
+            3 => Self::BTree,
             _ => Self::Bitcask,
         }
     }
 }
 
+/// Compression codec applied to Bitcask values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// LZ4, fast with a moderate ratio. Requires the `lz4` feature.
+    Lz4,
+    /// Zstandard at the given level (1-22), uses the configured dictionary if any.
+    /// Requires the `zstd` feature.
+    Zstd(i32),
+}
+
+/// I/O backend of the Bitcask data files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Standard blocking reads and writes.
+    Std,
+    /// io_uring on Linux: concurrent puts are appended with a single submission, reads
+    /// are positional and loading reads ahead. Requires the `io-uring` feature, falls
+    /// back to `Std` without it or if the kernel doesn't allow setting up a ring.
+    IoUring,
+}
+
+/// A time of day range, in UTC, during which the merge scheduler may start merges.
+/// A window whose end is before its start wraps around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeWindow {
+    /// Start of the window, as the time elapsed since midnight.
+    pub start: Duration,
+    /// End of the window, as the time elapsed since midnight.
+    pub end: Duration,
+}
+
+impl MergeWindow {
+    /// Check if `time_of_day`, the time elapsed since midnight, is inside the window.
+    pub fn contains(&self, time_of_day: Duration) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            self.start <= time_of_day || time_of_day < self.end
+        }
+    }
+}
+
+/// When the data files are merged, and which ones.
+///
+/// Bitcask merges all historical files once `max_historical_files` is reached. Otherwise
+/// it only merges the files with the most garbage, once a threshold set here is reached.
+/// The active file is never merged. With a `check_interval`, a background scheduler
+/// checks the thresholds and merges on its own, only inside `windows` if any are set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergePolicy {
+    /// Fraction of the bytes of a historical file that are dead, from 0 to 1.
+    /// Every file reaching it is merged.
+    pub max_dead_ratio: Option<f64>,
+    /// Total dead bytes of the historical files. Once reached, the files with the most
+    /// dead bytes are merged until that much is reclaimed.
+    pub max_dead_bytes: Option<u64>,
+    /// Times of day during which the scheduler may start merges, empty for any time.
+    pub windows: Vec<MergeWindow>,
+    /// Interval between two checks of the scheduler, `None` to disable it.
+    pub check_interval: Option<Duration>,
+}
+
+impl MergePolicy {
+    /// Check if the scheduler may start a merge at `now`.
+    pub(crate) fn in_window(&self, now: SystemTime) -> bool {
+        if self.windows.is_empty() {
+            return true;
+        }
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let time_of_day = Duration::from_secs(since_epoch.as_secs() % (24 * 60 * 60));
+        self.windows
+            .iter()
+            .any(|window| window.contains(time_of_day))
+    }
+}
+
+/// A 256-bit encryption key, redacted from debug output.
+#[derive(Clone)]
+struct EncryptionKey(Arc<[u8; 32]>);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     data_dir: PathBuf,
     name: String,
     max_file_size: u64,
+    max_key_size: u64,
+    max_value_size: u64,
     max_file_handle_caches: u32,
     max_historical_files: u32,
     strict_crc_validation: bool,
+    merge_policy: MergePolicy,
+    merge_rate: u64,
     store_model: StoreModel,
+    compression: Compression,
+    compression_min_size: u64,
+    compression_dictionary: Option<Arc<Vec<u8>>>,
+    encryption_key: Option<EncryptionKey>,
+    scrub_interval: Option<Duration>,
+    scrub_rate: u64,
+    sync_interval: Option<Duration>,
+    lock_timeout: Option<Duration>,
+    read_only: bool,
+    io_backend: IoBackend,
+    mmap_reads: bool,
+    error_handler: Option<ErrorHandler>,
 }
 
 impl Default for Config {
@@ -53,10 +173,26 @@ impl Default for Config {
             data_dir: PathBuf::from("data"),
             name: String::from("bitcask"),
             max_file_size: 8 * 1024 * 1024,
+            max_key_size: 64 * 1024,
+            max_value_size: 256 * 1024 * 1024,
             max_file_handle_caches: 30,
             max_historical_files: 5,
             strict_crc_validation: false,
+            merge_policy: MergePolicy::default(),
+            merge_rate: 0,
             store_model: StoreModel::Bitcask,
+            compression: Compression::None,
+            compression_min_size: 256,
+            compression_dictionary: None,
+            encryption_key: None,
+            scrub_interval: None,
+            scrub_rate: 4 * 1024 * 1024,
+            sync_interval: None,
+            lock_timeout: None,
+            read_only: false,
+            io_backend: IoBackend::Std,
+            mmap_reads: false,
+            error_handler: None,
         }
     }
 }
@@ -75,7 +211,7 @@ impl Config {
     /// Get the full database path by joining data directory with name.
     /// Internal crate visibility only.
     pub(crate) fn database_path(&self) -> PathBuf {
-        (&self.data_dir).join(&self.name)
+        self.data_dir.join(&self.name)
     }
 
     /// Get the maximum file size in bytes.
@@ -83,6 +219,35 @@ impl Config {
         self.max_file_size
     }
 
+    /// Get the maximum key size in bytes.
+    pub fn max_key_size(&self) -> u64 {
+        self.max_key_size
+    }
+
+    /// Get the maximum value size in bytes.
+    pub fn max_value_size(&self) -> u64 {
+        self.max_value_size
+    }
+
+    /// Check that a key and value are within the configured size limits.
+    pub(crate) fn check_entry_size(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        if key.len() as u64 > self.max_key_size {
+            return Err(crate::Error::InvalidData(format!(
+                "Key of {} bytes exceeds the limit of {} bytes",
+                key.len(),
+                self.max_key_size
+            )));
+        }
+        if value.len() as u64 > self.max_value_size {
+            return Err(crate::Error::InvalidData(format!(
+                "Value of {} bytes exceeds the limit of {} bytes",
+                value.len(),
+                self.max_value_size
+            )));
+        }
+        Ok(())
+    }
+
     /// Get the maximum number of file handle caches.
     pub fn max_file_handle_caches(&self) -> u32 {
         self.max_file_handle_caches
@@ -99,11 +264,82 @@ impl Config {
         self.strict_crc_validation
     }
 
+    /// Get the policy deciding when the data files are merged.
+    pub fn merge_policy(&self) -> &MergePolicy {
+        &self.merge_policy
+    }
+
+    /// Get the maximum I/O rate of merges in bytes per second, zero for no limit.
+    pub fn merge_rate(&self) -> u64 {
+        self.merge_rate
+    }
+
     /// Get the storage model configuration.
     pub fn store_model(&self) -> &StoreModel {
         &self.store_model
     }
 
+    /// Get the compression codec for new values.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Get the minimum value size in bytes for compression to be attempted.
+    pub fn compression_min_size(&self) -> u64 {
+        self.compression_min_size
+    }
+
+    /// Get the Zstandard dictionary, if any.
+    pub fn compression_dictionary(&self) -> Option<&[u8]> {
+        self.compression_dictionary.as_ref().map(|d| d.as_slice())
+    }
+
+    /// Get the encryption key, if encryption at rest is enabled.
+    pub fn encryption_key(&self) -> Option<&[u8; 32]> {
+        self.encryption_key.as_ref().map(|k| k.0.as_ref())
+    }
+
+    /// Get the interval between background scrubs, `None` if the scrubber is disabled.
+    pub fn scrub_interval(&self) -> Option<Duration> {
+        self.scrub_interval
+    }
+
+    /// Get the maximum read rate of the scrubber in bytes per second, zero for no limit.
+    pub fn scrub_rate(&self) -> u64 {
+        self.scrub_rate
+    }
+
+    /// Get the interval between background syncs, `None` if writes are only synced by
+    /// `Kving::sync` and on close.
+    pub fn sync_interval(&self) -> Option<Duration> {
+        self.sync_interval
+    }
+
+    /// Get how long opening waits for the database lock, `None` to fail immediately.
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        self.lock_timeout
+    }
+
+    /// Check if the database is opened read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Get the I/O backend of the data files.
+    pub fn io_backend(&self) -> IoBackend {
+        self.io_backend
+    }
+
+    /// Check if immutable data files are read through memory maps.
+    pub fn mmap_reads(&self) -> bool {
+        self.mmap_reads
+    }
+
+    /// Get the callback receiving background errors, if any.
+    pub(crate) fn error_handler(&self) -> Option<&ErrorHandler> {
+        self.error_handler.as_ref()
+    }
+
     /// Create a new builder for Config.
     pub fn builder() -> Builder {
         Builder::new()
@@ -161,6 +397,31 @@ impl Builder {
         self
     }
 
+    /// Sets the maximum key size in bytes and returns the builder for method chaining.
+    /// Writes with a larger key are rejected, and records read from disk are checked
+    /// against the limit before their key is allocated.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The maximum key size in bytes, including the namespace prefix
+    pub fn set_max_key_size(mut self, size: u64) -> Builder {
+        self.config.max_key_size = size;
+        self
+    }
+
+    /// Sets the maximum value size in bytes and returns the builder for method chaining.
+    /// Writes with a larger value are rejected, and records read from disk are checked
+    /// against the limit before their value is allocated, so it can't be lowered below
+    /// the size of values already stored.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The maximum value size in bytes, before compression
+    pub fn set_max_value_size(mut self, size: u64) -> Builder {
+        self.config.max_value_size = size;
+        self
+    }
+
     /// Sets the maximum number of file handle caches and returns the builder for method chaining.
     ///
     /// # Arguments
@@ -191,6 +452,31 @@ impl Builder {
         self
     }
 
+    /// Sets the merge policy and returns the builder for method chaining.
+    /// The default policy only merges once `max_historical_files` is reached, and
+    /// starts no scheduler.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The thresholds, windows and check interval of merges
+    pub fn set_merge_policy(mut self, policy: MergePolicy) -> Builder {
+        self.config.merge_policy = policy;
+        self
+    }
+
+    /// Sets the maximum I/O rate of merges and returns the builder for method chaining.
+    /// The bytes read from the merged files and written to the new ones both count.
+    /// Rekeying is never limited.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes_per_sec` - The maximum number of bytes read and written per second, zero
+    ///   for no limit
+    pub fn set_merge_rate(mut self, bytes_per_sec: u64) -> Builder {
+        self.config.merge_rate = bytes_per_sec;
+        self
+    }
+
     /// Sets the storage model and returns the builder for method chaining.
     ///
     /// # Arguments
@@ -200,4 +486,198 @@ impl Builder {
         self.config.store_model = model;
         self
     }
+
+    /// Sets the compression codec for new values and returns the builder for method chaining.
+    /// Values are stored raw when compression doesn't make them smaller, and existing
+    /// values are recompressed with the current codec when they are merged.
+    ///
+    /// # Arguments
+    ///
+    /// * `compression` - The codec to apply to values
+    pub fn set_compression(mut self, compression: Compression) -> Builder {
+        self.config.compression = compression;
+        self
+    }
+
+    /// Sets the minimum value size for compression and returns the builder for method chaining.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - Values smaller than this many bytes are stored raw
+    pub fn set_compression_min_size(mut self, size: u64) -> Builder {
+        self.config.compression_min_size = size;
+        self
+    }
+
+    /// Sets a trained Zstandard dictionary and returns the builder for method chaining.
+    /// Values compressed with a dictionary can only be read with the same dictionary.
+    ///
+    /// # Arguments
+    ///
+    /// * `dictionary` - The dictionary, for example from `train_compression_dictionary`
+    pub fn set_compression_dictionary(mut self, dictionary: Vec<u8>) -> Builder {
+        self.config.compression_dictionary = Some(Arc::new(dictionary));
+        self
+    }
+
+    /// Sets the encryption key and returns the builder for method chaining.
+    /// The key and value of every new record are encrypted with XChaCha20-Poly1305,
+    /// existing records are encrypted when they are merged. Opening encrypted data with
+    /// a different key fails with `Error::DecryptionFailed`. Requires the `encryption`
+    /// feature, and is only supported by Bitcask.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The 256-bit key, use `Kving::rekey` to change it later
+    pub fn set_encryption_key(mut self, key: [u8; 32]) -> Builder {
+        self.config.encryption_key = Some(EncryptionKey(Arc::new(key)));
+        self
+    }
+
+    /// Enables the background scrubber and returns the builder for method chaining.
+    /// The scrubber runs `Kving::verify` periodically and reports corrupted data to the
+    /// error handler, the last report is available from `Kving::last_scrub_report`.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The time to wait between two scrubs
+    pub fn set_scrub_interval(mut self, interval: Duration) -> Builder {
+        self.config.scrub_interval = Some(interval);
+        self
+    }
+
+    /// Sets the maximum read rate of the scrubber and returns the builder for method chaining.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes_per_sec` - The maximum number of bytes read per second, zero for no limit
+    pub fn set_scrub_rate(mut self, bytes_per_sec: u64) -> Builder {
+        self.config.scrub_rate = bytes_per_sec;
+        self
+    }
+
+    /// Enables the background syncer and returns the builder for method chaining.
+    /// The syncer runs `Kving::sync` periodically, bounding how much of the writes a
+    /// crash of the machine can lose. Failures are reported to the error handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The time to wait between two syncs
+    pub fn set_sync_interval(mut self, interval: Duration) -> Builder {
+        self.config.sync_interval = Some(interval);
+        self
+    }
+
+    /// Sets how long opening waits for the database lock and returns the builder for method chaining.
+    /// A database can only be opened by one instance at a time, by default opening a locked
+    /// database fails immediately with `Error::Locked`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The maximum time to wait for another instance to close the database
+    pub fn set_lock_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Opens the database read-only and returns the builder for method chaining.
+    /// No file is created, written or deleted, not even the lock file, so databases on
+    /// read-only media can be opened. Writes fail with `Error::ReadOnly`. Only supported
+    /// by Bitcask.
+    ///
+    /// # Arguments
+    ///
+    /// * `read_only` - Whether to open the database read-only
+    pub fn set_read_only(mut self, read_only: bool) -> Builder {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Sets the I/O backend of the data files and returns the builder for method chaining.
+    /// Only used by Bitcask.
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - The backend used to append, read and load records
+    pub fn set_io_backend(mut self, backend: IoBackend) -> Builder {
+        self.config.io_backend = backend;
+        self
+    }
+
+    /// Sets whether immutable data files are read through memory maps and returns the
+    /// builder for method chaining. Data files never change once rotated, so their records
+    /// are read in place instead of through a file handle, and `Kving::get_ref` lends
+    /// uncompressed, unencrypted values without copying them. Only used by Bitcask,
+    /// requires the `mmap` feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to map the immutable data files
+    pub fn set_mmap_reads(mut self, enabled: bool) -> Builder {
+        self.config.mmap_reads = enabled;
+        self
+    }
+
+    /// Sets the callback receiving background errors and returns the builder for method chaining.
+    /// Merge, scrub, file deletion and close errors, and records skipped because they
+    /// failed their checksum, have no caller to be returned to. They are passed to the
+    /// callback, and logged as `tracing` warnings with the `tracing` feature. Without
+    /// either, they are printed to stderr.
+    ///
+    /// The callback runs on the thread where the error happened, often a background
+    /// thread, and must not call back into the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - Function invoked with each error
+    pub fn set_error_handler<F>(mut self, callback: F) -> Builder
+    where
+        F: Fn(&ErrorEvent) + Send + Sync + 'static,
+    {
+        self.config.error_handler = Some(ErrorHandler::new(callback));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn merge_windows_wrap_around_midnight() {
+        let day = MergeWindow {
+            start: 9 * HOUR,
+            end: 17 * HOUR,
+        };
+        assert!(day.contains(9 * HOUR));
+        assert!(!day.contains(17 * HOUR));
+        assert!(!day.contains(HOUR));
+
+        let night = MergeWindow {
+            start: 22 * HOUR,
+            end: 2 * HOUR,
+        };
+        assert!(night.contains(23 * HOUR));
+        assert!(night.contains(HOUR));
+        assert!(!night.contains(12 * HOUR));
+    }
+
+    #[test]
+    fn merge_policy_without_windows_is_always_in_window() {
+        let noon = UNIX_EPOCH + 12 * HOUR;
+        assert!(MergePolicy::default().in_window(noon));
+
+        let night = MergeWindow {
+            start: 22 * HOUR,
+            end: 2 * HOUR,
+        };
+        let policy = MergePolicy {
+            windows: vec![night],
+            ..MergePolicy::default()
+        };
+        assert!(!policy.in_window(noon));
+        assert!(policy.in_window(noon + 11 * HOUR + 24 * HOUR));
+    }
 }
@@ -0,0 +1,267 @@
+use std::sync::Arc;
+
+/// The operation during which an [`ErrorEvent`] happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorSource {
+    /// A record that failed its checksum was skipped while loading a data file.
+    Load,
+    /// A merge failed, or skipped a record that failed its checksum.
+    Merge,
+    /// The background scrubber failed, or found corrupted data.
+    Scrub,
+    /// A background sync failed.
+    Sync,
+    /// A data file could not be deleted after a merge or clear.
+    DeleteFile,
+    /// Flushing the data failed while dropping the store.
+    Close,
+}
+
+/// An error that happened in the background, or that was recovered from, and that no
+/// caller receives as a return value.
+#[derive(Debug)]
+pub struct ErrorEvent<'a> {
+    /// The operation during which the error happened.
+    pub source: ErrorSource,
+    /// The error itself.
+    pub error: &'a crate::Error,
+    /// The data file involved, if any.
+    pub file_id: Option<u64>,
+    /// The offset in the data file, if known.
+    pub offset: Option<u64>,
+}
+
+impl<'a> ErrorEvent<'a> {
+    pub(crate) fn new(source: ErrorSource, error: &'a crate::Error) -> Self {
+        Self {
+            source,
+            error,
+            file_id: None,
+            offset: None,
+        }
+    }
+
+    pub(crate) fn at(mut self, file_id: u64, offset: Option<u64>) -> Self {
+        self.file_id = Some(file_id);
+        self.offset = offset;
+        self
+    }
+}
+
+/// A callback receiving background errors, redacted from debug output.
+#[derive(Clone)]
+pub(crate) struct ErrorHandler(Arc<dyn Fn(&ErrorEvent) + Send + Sync>);
+
+impl ErrorHandler {
+    pub(crate) fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&ErrorEvent) + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+}
+
+impl std::fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorHandler(..)")
+    }
+}
+
+/// Report `event` to the installed error handler, and as a `tracing` warning with the
+/// `tracing` feature. Without either, it is printed to stderr.
+pub(crate) fn report(handler: Option<&ErrorHandler>, event: ErrorEvent) {
+    if let Some(handler) = handler {
+        (handler.0)(&event);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        source = ?event.source,
+        file_id = event.file_id,
+        offset = event.offset,
+        error = %event.error,
+        "kving background error"
+    );
+
+    #[cfg(not(feature = "tracing"))]
+    if handler.is_none() {
+        match (event.file_id, event.offset) {
+            (Some(file_id), Some(offset)) => eprintln!(
+                "{:?} error in data file {} at offset {}: {}",
+                event.source, file_id, offset, event.error
+            ),
+            (Some(file_id), None) => eprintln!(
+                "{:?} error in data file {}: {}",
+                event.source, file_id, event.error
+            ),
+            _ => eprintln!("{:?} error: {}", event.source, event.error),
+        }
+    }
+}
+
+/// Keeps a `tracing` span entered until dropped. Does nothing without the `tracing` feature.
+pub(crate) struct SpanGuard {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::EnteredSpan,
+}
+
+/// Enter the span of opening a database.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn open_span(config: &crate::Config) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _entered: tracing::info_span!(
+            "open",
+            path = %config.database_path().display(),
+            engine = ?config.store_model(),
+            read_only = config.read_only()
+        )
+        .entered(),
+    }
+}
+
+/// Enter the span of a merge.
+pub(crate) fn merge_span() -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _entered: tracing::info_span!("merge").entered(),
+    }
+}
+
+/// Enter the span of the rotation to the data file `file_id`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn rotation_span(file_id: u64) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "tracing")]
+        _entered: tracing::debug_span!("rotate", file_id).entered(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::{Builder, Config};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    fn config(dir: &Path) -> Builder {
+        Config::builder().set_data_dir(dir.to_path_buf())
+    }
+
+    /// Write `a` and `b` into a closed database, then flip the last byte of the value of `b`
+    fn write_and_corrupt(config: &Config) {
+        let kving = Kving::with_config(config.clone()).unwrap();
+        kving.put_string("a", "first").unwrap();
+        kving.put_string("b", "second").unwrap();
+        kving.close().unwrap();
+
+        let extension = config.store_model().extension();
+        let path = std::fs::read_dir(config.database_path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == extension.as_str())
+            })
+            .unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn reports_records_skipped_while_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        write_and_corrupt(&config(dir.path()).build());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        let config = config(dir.path())
+            .set_error_handler(move |event| {
+                events_clone.lock().unwrap().push((
+                    event.source,
+                    event.error.to_string(),
+                    event.file_id,
+                    event.offset,
+                ));
+            })
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        assert_eq!(kving.get_string("a").unwrap(), "first");
+        assert!(!kving.contains("b").unwrap());
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (source, error, file_id, offset) = &events[0];
+        assert_eq!(*source, ErrorSource::Load);
+        assert_eq!(*error, crate::Error::CorruptedData.to_string());
+        assert!(file_id.is_some());
+        assert!(offset.is_some_and(|offset| offset > 0));
+    }
+
+    #[test]
+    fn redacts_the_handler_from_debug_output() {
+        let config = Config::builder().set_error_handler(|_| {}).build();
+        assert!(format!("{:?}", config).contains("ErrorHandler(..)"));
+    }
+
+    #[cfg(feature = "tracing")]
+    mod tracing_output {
+        use super::*;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Level, Metadata, Subscriber};
+
+        /// Collects the names of new spans and the levels of events
+        #[derive(Default)]
+        struct Collector {
+            next_id: AtomicU64,
+            spans: Mutex<Vec<&'static str>>,
+            events: Mutex<Vec<Level>>,
+        }
+
+        impl Subscriber for &'static Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                self.spans.lock().unwrap().push(span.metadata().name());
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                self.events.lock().unwrap().push(*event.metadata().level());
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        #[test]
+        fn traces_opening_rotations_and_background_errors() {
+            let dir = tempfile::tempdir().unwrap();
+            let config = config(dir.path()).set_max_file_size(64).build();
+            write_and_corrupt(&config);
+
+            let collector: &'static Collector = Box::leak(Box::default());
+            tracing::subscriber::with_default(collector, || {
+                let kving = Kving::with_config(config).unwrap();
+                for key in ["c", "d", "e"] {
+                    kving.put_string(key, "value").unwrap();
+                }
+                kving.close().unwrap();
+            });
+            let spans = collector.spans.lock().unwrap();
+            assert!(spans.contains(&"open"));
+            assert!(spans.contains(&"rotate"));
+            assert!(collector.events.lock().unwrap().contains(&Level::WARN));
+        }
+    }
+}
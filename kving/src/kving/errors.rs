@@ -1,4 +1,5 @@
 use std::io::ErrorKind;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -18,6 +19,24 @@ pub enum Error {
     #[error("{0}")]
     InvalidData(String),
 
+    #[error("Decryption failed, the encryption key is wrong or the data was tampered with")]
+    DecryptionFailed,
+
+    #[error("Database {} is locked by another instance", .0.display())]
+    Locked(PathBuf),
+
+    #[error("The database is open read-only")]
+    ReadOnly,
+
+    #[error("The merge was cancelled")]
+    MergeCancelled,
+
+    #[error("Background work did not stop before the shutdown timeout")]
+    ShutdownTimeout,
+
+    #[error("{0} is not supported by this storage engine")]
+    Unsupported(&'static str),
+
     #[error("Remove failed")]
     RemoveError,
 
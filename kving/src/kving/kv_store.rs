@@ -1,21 +1,269 @@
+use crate::kving::merge::MergeControl;
+use crate::kving::stats::Stats;
+use crate::kving::value::ValueRef;
+use crate::kving::verify::VerifyReport;
+use std::sync::atomic::AtomicBool;
+
+/// A single operation of an atomic write batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// A storage engine usable behind [`Kving`](crate::Kving).
+///
+/// Only the basic operations are required. The others have defaults: `value_len` reads
+/// the value with `get`, and the engine-specific ones fail with `Error::Unsupported`.
+///
+/// Any key can be stored through [`Kving`](crate::Kving). Behind it, an engine only
+/// receives keys starting with the byte `0xFF` if it writes them itself, which leaves
+/// them free for internal records: `Kving` prefixes namespaced keys with `0xFE`, and
+/// keys of the default namespace starting with `0xFD`, `0xFE` or `0xFF` with `0xFD`.
 pub trait KvStore: Send + Sync {
+    /// Get the value stored under `key`.
     fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>>;
 
+    /// Get the value stored under `key`, lent without copying if the engine can.
+    /// Defaults to a copy made by `get`.
+    fn get_ref(&self, key: &[u8]) -> crate::Result<Option<ValueRef>> {
+        Ok(self.get(key)?.map(ValueRef::from))
+    }
+
+    /// Read the value stored under `key` into `buf`, replacing its contents so its
+    /// allocation can be reused. Returns `false`, with `buf` left empty, if there is none.
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> crate::Result<bool> {
+        buf.clear();
+        let value = self.get_ref(key)?;
+        buf.extend_from_slice(value.as_deref().unwrap_or_default());
+        Ok(value.is_some())
+    }
+
+    /// Store `value` under `key`, replacing any previous value.
     fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()>;
 
+    /// Delete `key`. Deleting a missing key is not an error.
     fn delete(&self, key: &[u8]) -> crate::Result<()>;
 
+    /// Check if a value is stored under `key`.
     fn contains(&self, key: &[u8]) -> crate::Result<bool>;
 
+    /// Get the size in bytes of the value stored under `key`.
+    fn value_len(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        Ok(self.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// Apply all `ops` atomically: either all of them become visible or none does.
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()>;
+
+    /// Get all keys.
     fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>>;
 
+    /// Delete all data.
     fn clear(&self) -> crate::Result<()>;
 
+    /// Flush all pending writes to persistent storage.
     fn sync(&self) -> crate::Result<()>;
 
+    /// Check if the engine has enough garbage for `merge` to be worthwhile.
     fn can_merge(&self) -> crate::Result<bool>;
 
-    fn merge(&self) -> crate::Result<()>;
+    /// Check, after every write, if the write left work that only `merge` clears. Must be
+    /// cheap, unlike `can_merge`, which the merge policy checks on its own schedule.
+    fn merge_pending(&self) -> bool {
+        false
+    }
+
+    /// Compact the stored data. Called from a background thread. `control` limits the
+    /// I/O rate, receives the progress and asks to stop with `Error::MergeCancelled`.
+    fn merge(&self, control: &MergeControl) -> crate::Result<()>;
+
+    /// Compact the data files `file_ids` only, like `merge`. Never called while `merge`
+    /// is running.
+    fn merge_files(&self, _file_ids: &[u64], _control: &MergeControl) -> crate::Result<()> {
+        Err(crate::Error::Unsupported("Merging chosen files"))
+    }
 
+    /// Rewrite all data encrypted with `key`. Never called while `merge` is running.
+    fn rekey(&self, _key: &[u8; 32]) -> crate::Result<()> {
+        Err(crate::Error::Unsupported("Encryption"))
+    }
+
+    /// Check the stored data for corruption, reading at most `max_bytes_per_sec`.
+    /// Returns early with a partial report once `stop` is set.
+    fn verify(
+        &self,
+        _max_bytes_per_sec: Option<u64>,
+        _stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        Err(crate::Error::Unsupported("Verification"))
+    }
+
+    /// Load the writes another instance made to the stored data since the last call.
+    /// Only secondary instances have anything to load, others return immediately.
+    fn catch_up(&self) -> crate::Result<()> {
+        Err(crate::Error::Unsupported("Catching up with a primary"))
+    }
+
+    /// Collect statistics about the stored data and the engine's caches.
+    fn stats(&self) -> crate::Result<Stats> {
+        Err(crate::Error::Unsupported("Statistics"))
+    }
+
+    /// Flush data and release resources.
     fn close(&self) -> crate::Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::{Config, StoreModel};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    /// An engine implementing only the required operations
+    #[derive(Default)]
+    struct MinimalStore(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+    impl KvStore for MinimalStore {
+        fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+            self.0.lock().unwrap().insert(key.to_vec(), value.to_vec());
+            Ok(())
+        }
+
+        fn delete(&self, key: &[u8]) -> crate::Result<()> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn contains(&self, key: &[u8]) -> crate::Result<bool> {
+            Ok(self.0.lock().unwrap().contains_key(key))
+        }
+
+        fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+            let mut data = self.0.lock().unwrap();
+            for op in ops {
+                match op {
+                    BatchOp::Put(key, value) => data.insert(key.clone(), value.clone()),
+                    BatchOp::Delete(key) => data.remove(key),
+                };
+            }
+            Ok(())
+        }
+
+        fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+
+        fn clear(&self) -> crate::Result<()> {
+            self.0.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn sync(&self) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn can_merge(&self) -> crate::Result<bool> {
+            Ok(false)
+        }
+
+        fn merge(&self, _control: &MergeControl) -> crate::Result<()> {
+            Ok(())
+        }
+
+        fn close(&self) -> crate::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn minimal_engine_works_behind_kving() {
+        let kving = Kving::with_store(MinimalStore::default()).unwrap();
+        kving.put_string("a", "value").unwrap();
+
+        assert_eq!(kving.get_string("a").as_deref(), Some("value"));
+        assert_eq!(kving.value_len("a").unwrap(), Some(5));
+        let mut buf = Vec::new();
+        assert!(kving.get_into("a", &mut buf).unwrap());
+        assert_eq!(buf, b"value");
+        assert!(matches!(kving.stats(), Err(crate::Error::Unsupported(_))));
+        assert!(matches!(
+            kving.rekey([0; 32]),
+            Err(crate::Error::Unsupported(_))
+        ));
+        assert!(matches!(kving.verify(), Err(crate::Error::Unsupported(_))));
+        kving.close().unwrap();
+    }
+
+    /// Check the copy-free reads of `kving`, whose `a` holds "value" and `empty` nothing
+    fn check_reads(kving: &Kving, engine: &str) {
+        // Large enough for any record, so no engine needs to grow it
+        let mut buf = Vec::with_capacity(1024);
+        buf.extend_from_slice(b"previous contents");
+        let capacity = buf.capacity();
+        assert!(kving.get_into("a", &mut buf).unwrap(), "{engine}");
+        assert_eq!(buf, b"value", "{engine}");
+        assert_eq!(buf.capacity(), capacity, "{engine}");
+        assert!(kving.get_into("empty", &mut buf).unwrap(), "{engine}");
+        assert!(buf.is_empty(), "{engine}");
+        buf.extend_from_slice(b"stale");
+        assert!(!kving.get_into("missing", &mut buf).unwrap(), "{engine}");
+        assert!(buf.is_empty(), "{engine}");
+
+        assert_eq!(
+            kving.get_with("a", <[u8]>::len).unwrap(),
+            Some(5),
+            "{engine}"
+        );
+        assert_eq!(
+            kving
+                .get_with("a", |value| value.to_ascii_uppercase())
+                .unwrap(),
+            Some(b"VALUE".to_vec()),
+            "{engine}"
+        );
+        assert_eq!(kving.get_with("missing", |_| ()).unwrap(), None, "{engine}");
+
+        assert_eq!(kving.value_len("a").unwrap(), Some(5), "{engine}");
+        assert_eq!(kving.value_len("empty").unwrap(), Some(0), "{engine}");
+        assert_eq!(kving.value_len("missing").unwrap(), None, "{engine}");
+        assert_eq!(kving.value_len("gone").unwrap(), None, "{engine}");
+    }
+
+    #[test]
+    fn reads_into_buffers_and_closures_with_every_engine() {
+        for model in [
+            StoreModel::Bitcask,
+            StoreModel::Memory,
+            StoreModel::Lsm,
+            StoreModel::BTree,
+        ] {
+            let engine = format!("{model:?}");
+            let persistent = !matches!(model, StoreModel::Memory);
+            let dir = tempfile::tempdir().unwrap();
+            let config = Config::builder()
+                .set_data_dir(dir.path().to_path_buf())
+                .set_store_model(model)
+                .build();
+            let kving = Kving::with_config(config.clone()).unwrap();
+            kving.put_string("a", "value").unwrap();
+            kving.put_string("empty", "").unwrap();
+            kving.put_string("gone", "value").unwrap();
+            kving.delete("gone").unwrap();
+            check_reads(&kving, &engine);
+            if !persistent {
+                continue;
+            }
+
+            drop(kving);
+            let kving = Kving::with_config(config).unwrap();
+            check_reads(&kving, &engine);
+        }
+    }
+}
@@ -1,18 +1,28 @@
 use crate::bitcask::bitcask::Bitcask;
-use crate::kving::config::Config;
-use crate::kving::kv_store::KvStore;
-use core::f32;
-use std::{
-    f64, isize,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
-    },
-};
+use crate::btree::btree::BTree;
+use crate::kving::batch::WriteBatch;
+use crate::kving::config::{Config, StoreModel};
+use crate::kving::diagnostics;
+use crate::kving::kv_store::{BatchOp, KvStore};
+use crate::kving::listener::{Change, ListenerHandle, Listeners};
+use crate::kving::merge::{MergeControl, MergeProgress, MergeScheduler, Merger};
+use crate::kving::metrics;
+use crate::kving::namespace::{self, Namespace};
+use crate::kving::stats::Stats;
+use crate::kving::value::ValueRef;
+use crate::kving::verify::{RepairReport, Scrubber, VerifyReport};
+use crate::kving::workers::{Syncer, Workers};
+use crate::lsm::lsm::Lsm;
+use crate::memory::memory::Memory;
+use std::sync::{Arc, atomic::AtomicBool};
+use std::time::{Duration, Instant};
 
 pub struct Kving {
+    config: Config,
     store: Arc<Box<dyn KvStore>>,
-    is_merging: Arc<AtomicBool>,
+    merger: Merger,
+    listeners: Listeners,
+    workers: Workers,
 }
 
 unsafe impl Send for Kving {}
@@ -21,7 +31,9 @@ unsafe impl Sync for Kving {}
 
 impl Kving {
     /// Creates a new Kving instance with the specified configuration.
-    /// Initializes the underlying storage and starts a background merge process.
+    /// Initializes the storage engine selected by the configured `StoreModel`
+    /// and starts a background merge process, and the scrubber and merge scheduler if
+    /// configured.
     ///
     /// # Arguments
     /// * `config` - Configuration settings for the KV store
@@ -29,14 +41,107 @@ impl Kving {
     /// # Returns
     /// * `Result<Self>` - New Kving instance or error if initialization fails
     pub fn with_config(config: Config) -> crate::Result<Self> {
-        let kving = Self {
-            store: Arc::new(Box::new(Bitcask::with_config(config)?)),
-            is_merging: Arc::new(AtomicBool::new(false)),
+        let _span = diagnostics::open_span(&config);
+        let store: Box<dyn KvStore> = match config.store_model() {
+            StoreModel::Bitcask => Box::new(Bitcask::with_config(config.clone())?),
+            StoreModel::Memory => Box::new(Memory::with_config(config.clone())?),
+            StoreModel::Lsm => Box::new(Lsm::with_config(config.clone())?),
+            StoreModel::BTree => Box::new(BTree::with_config(config.clone())?),
         };
-        kving.merge_transactions(true)?;
+        Self::with_boxed_store(store, &config)
+    }
+
+    /// Creates a new Kving instance on top of a custom storage engine.
+    /// Starts a background merge process like [`Kving::with_config`].
+    ///
+    /// # Arguments
+    /// * `store` - Any storage engine implementing `KvStore`
+    ///
+    /// # Returns
+    /// * `Result<Self>` - New Kving instance or error if initialization fails
+    pub fn with_store<S>(store: S) -> crate::Result<Self>
+    where
+        S: KvStore + 'static,
+    {
+        Self::with_boxed_store(Box::new(store), &Config::default())
+    }
+
+    /// Opens a read-only secondary instance on a database that another instance,
+    /// possibly in another process, has open for writing.
+    ///
+    /// The secondary takes no lock and never creates, writes or deletes a file, writes
+    /// fail with `Error::ReadOnly`. It sees the data as of opening, call
+    /// [`Kving::try_catch_up`] to load what the primary wrote since. Only supported by
+    /// Bitcask.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration of the primary, including its encryption key
+    ///
+    /// # Returns
+    /// * `Result<Self>` - New read-only Kving instance or error if opening fails
+    pub fn open_secondary(config: Config) -> crate::Result<Self> {
+        let _span = diagnostics::open_span(&config);
+        let store: Box<dyn KvStore> = match config.store_model() {
+            StoreModel::Bitcask => Box::new(Bitcask::open_read_only(config.clone())?),
+            _ => {
+                return Err(crate::Error::InvalidData(
+                    "Secondary instances are only supported by Bitcask".to_string(),
+                ));
+            }
+        };
+        Ok(Self::wrap_store(store, &config))
+    }
+
+    /// Wraps an opened storage engine.
+    fn with_boxed_store(store: Box<dyn KvStore>, config: &Config) -> crate::Result<Self> {
+        let kving = Self::wrap_store(store, config);
+        if !config.read_only() {
+            kving.merge_transactions(true)?;
+        }
         Ok(kving)
     }
 
+    /// Wraps an opened storage engine without starting a merge.
+    fn wrap_store(store: Box<dyn KvStore>, config: &Config) -> Self {
+        let store = Arc::new(store);
+        let scrubber = config.scrub_interval().map(|interval| {
+            let rate = Some(config.scrub_rate()).filter(|rate| *rate > 0);
+            Scrubber::start(
+                Arc::clone(&store),
+                interval,
+                rate,
+                config.error_handler().cloned(),
+            )
+        });
+        let merger = Merger::new(
+            Arc::clone(&store),
+            config.merge_rate(),
+            config.error_handler().cloned(),
+        );
+        let policy = config.merge_policy();
+        let merge_scheduler = policy
+            .check_interval
+            .filter(|_| !config.read_only())
+            .map(|interval| MergeScheduler::start(merger.clone(), policy.clone(), interval));
+        let syncer = config
+            .sync_interval()
+            .filter(|_| !config.read_only())
+            .map(|interval| {
+                Syncer::start(
+                    Arc::clone(&store),
+                    interval,
+                    config.error_handler().cloned(),
+                )
+            });
+        Self {
+            config: config.clone(),
+            store,
+            workers: Workers::new(merger.clone(), scrubber, merge_scheduler, syncer),
+            merger,
+            listeners: Listeners::default(),
+        }
+    }
+
     /// Retrieves a signed integer value for the given key.
     ///
     /// # Arguments
@@ -49,15 +154,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            match value.try_into() {
-                Ok(bytes) => Some(isize::from_be_bytes(bytes)),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        decode_isize(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves an unsigned integer value for the given key.
@@ -72,15 +169,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            match value.try_into() {
-                Ok(bytes) => Some(usize::from_be_bytes(bytes)),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        decode_usize(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves a 32-bit floating point value for the given key.
@@ -95,15 +184,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            match value.try_into() {
-                Ok(bytes) => Some(f32::from_be_bytes(bytes)),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        decode_f32(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves a 64-bit floating point value for the given key.
@@ -118,15 +199,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            match value.try_into() {
-                Ok(bytes) => Some(f64::from_be_bytes(bytes)),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        decode_f64(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves a boolean value for the given key.
@@ -141,16 +214,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            if value.len() == 1 {
-                Some(if value[0] == 1 { true } else { false })
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        decode_bool(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves a string value for the given key.
@@ -165,15 +229,7 @@ impl Kving {
         K: AsRef<str>,
     {
         let key = key.as_ref();
-        let result = self.get(key.as_bytes()).ok()?;
-        if let Some(value) = result {
-            match String::from_utf8(value) {
-                Ok(str) => Some(str),
-                Err(_) => None,
-            }
-        } else {
-            None
-        }
+        decode_string(self.get(key.as_bytes()).ok()??)
     }
 
     /// Retrieves a binary blob value for the given key.
@@ -191,6 +247,79 @@ impl Kving {
         self.get(key.as_bytes()).ok()?
     }
 
+    /// Retrieves a binary blob value for the given key without copying it when possible.
+    ///
+    /// With memory-mapped reads enabled, an uncompressed and unencrypted value of an
+    /// immutable Bitcask data file is borrowed from the map of its file. Other values are
+    /// copied into the returned guard, like [`Kving::get_blob`].
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Result<Option<ValueRef>>` - Guard dereferencing to the value if found, or error
+    pub fn get_ref<K>(&self, key: K) -> crate::Result<Option<ValueRef>>
+    where
+        K: AsRef<str>,
+    {
+        (self as &dyn KvStore).get_ref(key.as_ref().as_bytes())
+    }
+
+    /// Reads the value for the given key into a caller-provided buffer.
+    ///
+    /// The contents of `buf` are replaced, reusing its allocation, so reading many values
+    /// through the same buffer avoids allocating one per value. With Bitcask, a value
+    /// that is neither compressed nor encrypted is read without any allocation once `buf`
+    /// is large enough.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    /// * `buf` - Buffer receiving the value, left empty if the key is not found
+    ///
+    /// # Returns
+    /// * `Result<bool>` - True if the key was found, false otherwise, or error
+    pub fn get_into<K>(&self, key: K, buf: &mut Vec<u8>) -> crate::Result<bool>
+    where
+        K: AsRef<str>,
+    {
+        (self as &dyn KvStore).get_into(key.as_ref().as_bytes(), buf)
+    }
+
+    /// Calls `f` with the value for the given key, without keeping a copy of it.
+    ///
+    /// The value is lent like by [`Kving::get_ref`], and dropped once `f` returns.
+    /// To read many values, [`Kving::get_into`] can reuse a single buffer instead.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    /// * `f` - Function called with the value bytes
+    ///
+    /// # Returns
+    /// * `Result<Option<T>>` - What `f` returned if the key was found, None otherwise, or error
+    pub fn get_with<K, T, F>(&self, key: K, f: F) -> crate::Result<Option<T>>
+    where
+        K: AsRef<str>,
+        F: FnOnce(&[u8]) -> T,
+    {
+        Ok(self.get_ref(key)?.map(|value| f(&value)))
+    }
+
+    /// Returns the size in bytes of the value for the given key, without reading it.
+    ///
+    /// Bitcask answers from its in-memory index, so no I/O is performed.
+    ///
+    /// # Arguments
+    /// * `key` - Key to look up (can be any type that implements AsRef<str>)
+    ///
+    /// # Returns
+    /// * `Result<Option<u64>>` - Size of the decoded value if found, None otherwise, or error
+    pub fn value_len<K>(&self, key: K) -> crate::Result<Option<u64>>
+    where
+        K: AsRef<str>,
+    {
+        (self as &dyn KvStore).value_len(key.as_ref().as_bytes())
+    }
+
     /// Stores a signed integer value for the given key.
     ///
     /// # Arguments
@@ -205,7 +334,7 @@ impl Kving {
     {
         let key = key.as_ref();
         let value = value.to_be_bytes();
-        self.put(key.as_bytes(), &value.as_slice())
+        self.put(key.as_bytes(), value.as_slice())
     }
 
     /// Stores an unsigned integer value for the given key.
@@ -222,7 +351,7 @@ impl Kving {
     {
         let key = key.as_ref();
         let value = value.to_be_bytes();
-        self.put(key.as_bytes(), &value.as_slice())
+        self.put(key.as_bytes(), value.as_slice())
     }
 
     /// Stores a 32-bit floating point value for the given key.
@@ -239,7 +368,7 @@ impl Kving {
     {
         let key = key.as_ref();
         let value = value.to_be_bytes();
-        self.put(key.as_bytes(), &value.as_slice())
+        self.put(key.as_bytes(), value.as_slice())
     }
 
     /// Stores a 64-bit floating point value for the given key.
@@ -256,7 +385,7 @@ impl Kving {
     {
         let key = key.as_ref();
         let value = value.to_be_bytes();
-        self.put(key.as_bytes(), &value.as_slice())
+        self.put(key.as_bytes(), value.as_slice())
     }
 
     /// Stores a boolean value for the given key.
@@ -338,20 +467,70 @@ impl Kving {
         (self as &dyn KvStore).contains(key.as_ref().as_bytes())
     }
 
-    /// Returns a list of all keys in the store as strings.
+    /// Returns a list of all keys of the default namespace as strings.
     ///
     /// # Returns
     /// * `Result<Vec<String>>` - List of keys or error
     pub fn list_keys(&self) -> crate::Result<Vec<String>> {
         let keys = (self as &dyn KvStore)
             .list_keys()?
-            .iter()
-            .map(|k| String::from_utf8(k.to_vec()).unwrap())
+            .into_iter()
+            .filter_map(|k| String::from_utf8(k).ok())
             .collect::<Vec<String>>();
         Ok(keys)
     }
 
-    /// Clear all data.
+    /// Returns a handle to the namespace with the given name.
+    ///
+    /// Namespaces share this database's storage, file handles and merge thread, while
+    /// their keys are isolated from each other and from the default namespace.
+    ///
+    /// # Arguments
+    /// * `name` - Namespace name, must be non-empty and must not contain `'\0'`
+    ///
+    /// # Returns
+    /// * `Result<Namespace>` - Namespace handle or error if the name is invalid
+    pub fn namespace<N>(&self, name: N) -> crate::Result<Namespace<'_>>
+    where
+        N: Into<String>,
+    {
+        let name = name.into();
+        namespace::validate_name(&name)?;
+        Ok(Namespace::new(self, name))
+    }
+
+    /// Returns the names of all namespaces that currently hold at least one key.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - Sorted list of namespace names or error
+    pub fn namespaces(&self) -> crate::Result<Vec<String>> {
+        let mut names = self
+            .store
+            .list_keys()?
+            .iter()
+            .filter_map(|k| match namespace::split_key(k) {
+                Some((Some(name), _)) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        names.sort_unstable();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Applies all operations of a batch atomically.
+    /// The batch may span several namespaces.
+    ///
+    /// # Arguments
+    /// * `batch` - Operations to apply
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        self.write_batch_raw(batch.ops())
+    }
+
+    /// Clear all data, including the data of every namespace.
     ///
     /// # Returns
     /// * `Result<()>` - Success or error indicator
@@ -368,6 +547,7 @@ impl Kving {
     }
 
     /// Closes the store and releases any resources.
+    /// Stops the background workers first, like [`Kving::shutdown`] without a timeout.
     ///
     /// # Returns
     /// * `Result<()>` - Success or error indicator
@@ -375,6 +555,302 @@ impl Kving {
         (self as &dyn KvStore).close()
     }
 
+    /// Stops the background merge, merge scheduler, syncer and scrubber, then syncs and
+    /// closes the store.
+    ///
+    /// A running merge is cancelled, leaving the data files as they were before it, and a
+    /// running scrub is interrupted. No background work starts afterwards. If they haven't
+    /// stopped within `timeout`, the store is left open and `Error::ShutdownTimeout` is
+    /// returned, they still stop on their own and a later call or drop waits for them.
+    ///
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the background work to stop
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn shutdown(&self, timeout: Duration) -> crate::Result<()> {
+        self.shutdown_until(Some(Instant::now() + timeout))
+    }
+
+    /// Re-encrypts all data with a new key, rewriting the data files through the merge path.
+    /// Blocks reads and writes until done, the database must be opened with the new key
+    /// afterwards. Also encrypts data that was stored before encryption was enabled.
+    ///
+    /// A rekey interrupted by a crash is either discarded or completed by the next open:
+    /// once the rewritten files are synced the new key applies, before that the old one.
+    /// Opening with the wrong key fails with `Error::DecryptionFailed` and changes nothing.
+    ///
+    /// # Arguments
+    /// * `key` - The new 256-bit encryption key
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn rekey(&self, key: [u8; 32]) -> crate::Result<()> {
+        (self as &dyn KvStore).rekey(&key)
+    }
+
+    /// Loads the writes the primary instance made since the secondary was opened or
+    /// last caught up. Does nothing on a primary instance.
+    ///
+    /// Records appended by the primary are read incrementally. After the primary merged,
+    /// cleared or rekeyed its files, the whole index is reloaded.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn try_catch_up(&self) -> crate::Result<()> {
+        (self as &dyn KvStore).catch_up()
+    }
+
+    /// Returns operational statistics: live key count, total and live bytes of each
+    /// data file, active file size, file handle cache hits and misses, merges completed
+    /// since opening and an estimate of the memory used by the index.
+    ///
+    /// Bitcask derives the live bytes from its in-memory index, so this costs a pass
+    /// over every key. Other engines only count their keys.
+    ///
+    /// # Returns
+    /// * `Result<Stats>` - Statistics of the database, or error
+    pub fn stats(&self) -> crate::Result<Stats> {
+        (self as &dyn KvStore).stats()
+    }
+
+    /// Merges the data files now, waiting for a running merge to complete first.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator, `Error::MergeCancelled` if
+    ///   [`Kving::cancel_merge`] or [`Kving::close`] stopped it
+    pub fn merge(&self) -> crate::Result<()> {
+        self.merger
+            .exclusive(|| self.merger.run(|control| self.store.merge(control)))
+    }
+
+    /// Merges the chosen data files only, into new files split at `max_file_size`.
+    ///
+    /// Useful to reclaim the space of the few files holding most of the garbage without
+    /// rewriting the whole database. The id, size and live bytes of every data file are
+    /// listed by [`Kving::stats`]. Waits for a running merge to complete first. Only
+    /// supported by Bitcask, fails with `Error::InvalidData` if a file is the active one
+    /// or doesn't exist.
+    ///
+    /// # Arguments
+    /// * `file_ids` - Ids of the historical data files to merge
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn merge_files(&self, file_ids: &[u64]) -> crate::Result<()> {
+        self.merger.exclusive(|| {
+            self.merger
+                .run(|control| self.store.merge_files(file_ids, control))
+        })
+    }
+
+    /// Gets the progress of the running merge, whether started in the background or
+    /// by [`Kving::merge`] or [`Kving::merge_files`].
+    ///
+    /// # Returns
+    /// * `Option<MergeProgress>` - Files and bytes merged so far, `None` if no merge is
+    ///   running
+    pub fn merge_progress(&self) -> Option<MergeProgress> {
+        self.merger.progress()
+    }
+
+    /// Asks the running merge to stop, without waiting for it. The merge stops after the
+    /// record it is copying and removes the files it wrote, the merged files are kept.
+    /// Does nothing if no merge is running.
+    pub fn cancel_merge(&self) {
+        self.merger.cancel();
+    }
+
+    /// Checks all stored data for corruption while the store stays online.
+    ///
+    /// Walks every data file and reports the byte ranges that fail their checksum or
+    /// can't be decrypted, the records of incomplete batches that were never applied,
+    /// and the live keys whose value can't be read back.
+    ///
+    /// # Returns
+    /// * `Result<VerifyReport>` - Report of the problems found, or error
+    pub fn verify(&self) -> crate::Result<VerifyReport> {
+        self.store.verify(None, &AtomicBool::new(false))
+    }
+
+    /// Repairs the data files of a database that isn't open, after a crash or a
+    /// storage failure left them torn or corrupted.
+    ///
+    /// Torn tails are truncated and corrupt regions are moved into `.quarantine` files
+    /// next to the data, so the database opens again with everything else intact.
+    /// Bitcask rewrites damaged data files in place, the LSM engine drops unreadable
+    /// tables from its manifest and truncates its write-ahead log. The B+tree engine
+    /// isn't supported, it recovers from torn writes by itself on open. Fails with
+    /// `Error::Locked` while the database is open.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration the database is opened with, including its encryption key
+    ///
+    /// # Returns
+    /// * `Result<RepairReport>` - Report of the data that was removed, or error
+    pub fn repair(config: Config) -> crate::Result<RepairReport> {
+        match config.store_model() {
+            StoreModel::Bitcask => Bitcask::repair(&config),
+            StoreModel::Memory => Ok(RepairReport::default()),
+            StoreModel::Lsm => Lsm::repair(&config),
+            StoreModel::BTree => Err(crate::Error::InvalidData(
+                "Repair is not supported by the B+tree engine".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the report of the last completed background scrub.
+    ///
+    /// # Returns
+    /// * `Option<VerifyReport>` - The report, `None` if the scrubber is disabled or hasn't completed yet
+    pub fn last_scrub_report(&self) -> Option<VerifyReport> {
+        self.workers.last_scrub_report()
+    }
+
+    /// Registers a listener invoked after each successful put, delete or clear.
+    ///
+    /// The callback receives changes to keys starting with `prefix` (an empty prefix
+    /// matches every key, a full key matches that key and any key extending it), and
+    /// [`Change::Clear`] regardless of prefix. Deletes are notified whether or not the
+    /// key was stored, and keys that aren't valid UTF-8, which can be written through
+    /// [`KvStore`], are delivered with their invalid bytes replaced with U+FFFD.
+    ///
+    /// Threading contract: callbacks run synchronously on the thread that performed the
+    /// mutation, after it has been applied to the store and with no internal locks held.
+    /// Mutations from several threads may therefore invoke a callback concurrently.
+    /// Callbacks may read from the store, but writing from a callback re-enters it.
+    ///
+    /// The store keeps only a weak reference to the callback: it stays registered for as
+    /// long as the returned handle is alive.
+    ///
+    /// # Arguments
+    /// * `prefix` - Key prefix to listen to (can be any type that implements AsRef<str>)
+    /// * `callback` - Function invoked with each matching change
+    ///
+    /// # Returns
+    /// * `ListenerHandle` - Handle that unregisters the listener when dropped
+    pub fn register_listener<P, F>(&self, prefix: P, callback: F) -> ListenerHandle
+    where
+        P: AsRef<str>,
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.register_namespace_listener(None, prefix, callback)
+    }
+
+    /// Registers a listener for `namespace` (`None` for the default namespace).
+    pub(crate) fn register_namespace_listener<P, F>(
+        &self,
+        namespace: Option<String>,
+        prefix: P,
+        callback: F,
+    ) -> ListenerHandle
+    where
+        P: AsRef<str>,
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.listeners
+            .register(namespace, prefix.as_ref().to_string(), callback)
+    }
+
+    /// Returns all stored keys starting with `prefix`, without filtering namespaces.
+    pub(crate) fn list_raw_keys(&self, prefix: &[u8]) -> crate::Result<Vec<Vec<u8>>> {
+        let keys = self
+            .store
+            .list_keys()?
+            .into_iter()
+            .filter(|k| k.starts_with(prefix))
+            .collect();
+        Ok(keys)
+    }
+
+    /// Deletes every key of `namespace` in one batch and notifies its listeners.
+    pub(crate) fn clear_namespace(&self, namespace: &Namespace) -> crate::Result<()> {
+        let ops = self
+            .list_raw_keys(namespace.prefix())?
+            .into_iter()
+            .map(BatchOp::Delete)
+            .collect::<Vec<BatchOp>>();
+        self.store.write_batch(&ops)?;
+        self.listeners
+            .notify(Some(namespace.name()), &Change::Clear);
+        Ok(())
+    }
+
+    /// Get the value stored under `key`, which may be namespaced.
+    pub(crate) fn get_raw(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        metrics::observe(
+            "get",
+            || self.store.get(key),
+            |value| value.as_ref().map_or(0, |v| v.len() as u64),
+        )
+    }
+
+    /// Check if a value is stored under `key`, which may be namespaced.
+    pub(crate) fn contains_raw(&self, key: &[u8]) -> crate::Result<bool> {
+        self.store.contains(key)
+    }
+
+    /// Get the size of the value stored under `key`, which may be namespaced.
+    pub(crate) fn value_len_raw(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        self.store.value_len(key)
+    }
+
+    /// Store `value` under `key`, which may be namespaced.
+    pub(crate) fn put_raw(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        self.config.check_entry_size(key, value)?;
+        metrics::observe(
+            "put",
+            || self.store.put(key, value),
+            |_| (key.len() + value.len()) as u64,
+        )?;
+        self.notify_key(key, |k| Change::Put(k));
+        self.spawn_pending_merge();
+        Ok(())
+    }
+
+    /// Delete `key`, which may be namespaced.
+    pub(crate) fn delete_raw(&self, key: &[u8]) -> crate::Result<()> {
+        metrics::observe("delete", || self.store.delete(key), |_| key.len() as u64)?;
+        self.notify_key(key, |k| Change::Delete(k));
+        self.spawn_pending_merge();
+        Ok(())
+    }
+
+    /// Apply `ops` atomically, their keys may be namespaced.
+    pub(crate) fn write_batch_raw(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        for op in ops {
+            if let BatchOp::Put(key, value) = op {
+                self.config.check_entry_size(key, value)?;
+            }
+        }
+        self.store.write_batch(ops)?;
+        for op in ops {
+            match op {
+                BatchOp::Put(key, _) => self.notify_key(key, |k| Change::Put(k)),
+                BatchOp::Delete(key) => self.notify_key(key, |k| Change::Delete(k)),
+            }
+        }
+        self.spawn_pending_merge();
+        Ok(())
+    }
+
+    /// Notifies the listeners of the namespace `key` belongs to.
+    /// Bytes that aren't valid UTF-8 are replaced with U+FFFD.
+    fn notify_key(&self, key: &[u8], change: fn(&str) -> Change<'_>) {
+        if let Some((namespace, key)) = namespace::split_raw_key(key) {
+            let namespace = namespace.map(String::from_utf8_lossy);
+            let key = String::from_utf8_lossy(key);
+            self.listeners.notify(namespace.as_deref(), &change(&key));
+        }
+    }
+
+    /// Starts a background merge if a write left work that only a merge clears.
+    fn spawn_pending_merge(&self) {
+        if self.store.merge_pending() {
+            self.workers.spawn_merge();
+        }
+    }
+
     /// Initiates a background merge process if not already running.
     ///
     /// # Returns
@@ -384,53 +860,105 @@ impl Kving {
             return Ok(());
         }
 
-        if self
-            .is_merging
-            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            return Ok(());
-        }
-
-        let store_clone = Arc::clone(&self.store);
-        let is_merging_clone = Arc::clone(&self.is_merging);
+        self.workers.spawn_merge();
+        Ok(())
+    }
 
-        std::thread::spawn(move || {
-            if let Err(e) = store_clone.merge() {
-                eprintln!("{:?}", e)
-            }
-            is_merging_clone.store(false, Ordering::Release);
-        });
+    /// Stops the background workers and closes the store, giving up at `deadline`.
+    fn shutdown_until(&self, deadline: Option<Instant>) -> crate::Result<()> {
+        if !self.workers.stop(deadline) {
+            return Err(crate::Error::ShutdownTimeout);
+        }
+        // A merge started by a caller of `merge` is cancelled as well
+        self.merger
+            .abort(deadline, || self.store.close())
+            .ok_or(crate::Error::ShutdownTimeout)?
+    }
+}
 
-        Ok(())
+impl Drop for Kving {
+    /// Cancels a running merge and joins every background thread, the store is then
+    /// closed by its own drop.
+    fn drop(&mut self) {
+        self.workers.stop(None);
     }
 }
 
+/// Keys are those of the default namespace, escaped by [`namespace::escape_key`] so that
+/// any key can be stored without clashing with namespaced keys or engine records.
 impl KvStore for Kving {
     fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
-        self.store.get(key)
+        self.get_raw(&namespace::escape_key(key))
+    }
+
+    fn get_ref(&self, key: &[u8]) -> crate::Result<Option<ValueRef>> {
+        let key = namespace::escape_key(key);
+        metrics::observe(
+            "get",
+            || self.store.get_ref(&key),
+            |value| value.as_ref().map_or(0, |v| v.len() as u64),
+        )
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> crate::Result<bool> {
+        let key = namespace::escape_key(key);
+        let len = metrics::observe(
+            "get",
+            || Ok(self.store.get_into(&key, buf)?.then_some(buf.len() as u64)),
+            |len| len.unwrap_or(0),
+        )?;
+        Ok(len.is_some())
     }
 
     fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
-        self.store.put(key, value)
-        // self.merge_transactions(false)
+        self.put_raw(&namespace::escape_key(key), value)
     }
 
     fn delete(&self, key: &[u8]) -> crate::Result<()> {
-        self.store.delete(key)
-        // self.merge_transactions(false)
+        self.delete_raw(&namespace::escape_key(key))
     }
 
     fn contains(&self, key: &[u8]) -> crate::Result<bool> {
-        self.store.contains(key)
+        self.contains_raw(&namespace::escape_key(key))
+    }
+
+    fn value_len(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        self.value_len_raw(&namespace::escape_key(key))
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        let ops = ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => {
+                    BatchOp::Put(namespace::escape_key(key).into_owned(), value.clone())
+                }
+                BatchOp::Delete(key) => BatchOp::Delete(namespace::escape_key(key).into_owned()),
+            })
+            .collect::<Vec<BatchOp>>();
+        self.write_batch_raw(&ops)
     }
 
     fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
-        self.store.list_keys()
+        let keys = self
+            .store
+            .list_keys()?
+            .iter()
+            .filter_map(|k| match namespace::split_raw_key(k) {
+                Some((None, key)) => Some(key.to_vec()),
+                _ => None,
+            })
+            .collect();
+        Ok(keys)
     }
 
     fn clear(&self) -> crate::Result<()> {
-        self.store.clear()
+        // A running merge would still read, and write out, the files being deleted
+        self.merger
+            .abort(None, || self.store.clear())
+            .expect("Waits without a deadline")?;
+        self.listeners.notify_clear_all();
+        Ok(())
     }
 
     fn sync(&self) -> crate::Result<()> {
@@ -441,11 +969,76 @@ impl KvStore for Kving {
         self.store.can_merge()
     }
 
-    fn merge(&self) -> crate::Result<()> {
-        self.store.merge()
+    fn merge_pending(&self) -> bool {
+        self.store.merge_pending()
+    }
+
+    fn merge(&self, control: &MergeControl) -> crate::Result<()> {
+        self.merger.exclusive(|| self.store.merge(control))
+    }
+
+    fn merge_files(&self, file_ids: &[u64], control: &MergeControl) -> crate::Result<()> {
+        self.merger
+            .exclusive(|| self.store.merge_files(file_ids, control))
+    }
+
+    fn verify(
+        &self,
+        max_bytes_per_sec: Option<u64>,
+        stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        self.store.verify(max_bytes_per_sec, stop)
+    }
+
+    fn rekey(&self, key: &[u8; 32]) -> crate::Result<()> {
+        // Wait for a running merge, and keep new ones from starting meanwhile
+        self.merger.exclusive(|| self.store.rekey(key))
+    }
+
+    fn catch_up(&self) -> crate::Result<()> {
+        self.store.catch_up()
+    }
+
+    fn stats(&self) -> crate::Result<Stats> {
+        let mut stats = self.store.stats()?;
+        self.merger.stats().fill(&mut stats);
+        Ok(stats)
     }
 
     fn close(&self) -> crate::Result<()> {
-        self.store.close()
+        self.shutdown_until(None)
+    }
+}
+
+/// Decode a signed integer value.
+pub(crate) fn decode_isize(value: Vec<u8>) -> Option<isize> {
+    Some(isize::from_be_bytes(value.try_into().ok()?))
+}
+
+/// Decode an unsigned integer value.
+pub(crate) fn decode_usize(value: Vec<u8>) -> Option<usize> {
+    Some(usize::from_be_bytes(value.try_into().ok()?))
+}
+
+/// Decode a 32-bit floating point value.
+pub(crate) fn decode_f32(value: Vec<u8>) -> Option<f32> {
+    Some(f32::from_be_bytes(value.try_into().ok()?))
+}
+
+/// Decode a 64-bit floating point value.
+pub(crate) fn decode_f64(value: Vec<u8>) -> Option<f64> {
+    Some(f64::from_be_bytes(value.try_into().ok()?))
+}
+
+/// Decode a boolean value.
+pub(crate) fn decode_bool(value: Vec<u8>) -> Option<bool> {
+    match value.as_slice() {
+        [byte] => Some(*byte == 1),
+        _ => None,
     }
 }
+
+/// Decode a UTF-8 string value.
+pub(crate) fn decode_string(value: Vec<u8>) -> Option<String> {
+    String::from_utf8(value).ok()
+}
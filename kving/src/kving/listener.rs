@@ -0,0 +1,211 @@
+use std::sync::{Arc, RwLock, Weak};
+
+/// A change that was applied to the store.
+///
+/// Keys that aren't valid UTF-8 are given with their invalid bytes replaced with U+FFFD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    /// A value was stored under the key.
+    Put(&'a str),
+    /// The key was deleted. Also delivered if the key wasn't stored, as deleting a
+    /// missing key isn't checked against the store.
+    Delete(&'a str),
+    /// All data was cleared. Delivered to every listener regardless of its prefix.
+    Clear,
+}
+
+impl Change<'_> {
+    /// Get the key affected by this change, or `None` for [`Change::Clear`].
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Change::Put(key) | Change::Delete(key) => Some(key),
+            Change::Clear => None,
+        }
+    }
+}
+
+type Callback = dyn Fn(&Change) + Send + Sync;
+
+/// Keeps a registered listener alive.
+///
+/// The store only holds a weak reference to the callback, so dropping the
+/// handle (or calling [`ListenerHandle::unregister`]) deregisters it.
+#[must_use = "the listener is unregistered as soon as the handle is dropped"]
+pub struct ListenerHandle {
+    _callback: Arc<Callback>,
+}
+
+impl ListenerHandle {
+    /// Unregisters the listener. Equivalent to dropping the handle.
+    pub fn unregister(self) {}
+}
+
+struct Entry {
+    namespace: Option<String>,
+    prefix: String,
+    callback: Weak<Callback>,
+}
+
+/// Registry of change listeners keyed by prefix.
+#[derive(Default)]
+pub(crate) struct Listeners {
+    entries: RwLock<Vec<Entry>>,
+}
+
+impl Listeners {
+    /// Registers a callback for keys of `namespace` starting with `prefix`.
+    pub(crate) fn register<F>(
+        &self,
+        namespace: Option<String>,
+        prefix: String,
+        callback: F,
+    ) -> ListenerHandle
+    where
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        let callback: Arc<Callback> = Arc::new(callback);
+        let mut entries = self.entries.write().expect("Failed to write listeners");
+        entries.retain(|e| e.callback.strong_count() > 0);
+        entries.push(Entry {
+            namespace,
+            prefix,
+            callback: Arc::downgrade(&callback),
+        });
+        ListenerHandle {
+            _callback: callback,
+        }
+    }
+
+    /// Invokes every live listener of `namespace` interested in `change`.
+    pub(crate) fn notify(&self, namespace: Option<&str>, change: &Change) {
+        self.notify_matching(change, |e| e.namespace.as_deref() == namespace);
+    }
+
+    /// Invokes every live listener of every namespace with [`Change::Clear`].
+    pub(crate) fn notify_clear_all(&self) {
+        self.notify_matching(&Change::Clear, |_| true);
+    }
+
+    /// Invokes every live listener accepted by `filter` and interested in `change`.
+    ///
+    /// Callbacks are collected first and invoked after the registry lock is
+    /// released, so they may register or drop listeners and call back into the store.
+    fn notify_matching<P>(&self, change: &Change, filter: P)
+    where
+        P: Fn(&Entry) -> bool,
+    {
+        let mut has_dead = false;
+        let callbacks: Vec<Arc<Callback>> = {
+            let entries = self.entries.read().expect("Failed to read listeners");
+            if entries.is_empty() {
+                return;
+            }
+            entries
+                .iter()
+                .filter(|e| filter(e))
+                .filter(|e| match change.key() {
+                    Some(key) => key.starts_with(e.prefix.as_str()),
+                    None => true,
+                })
+                .filter_map(|e| {
+                    let callback = e.callback.upgrade();
+                    has_dead |= callback.is_none();
+                    callback
+                })
+                .collect()
+        };
+
+        for callback in callbacks {
+            callback(change);
+        }
+
+        if has_dead {
+            let mut entries = self.entries.write().expect("Failed to write listeners");
+            entries.retain(|e| e.callback.strong_count() > 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Kving, StoreModel};
+    use std::sync::Mutex;
+
+    fn recorder() -> (
+        Arc<Mutex<Vec<String>>>,
+        impl Fn(&Change) + Send + Sync + 'static,
+    ) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let callback = move |change: &Change| sink.lock().unwrap().push(format!("{change:?}"));
+        (seen, callback)
+    }
+
+    #[test]
+    fn notifies_matching_prefixes_only() {
+        let listeners = Listeners::default();
+        let (seen, callback) = recorder();
+        let _handle = listeners.register(None, "user.".to_string(), callback);
+
+        listeners.notify(None, &Change::Put("user.name"));
+        listeners.notify(None, &Change::Put("session"));
+        listeners.notify(Some("other"), &Change::Delete("user.name"));
+        listeners.notify_clear_all();
+
+        assert_eq!(*seen.lock().unwrap(), ["Put(\"user.name\")", "Clear"]);
+    }
+
+    #[test]
+    fn dropping_the_handle_unregisters() {
+        let listeners = Listeners::default();
+        let (seen, callback) = recorder();
+        let handle = listeners.register(None, String::new(), callback);
+
+        listeners.notify(None, &Change::Put("a"));
+        handle.unregister();
+        listeners.notify(None, &Change::Put("b"));
+
+        assert_eq!(*seen.lock().unwrap(), ["Put(\"a\")"]);
+        assert!(listeners.entries.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn kving_notifies_after_each_mutation() {
+        let config = Config::builder()
+            .set_store_model(StoreModel::Memory)
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        let (seen, callback) = recorder();
+        let _handle = kving.register_listener("k", callback);
+
+        kving.put_string("k1", "v").unwrap();
+        kving.delete("k1").unwrap();
+        kving.put_string("other", "v").unwrap();
+        kving.clear().unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["Put(\"k1\")", "Delete(\"k1\")", "Clear"]
+        );
+    }
+
+    #[test]
+    fn kving_notifies_invalid_utf8_keys_and_absent_deletes() {
+        let config = Config::builder()
+            .set_store_model(StoreModel::Memory)
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        let (seen, callback) = recorder();
+        let _handle = kving.register_listener("", callback);
+
+        let store: &dyn crate::KvStore = &kving;
+        store.put(b"k\xC3(", b"v").unwrap();
+        kving.delete("missing").unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["Put(\"k\u{fffd}(\")", "Delete(\"missing\")"]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::kving::config::Config;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Name of the lock file in the database directory
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// Delay between two attempts while waiting for the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Exclusive advisory lock on a database directory, released when dropped.
+///
+/// The lock is taken on an open file description, so it conflicts both with other
+/// processes and with other instances opened in the same process.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Acquire the lock of the database directory of `config`, waiting up to the
+    /// configured lock timeout. Fails with `Error::Locked` if it is still held.
+    pub(crate) fn acquire(config: &Config) -> crate::Result<Self> {
+        let path = config.database_path();
+        std::fs::create_dir_all(&path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE_NAME))?;
+
+        let deadline = config
+            .lock_timeout()
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+            if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+                return Err(crate::Error::Locked(path));
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &std::path::Path, timeout: Option<Duration>) -> Config {
+        let builder = Config::builder().set_data_dir(dir.to_path_buf());
+        match timeout {
+            Some(timeout) => builder.set_lock_timeout(timeout).build(),
+            None => builder.build(),
+        }
+    }
+
+    #[test]
+    fn fails_fast_while_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DirLock::acquire(&config(dir.path(), None)).unwrap();
+        assert!(matches!(
+            DirLock::acquire(&config(dir.path(), None)),
+            Err(crate::Error::Locked(_))
+        ));
+
+        drop(lock);
+        DirLock::acquire(&config(dir.path(), None)).unwrap();
+    }
+
+    #[test]
+    fn waits_for_the_lock_until_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DirLock::acquire(&config(dir.path(), None)).unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            DirLock::acquire(&config(dir.path(), Some(Duration::from_millis(50)))),
+            Err(crate::Error::Locked(_))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(lock);
+        });
+        DirLock::acquire(&config(dir.path(), Some(Duration::from_secs(10)))).unwrap();
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn second_instance_of_a_database_is_locked_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let kving = crate::Kving::with_config(config(dir.path(), None)).unwrap();
+        assert!(matches!(
+            crate::Kving::with_config(config(dir.path(), None)),
+            Err(crate::Error::Locked(_))
+        ));
+        kving.close().unwrap();
+        drop(kving);
+        crate::Kving::with_config(config(dir.path(), None)).unwrap();
+    }
+}
@@ -0,0 +1,610 @@
+use crate::kving::config::MergePolicy;
+use crate::kving::diagnostics::{self, ErrorEvent, ErrorHandler, ErrorSource};
+use crate::kving::kv_store::KvStore;
+use crate::kving::metrics;
+use crate::kving::stats::MergeStats;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Progress of a running merge, see [`Kving::merge_progress`](crate::Kving::merge_progress).
+///
+/// Engines that don't report their progress leave the totals at zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeProgress {
+    /// Number of files being merged.
+    pub files_total: u64,
+    /// Number of files merged so far.
+    pub files_done: u64,
+    /// Size of the files being merged in bytes.
+    pub bytes_total: u64,
+    /// Bytes of the merged files read so far.
+    pub bytes_read: u64,
+    /// Bytes of live records written to the new files so far.
+    pub bytes_rewritten: u64,
+    /// Time since the merge started.
+    pub elapsed: Duration,
+}
+
+impl MergeProgress {
+    /// Estimate the time left from the rate the files were read at so far.
+    /// `None` until something was read.
+    pub fn estimated_remaining(&self) -> Option<Duration> {
+        if self.bytes_read == 0 {
+            return None;
+        }
+        let remaining = self.bytes_total.saturating_sub(self.bytes_read);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.bytes_read as f64),
+        )
+    }
+}
+
+/// Handed to [`KvStore::merge`] to limit its I/O rate, report its progress and
+/// cancel it.
+pub struct MergeControl {
+    bytes_per_sec: Option<u64>,
+    cancelled: AtomicBool,
+    /// Start and progress of the running merge, `None` between merges
+    progress: Mutex<Option<(Instant, MergeProgress)>>,
+}
+
+impl MergeControl {
+    /// Create a control limiting merges to `bytes_per_sec`, zero for no limit.
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: Some(bytes_per_sec).filter(|rate| *rate > 0),
+            cancelled: AtomicBool::new(false),
+            progress: Mutex::new(None),
+        }
+    }
+
+    /// Announce the number and total size of the files about to be merged.
+    pub fn begin(&self, files_total: u64, bytes_total: u64) {
+        if let Some((_, progress)) = self.lock().as_mut() {
+            progress.files_total = files_total;
+            progress.bytes_total = bytes_total;
+        }
+    }
+
+    /// Account for `bytes_read` from the merged files and `bytes_written` to the new
+    /// ones, sleeping as long as needed to stay under the rate limit.
+    /// Returns `false` once the merge should stop.
+    pub fn consume(&self, bytes_read: u64, bytes_written: u64) -> bool {
+        let due = match self.lock().as_mut() {
+            Some((start, progress)) => {
+                progress.bytes_read += bytes_read;
+                progress.bytes_rewritten += bytes_written;
+                let bytes = progress.bytes_read + progress.bytes_rewritten;
+                self.bytes_per_sec
+                    .map(|rate| *start + Duration::from_secs_f64(bytes as f64 / rate as f64))
+            }
+            None => None,
+        };
+        if let Some(due) = due {
+            while !self.is_cancelled() {
+                let now = Instant::now();
+                if now >= due {
+                    break;
+                }
+                std::thread::sleep((due - now).min(Duration::from_millis(100)));
+            }
+        }
+        !self.is_cancelled()
+    }
+
+    /// Account for a file merged completely.
+    pub fn file_done(&self) {
+        if let Some((_, progress)) = self.lock().as_mut() {
+            progress.files_done += 1;
+        }
+    }
+
+    /// Check if the merge should stop, leaving the data as it was before the merge.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Start tracking a new merge.
+    fn start(&self) {
+        *self.lock() = Some((Instant::now(), MergeProgress::default()));
+    }
+
+    /// Stop tracking the merge, and forget a cancellation.
+    fn finish(&self) {
+        let mut progress = self.lock();
+        *progress = None;
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    /// Ask the running merge to stop. Does nothing between merges.
+    fn cancel(&self) {
+        let progress = self.lock();
+        if progress.is_some() {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Get the progress of the running merge.
+    fn progress(&self) -> Option<MergeProgress> {
+        self.lock().as_ref().map(|(start, progress)| MergeProgress {
+            elapsed: start.elapsed(),
+            ..progress.clone()
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(Instant, MergeProgress)>> {
+        self.progress.lock().expect("Failed to lock merge progress")
+    }
+}
+
+/// Runs the merges of a store, one at a time.
+#[derive(Clone)]
+pub(crate) struct Merger {
+    store: Arc<Box<dyn KvStore>>,
+    /// Set while a merge or rekey holds the store, signalled once released
+    claim: Arc<(Mutex<bool>, Condvar)>,
+    control: Arc<MergeControl>,
+    stats: Arc<MergeStats>,
+    error_handler: Option<ErrorHandler>,
+}
+
+impl Merger {
+    pub(crate) fn new(
+        store: Arc<Box<dyn KvStore>>,
+        bytes_per_sec: u64,
+        error_handler: Option<ErrorHandler>,
+    ) -> Self {
+        Self {
+            store,
+            claim: Arc::new((Mutex::new(false), Condvar::new())),
+            control: Arc::new(MergeControl::new(bytes_per_sec)),
+            stats: Arc::new(MergeStats::default()),
+            error_handler,
+        }
+    }
+
+    /// Claim the store for a merge, or a rekey. Returns false while another claim is held.
+    pub(crate) fn try_begin(&self) -> bool {
+        let mut claimed = self.lock_claim();
+        !std::mem::replace(&mut *claimed, true)
+    }
+
+    /// Release the claim taken by `try_begin`, waking the callers waiting for it.
+    pub(crate) fn end(&self) {
+        *self.lock_claim() = false;
+        self.claim.1.notify_all();
+    }
+
+    /// Run `f` under a claim, waiting for a running merge to complete first.
+    pub(crate) fn exclusive<T>(&self, f: impl FnOnce() -> T) -> T {
+        let mut claimed = self.lock_claim();
+        while *claimed {
+            claimed = self
+                .claim
+                .1
+                .wait(claimed)
+                .expect("Failed to lock merge claim");
+        }
+        *claimed = true;
+        drop(claimed);
+
+        let result = f();
+        self.end();
+        result
+    }
+
+    /// Run `f` under a claim, cancelling a running merge first. Gives up at `deadline` if
+    /// the merge hasn't stopped by then, returning `None`.
+    pub(crate) fn abort<T>(&self, deadline: Option<Instant>, f: impl FnOnce() -> T) -> Option<T> {
+        let mut claimed = self.lock_claim();
+        while *claimed {
+            // Unlike `cancel`, also stops a merge that claimed the store but hasn't started
+            self.control.cancelled.store(true, Ordering::Relaxed);
+            claimed = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return None;
+                    }
+                    let (claimed, _) = self
+                        .claim
+                        .1
+                        .wait_timeout(claimed, timeout)
+                        .expect("Failed to lock merge claim");
+                    claimed
+                }
+                None => self
+                    .claim
+                    .1
+                    .wait(claimed)
+                    .expect("Failed to lock merge claim"),
+            };
+        }
+        *claimed = true;
+        drop(claimed);
+        // The cancellation may have come after the merge was done, the next one must run
+        self.control.cancelled.store(false, Ordering::Relaxed);
+
+        let result = f();
+        self.end();
+        Some(result)
+    }
+
+    fn lock_claim(&self) -> std::sync::MutexGuard<'_, bool> {
+        self.claim.0.lock().expect("Failed to lock merge claim")
+    }
+
+    /// Merge with `merge`, tracking its progress and duration. Needs a claim.
+    pub(crate) fn run(
+        &self,
+        merge: impl FnOnce(&MergeControl) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        let _span = diagnostics::merge_span();
+        let start = Instant::now();
+        self.control.start();
+        let result = merge(&self.control);
+        self.control.finish();
+        match &result {
+            Ok(()) => self.stats.record(start.elapsed()),
+            Err(crate::Error::MergeCancelled) => {}
+            Err(_) => metrics::merge_failed(),
+        }
+        result
+    }
+
+    /// Merge the store under a claim taken by `try_begin`, then release it.
+    /// Errors have no caller to be returned to, they are reported.
+    pub(crate) fn run_claimed(&self) {
+        match self.run(|control| self.store.merge(control)) {
+            Ok(()) | Err(crate::Error::MergeCancelled) => {}
+            Err(e) => diagnostics::report(
+                self.error_handler.as_ref(),
+                ErrorEvent::new(ErrorSource::Merge, &e),
+            ),
+        }
+        self.end();
+    }
+
+    /// Ask the running merge to stop.
+    pub(crate) fn cancel(&self) {
+        self.control.cancel();
+    }
+
+    /// Get the progress of the running merge, `None` if no merge is running.
+    pub(crate) fn progress(&self) -> Option<MergeProgress> {
+        self.control.progress()
+    }
+
+    /// Get the count and duration of the completed merges.
+    pub(crate) fn stats(&self) -> &MergeStats {
+        &self.stats
+    }
+}
+
+/// Background thread merging the store whenever the merge policy asks for it.
+/// Dropping it stops the thread, once a running merge has completed.
+pub(crate) struct MergeScheduler {
+    wake: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MergeScheduler {
+    /// Starts checking `policy` every `interval`, merging through `merger`.
+    pub(crate) fn start(merger: Merger, policy: MergePolicy, interval: Duration) -> Self {
+        let (wake, sleep) = mpsc::channel::<()>();
+
+        let handle = std::thread::spawn(move || {
+            // Sleeps for the interval, a message or a disconnect ends the thread
+            while let Err(RecvTimeoutError::Timeout) = sleep.recv_timeout(interval) {
+                if !policy.in_window(SystemTime::now()) {
+                    continue;
+                }
+                match merger.store.can_merge() {
+                    Ok(true) if merger.try_begin() => merger.run_claimed(),
+                    Ok(_) => {}
+                    Err(e) => diagnostics::report(
+                        merger.error_handler.as_ref(),
+                        ErrorEvent::new(ErrorSource::Merge, &e),
+                    ),
+                }
+            }
+        });
+
+        Self {
+            wake: Some(wake),
+            handle: Some(handle),
+        }
+    }
+
+    /// Ask the thread to stop once a running merge has completed, without waiting.
+    pub(crate) fn signal_stop(&mut self) {
+        drop(self.wake.take());
+    }
+
+    /// Check if the thread has stopped.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        self.signal_stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::{Config, MergeWindow};
+    use crate::memory::memory::Memory;
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+
+    /// Open a database checking `policy` every 10 ms, and overwrite half of its keys.
+    /// Returns the number of merges completed before, the one started on open included.
+    fn overwritten(dir: &Path, policy: MergePolicy) -> (Kving, u64) {
+        let policy = MergePolicy {
+            max_dead_ratio: Some(0.5),
+            check_interval: Some(Duration::from_millis(10)),
+            ..policy
+        };
+        let config = Config::builder()
+            .set_data_dir(dir.to_path_buf())
+            .set_max_file_size(100)
+            .set_max_historical_files(100)
+            .set_merge_policy(policy)
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        kving.merge().unwrap();
+        let merges = kving.stats().unwrap().merge_count;
+        for i in 0..8 {
+            kving.put_string(format!("key-{}", i), "first").unwrap();
+        }
+        for i in 0..4 {
+            kving.put_string(format!("key-{}", i), "second").unwrap();
+        }
+        (kving, merges)
+    }
+
+    #[test]
+    fn scheduler_merges_when_the_policy_asks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (kving, merges) = overwritten(dir.path(), MergePolicy::default());
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while kving.stats().unwrap().merge_count == merges {
+            assert!(Instant::now() < deadline, "no scheduled merge");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(kving.get_string("key-0").unwrap(), "second");
+        assert_eq!(kving.get_string("key-7").unwrap(), "first");
+        kving.close().unwrap();
+    }
+
+    #[test]
+    fn scheduler_waits_for_its_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let time_of_day = Duration::from_secs(since_epoch.as_secs() % (24 * 60 * 60));
+        let hour = Duration::from_secs(60 * 60);
+        let later = MergeWindow {
+            start: time_of_day + hour,
+            end: time_of_day + 2 * hour,
+        };
+        let policy = MergePolicy {
+            windows: vec![later],
+            ..MergePolicy::default()
+        };
+        let (kving, merges) = overwritten(dir.path(), policy);
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(kving.can_merge().unwrap());
+        assert_eq!(kving.stats().unwrap().merge_count, merges);
+        kving.close().unwrap();
+    }
+
+    #[test]
+    fn estimates_the_time_left_from_the_rate_so_far() {
+        let mut progress = MergeProgress {
+            bytes_total: 300,
+            elapsed: Duration::from_secs(2),
+            ..MergeProgress::default()
+        };
+        assert_eq!(progress.estimated_remaining(), None);
+        progress.bytes_read = 100;
+        assert_eq!(progress.estimated_remaining(), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn control_limits_the_rate_and_tracks_progress() {
+        let control = MergeControl::new(1000);
+        assert_eq!(control.progress(), None);
+        control.start();
+        control.begin(2, 200);
+
+        let start = Instant::now();
+        assert!(control.consume(100, 50));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        control.file_done();
+        let progress = control.progress().unwrap();
+        assert_eq!((progress.files_total, progress.files_done), (2, 1));
+        assert_eq!(
+            (
+                progress.bytes_total,
+                progress.bytes_read,
+                progress.bytes_rewritten
+            ),
+            (200, 100, 50)
+        );
+
+        control.finish();
+        assert_eq!(control.progress(), None);
+    }
+
+    #[test]
+    fn cancel_interrupts_the_rate_limit() {
+        let control = MergeControl::new(1);
+        // Between merges there is nothing to cancel
+        control.cancel();
+        assert!(!control.is_cancelled());
+
+        control.start();
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                control.cancel();
+            });
+            assert!(!control.consume(1000, 0));
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        control.finish();
+        assert!(!control.is_cancelled());
+    }
+
+    /// Open a database merging at 4 KiB/s, holding enough garbage for a merge of seconds
+    fn slow_merging(dir: &Path) -> Kving {
+        let config = Config::builder()
+            .set_data_dir(dir.to_path_buf())
+            .set_max_file_size(1024)
+            .set_max_historical_files(1000)
+            .set_merge_rate(4096)
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        kving.merge().unwrap();
+        for round in 0..2 {
+            for i in 0..100 {
+                kving
+                    .put_string(format!("key-{}", i), format!("{:0>100}", round))
+                    .unwrap();
+            }
+        }
+        kving
+    }
+
+    /// Wait until the merge started by another thread has read something
+    fn wait_for_progress(kving: &Kving) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while kving
+            .merge_progress()
+            .is_none_or(|progress| progress.bytes_read == 0)
+        {
+            assert!(Instant::now() < deadline, "no merge progress");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn cancelled_merge_leaves_the_data_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let kving = slow_merging(dir.path());
+        let before = kving.stats().unwrap();
+
+        std::thread::scope(|scope| {
+            let merge = scope.spawn(|| kving.merge());
+            wait_for_progress(&kving);
+            let progress = kving.merge_progress().unwrap();
+            assert!(progress.files_total > 0);
+            assert!(progress.bytes_total > progress.bytes_read);
+            kving.cancel_merge();
+            assert!(matches!(
+                merge.join().unwrap(),
+                Err(crate::Error::MergeCancelled)
+            ));
+        });
+
+        assert_eq!(kving.merge_progress(), None);
+        let after = kving.stats().unwrap();
+        assert_eq!(after.merge_count, before.merge_count);
+        assert_eq!(after.total_bytes(), before.total_bytes());
+        assert_eq!(kving.get_string("key-7").unwrap(), format!("{:0>100}", 1));
+        kving.close().unwrap();
+    }
+
+    #[test]
+    fn close_aborts_a_running_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let kving = slow_merging(dir.path());
+
+        std::thread::scope(|scope| {
+            let merge = scope.spawn(|| kving.merge());
+            wait_for_progress(&kving);
+            let start = Instant::now();
+            kving.close().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(matches!(
+                merge.join().unwrap(),
+                Err(crate::Error::MergeCancelled)
+            ));
+        });
+        drop(kving);
+
+        let config = Config::builder()
+            .set_data_dir(dir.path().to_path_buf())
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        assert_eq!(kving.get_string("key-99").unwrap(), format!("{:0>100}", 1));
+    }
+
+    fn merger() -> Merger {
+        let store = Memory::with_config(Config::default()).unwrap();
+        Merger::new(Arc::new(Box::new(store)), 0, None)
+    }
+
+    #[test]
+    fn exclusive_waits_for_the_claim_to_be_released() {
+        let merger = merger();
+        assert!(merger.try_begin());
+        assert!(!merger.try_begin());
+
+        let ran = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| merger.exclusive(|| ran.store(true, Ordering::Relaxed)));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!ran.load(Ordering::Relaxed));
+            merger.end();
+            waiter.join().unwrap();
+        });
+        assert!(ran.load(Ordering::Relaxed));
+        assert!(merger.try_begin());
+    }
+
+    #[test]
+    fn abort_cancels_a_merge_that_has_not_started() {
+        let merger = merger();
+        assert!(merger.try_begin());
+
+        std::thread::scope(|scope| {
+            // Holds the claim without having started, until asked to stop
+            scope.spawn(|| {
+                while !merger.control.is_cancelled() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                merger.end();
+            });
+            let deadline = Instant::now() + Duration::from_secs(10);
+            assert_eq!(merger.abort(Some(deadline), || 1), Some(1));
+        });
+        assert!(!merger.control.is_cancelled());
+    }
+
+    #[test]
+    fn abort_gives_up_at_the_deadline() {
+        let merger = merger();
+        assert!(merger.try_begin());
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert_eq!(merger.abort(Some(deadline), || 1), None);
+        assert!(Instant::now() >= deadline);
+    }
+}
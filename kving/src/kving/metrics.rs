@@ -0,0 +1,280 @@
+#[cfg(feature = "prometheus")]
+use crate::kving::kving::Kving;
+#[cfg(feature = "prometheus")]
+pub use metrics_exporter_prometheus::PrometheusHandle;
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+
+/// Run the operation `op` with `f`, recording its latency and the bytes it moved as
+/// reported by `bytes`, or an error.
+#[cfg(feature = "metrics")]
+pub(crate) fn observe<T>(
+    op: &'static str,
+    f: impl FnOnce() -> crate::Result<T>,
+    bytes: impl FnOnce(&T) -> u64,
+) -> crate::Result<T> {
+    let start = Instant::now();
+    let result = f();
+    match &result {
+        Ok(value) => {
+            metrics::histogram!("kving_operation_duration_seconds", "op" => op)
+                .record(start.elapsed());
+            metrics::histogram!("kving_operation_bytes", "op" => op).record(bytes(value) as f64);
+        }
+        Err(_) => metrics::counter!("kving_operation_errors_total", "op" => op).increment(1),
+    }
+    result
+}
+
+/// Run the operation `op` with `f`.
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn observe<T>(
+    _op: &'static str,
+    f: impl FnOnce() -> crate::Result<T>,
+    _bytes: impl FnOnce(&T) -> u64,
+) -> crate::Result<T> {
+    f()
+}
+
+/// Count a record, block or page of `engine` that failed its checksum.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn crc_failure(engine: &'static str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("kving_crc_failures_total", "engine" => engine).increment(1);
+}
+
+/// Count the switch to a new active data file.
+pub(crate) fn file_rotation() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("kving_file_rotations_total").increment(1);
+}
+
+/// Record a group commit that appended `puts` puts with a single write.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn group_commit(puts: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("kving_group_commit_puts").record(puts as f64);
+}
+
+/// Record a completed merge that took `duration`.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn merge_completed(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("kving_merges_total").increment(1);
+        metrics::histogram!("kving_merge_duration_seconds").record(duration);
+    }
+}
+
+/// Count a merge that failed.
+pub(crate) fn merge_failed() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("kving_merge_errors_total").increment(1);
+}
+
+/// Registers the units and descriptions of the kving metrics with the installed recorder.
+/// Call it once after installing a recorder other than the one of
+/// [`install_prometheus_recorder`].
+///
+/// | Metric | Kind | Labels |
+/// |---|---|---|
+/// | `kving_operation_duration_seconds` | histogram | `op` |
+/// | `kving_operation_bytes` | histogram | `op` |
+/// | `kving_operation_errors_total` | counter | `op` |
+/// | `kving_crc_failures_total` | counter | `engine` |
+/// | `kving_file_rotations_total` | counter | |
+/// | `kving_group_commit_puts` | histogram | |
+/// | `kving_merges_total` | counter | |
+/// | `kving_merge_errors_total` | counter | |
+/// | `kving_merge_duration_seconds` | histogram | |
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+    use metrics::{Unit, describe_counter, describe_gauge, describe_histogram};
+
+    describe_histogram!(
+        "kving_operation_duration_seconds",
+        Unit::Seconds,
+        "Latency of successful get, put and delete operations"
+    );
+    describe_histogram!(
+        "kving_operation_bytes",
+        Unit::Bytes,
+        "Bytes read by get, or written by put and delete"
+    );
+    describe_counter!(
+        "kving_operation_errors_total",
+        Unit::Count,
+        "Operations that failed"
+    );
+    describe_counter!(
+        "kving_crc_failures_total",
+        Unit::Count,
+        "Stored data that failed its checksum"
+    );
+    describe_counter!(
+        "kving_file_rotations_total",
+        Unit::Count,
+        "Switches to a new active data file"
+    );
+    describe_histogram!(
+        "kving_group_commit_puts",
+        Unit::Count,
+        "Puts appended by each group commit of the io_uring backend"
+    );
+    describe_counter!("kving_merges_total", Unit::Count, "Completed merges");
+    describe_counter!("kving_merge_errors_total", Unit::Count, "Failed merges");
+    describe_histogram!(
+        "kving_merge_duration_seconds",
+        Unit::Seconds,
+        "Duration of completed merges"
+    );
+    describe_gauge!("kving_keys", Unit::Count, "Live keys");
+    describe_gauge!("kving_data_bytes", Unit::Bytes, "Size of the data files");
+    describe_gauge!(
+        "kving_live_bytes",
+        Unit::Bytes,
+        "Size of the records live keys point to"
+    );
+    describe_gauge!(
+        "kving_data_files",
+        Unit::Count,
+        "Data files, the active file included"
+    );
+    describe_gauge!(
+        "kving_keydir_memory_bytes",
+        Unit::Bytes,
+        "Estimated memory used by the in-memory index"
+    );
+}
+
+/// Installs a global Prometheus recorder for the kving metrics and any other metrics
+/// the application emits.
+///
+/// # Returns
+/// * `Result<PrometheusHandle>` - Handle to pass to [`render_prometheus`], or error if
+///   a recorder is already installed
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus_recorder() -> crate::Result<PrometheusHandle> {
+    use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+
+    const LATENCY_BUCKETS: [f64; 12] = [
+        0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+    ];
+    const SIZE_BUCKETS: [f64; 8] = [
+        64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+    ];
+    const MERGE_BUCKETS: [f64; 8] = [0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0, 300.0];
+
+    let to_error = |e: metrics_exporter_prometheus::BuildError| {
+        crate::Error::InvalidData(format!("Failed to install Prometheus recorder: {}", e))
+    };
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("kving_operation_duration_seconds".to_string()),
+            &LATENCY_BUCKETS,
+        )
+        .map_err(to_error)?
+        .set_buckets_for_metric(
+            Matcher::Full("kving_operation_bytes".to_string()),
+            &SIZE_BUCKETS,
+        )
+        .map_err(to_error)?
+        .set_buckets_for_metric(
+            Matcher::Full("kving_merge_duration_seconds".to_string()),
+            &MERGE_BUCKETS,
+        )
+        .map_err(to_error)?
+        .install_recorder()
+        .map_err(to_error)?;
+    describe_metrics();
+    Ok(handle)
+}
+
+/// Renders the Prometheus exposition text of everything recorded through `handle`,
+/// after publishing the statistics of `kving` as gauges.
+///
+/// # Arguments
+/// * `handle` - Handle returned by [`install_prometheus_recorder`]
+/// * `kving` - Database whose statistics are published
+///
+/// # Returns
+/// * `Result<String>` - Text to serve on the metrics endpoint, or error
+#[cfg(feature = "prometheus")]
+pub fn render_prometheus(handle: &PrometheusHandle, kving: &Kving) -> crate::Result<String> {
+    let stats = kving.stats()?;
+    metrics::gauge!("kving_keys").set(stats.key_count as f64);
+    metrics::gauge!("kving_data_bytes").set(stats.total_bytes() as f64);
+    metrics::gauge!("kving_live_bytes").set(stats.live_bytes() as f64);
+    metrics::gauge!("kving_data_files").set(stats.files.len() as f64);
+    metrics::gauge!("kving_keydir_memory_bytes").set(stats.keydir_memory as f64);
+    Ok(handle.render())
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+    use super::*;
+    use crate::kving::config::Config;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    fn config(dir: &tempfile::TempDir, read_only: bool) -> Config {
+        Config::builder()
+            .set_data_dir(dir.path().to_path_buf())
+            .set_max_file_size(64)
+            .set_read_only(read_only)
+            .build()
+    }
+
+    #[test]
+    fn records_operations_rotations_and_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let kving = Kving::with_config(config(&dir, false)).unwrap();
+            for i in 0..4 {
+                kving
+                    .put_string(format!("key-{}", i), "v".repeat(32))
+                    .unwrap();
+            }
+            assert_eq!(kving.get_string("key-0").unwrap(), "v".repeat(32));
+            kving.delete("key-1").unwrap();
+            drop(kving);
+
+            let kving = Kving::with_config(config(&dir, true)).unwrap();
+            assert!(kving.put_string("key-4", "value").is_err());
+        });
+
+        let text = handle.render();
+        assert!(text.contains("kving_operation_duration_seconds_count{op=\"put\"} 4"));
+        assert!(text.contains("kving_operation_duration_seconds_count{op=\"get\"} 1"));
+        assert!(text.contains("kving_operation_duration_seconds_count{op=\"delete\"} 1"));
+        assert!(text.contains("kving_operation_bytes_sum{op=\"get\"} 32"));
+        assert!(text.contains("kving_operation_errors_total{op=\"put\"} 1"));
+        assert!(text.contains("kving_file_rotations_total"));
+    }
+
+    #[test]
+    fn renders_statistics_as_gauges() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let kving = Kving::with_config(config(&dir, false)).unwrap();
+        kving.put_string("key-0", "value").unwrap();
+        kving.put_string("key-1", "value").unwrap();
+
+        let text =
+            metrics::with_local_recorder(&recorder, || render_prometheus(&handle, &kving).unwrap());
+        assert!(text.contains("kving_keys 2"));
+        assert!(text.contains("kving_data_files "));
+        assert!(text.contains("kving_keydir_memory_bytes "));
+    }
+
+    #[test]
+    fn installs_the_global_recorder_once() {
+        let handle = install_prometheus_recorder().unwrap();
+        metrics::counter!("kving_merges_total").increment(1);
+        assert!(handle.render().contains("kving_merges_total 1"));
+        assert!(install_prometheus_recorder().is_err());
+    }
+}
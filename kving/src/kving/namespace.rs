@@ -0,0 +1,423 @@
+use crate::kving::kving::{
+    Kving, decode_bool, decode_f32, decode_f64, decode_isize, decode_string, decode_usize,
+};
+use crate::kving::listener::{Change, ListenerHandle};
+use std::borrow::Cow;
+
+/// First byte of every namespaced key. It can never start a UTF-8 encoded key,
+/// so namespaced keys don't collide with keys of the default namespace.
+const NAMESPACE_TAG: u8 = 0xFE;
+
+/// Separates the namespace name from the key.
+const NAMESPACE_SEPARATOR: u8 = 0x00;
+
+/// First byte of escaped keys of the default namespace. Like the namespace tag, it can
+/// never start a UTF-8 encoded key, so databases written before escaping contain none.
+const ESCAPE_TAG: u8 = 0xFD;
+
+/// Get the stored form of `key` of the default namespace. Keys starting with a byte
+/// reserved for escaped keys, namespaced keys or the records of engines (`0xFF`) are
+/// prefixed with `ESCAPE_TAG`, any other key is stored as it is.
+pub(crate) fn escape_key(key: &[u8]) -> Cow<'_, [u8]> {
+    match key.first() {
+        Some(&(ESCAPE_TAG..)) => {
+            let mut escaped = Vec::with_capacity(key.len() + 1);
+            escaped.push(ESCAPE_TAG);
+            escaped.extend_from_slice(key);
+            Cow::Owned(escaped)
+        }
+        _ => Cow::Borrowed(key),
+    }
+}
+
+/// Build the key prefix shared by every key of a namespace.
+pub(crate) fn namespace_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(name.len() + 2);
+    prefix.push(NAMESPACE_TAG);
+    prefix.extend_from_slice(name.as_bytes());
+    prefix.push(NAMESPACE_SEPARATOR);
+    prefix
+}
+
+/// Split a stored key into its namespace (`None` for the default namespace) and key.
+/// Returns `None` if the key isn't valid UTF-8.
+pub(crate) fn split_key(key: &[u8]) -> Option<(Option<&str>, &str)> {
+    let (name, key) = split_raw_key(key)?;
+    let name = match name {
+        Some(name) => Some(std::str::from_utf8(name).ok()?),
+        None => None,
+    };
+    Some((name, std::str::from_utf8(key).ok()?))
+}
+
+/// Split a stored key into the bytes of its namespace (`None` for the default namespace)
+/// and key, unescaping keys of the default namespace. Returns `None` if a namespaced key
+/// has no separator.
+pub(crate) fn split_raw_key(key: &[u8]) -> Option<(Option<&[u8]>, &[u8])> {
+    match key.split_first() {
+        Some((&NAMESPACE_TAG, rest)) => {
+            let sep = rest.iter().position(|&b| b == NAMESPACE_SEPARATOR)?;
+            Some((Some(&rest[..sep]), &rest[sep + 1..]))
+        }
+        Some((&ESCAPE_TAG, rest)) => Some((None, rest)),
+        _ => Some((None, key)),
+    }
+}
+
+/// Check that `name` can be used as a namespace name.
+pub(crate) fn validate_name(name: &str) -> crate::Result<()> {
+    if name.is_empty() || name.as_bytes().contains(&NAMESPACE_SEPARATOR) {
+        return Err(crate::Error::InvalidData(format!(
+            "Invalid namespace name: {:?}",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Statistics of a single namespace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    /// Number of live keys.
+    pub key_count: u64,
+    /// Total size of the live keys in bytes, excluding the namespace prefix.
+    pub key_bytes: u64,
+    /// Total size of the live values in bytes.
+    pub value_bytes: u64,
+}
+
+/// A handle to an isolated key space inside a [`Kving`] database.
+///
+/// All namespaces share the storage, file handles and merge thread of the database
+/// they were created from, but their keys never collide with each other or with the
+/// keys of the default namespace.
+pub struct Namespace<'a> {
+    kving: &'a Kving,
+    name: String,
+    prefix: Vec<u8>,
+}
+
+impl<'a> Namespace<'a> {
+    /// Creates a namespace handle. The name has already been validated.
+    pub(crate) fn new(kving: &'a Kving, name: String) -> Self {
+        let prefix = namespace_prefix(&name);
+        Self {
+            kving,
+            name,
+            prefix,
+        }
+    }
+
+    /// Get the namespace name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the key prefix of this namespace.
+    pub(crate) fn prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Build the stored key for `key` in this namespace.
+    fn key(&self, key: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.prefix.len() + key.len());
+        buf.extend_from_slice(&self.prefix);
+        buf.extend_from_slice(key.as_bytes());
+        buf
+    }
+
+    /// Retrieves a signed integer value for the given key.
+    pub fn get_isize<K>(&self, key: K) -> Option<isize>
+    where
+        K: AsRef<str>,
+    {
+        decode_isize(self.get_blob(key)?)
+    }
+
+    /// Retrieves an unsigned integer value for the given key.
+    pub fn get_usize<K>(&self, key: K) -> Option<usize>
+    where
+        K: AsRef<str>,
+    {
+        decode_usize(self.get_blob(key)?)
+    }
+
+    /// Retrieves a 32-bit floating point value for the given key.
+    pub fn get_f32<K>(&self, key: K) -> Option<f32>
+    where
+        K: AsRef<str>,
+    {
+        decode_f32(self.get_blob(key)?)
+    }
+
+    /// Retrieves a 64-bit floating point value for the given key.
+    pub fn get_f64<K>(&self, key: K) -> Option<f64>
+    where
+        K: AsRef<str>,
+    {
+        decode_f64(self.get_blob(key)?)
+    }
+
+    /// Retrieves a boolean value for the given key.
+    pub fn get_bool<K>(&self, key: K) -> Option<bool>
+    where
+        K: AsRef<str>,
+    {
+        decode_bool(self.get_blob(key)?)
+    }
+
+    /// Retrieves a string value for the given key.
+    pub fn get_string<K>(&self, key: K) -> Option<String>
+    where
+        K: AsRef<str>,
+    {
+        decode_string(self.get_blob(key)?)
+    }
+
+    /// Retrieves a binary blob value for the given key.
+    pub fn get_blob<K>(&self, key: K) -> Option<Vec<u8>>
+    where
+        K: AsRef<str>,
+    {
+        self.kving.get_raw(&self.key(key.as_ref())).ok()?
+    }
+
+    /// Stores a signed integer value for the given key.
+    pub fn put_isize<K>(&self, key: K, value: isize) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Stores an unsigned integer value for the given key.
+    pub fn put_usize<K>(&self, key: K, value: usize) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Stores a 32-bit floating point value for the given key.
+    pub fn put_f32<K>(&self, key: K, value: f32) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Stores a 64-bit floating point value for the given key.
+    pub fn put_f64<K>(&self, key: K, value: f64) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, &value.to_be_bytes())
+    }
+
+    /// Stores a boolean value for the given key.
+    pub fn put_bool<K>(&self, key: K, value: bool) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.put_blob(key, if value { &[1] } else { &[0] })
+    }
+
+    /// Stores a string value for the given key.
+    pub fn put_string<K, V>(&self, key: K, value: V) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.put_blob(key, value.as_ref().as_bytes())
+    }
+
+    /// Stores a binary blob value for the given key.
+    pub fn put_blob<K>(&self, key: K, value: &[u8]) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.kving.put_raw(&self.key(key.as_ref()), value)
+    }
+
+    /// Deletes the value associated with the given key.
+    pub fn delete<K>(&self, key: K) -> crate::Result<()>
+    where
+        K: AsRef<str>,
+    {
+        self.kving.delete_raw(&self.key(key.as_ref()))
+    }
+
+    /// Checks if the namespace contains a value for the given key.
+    pub fn contains<K>(&self, key: K) -> crate::Result<bool>
+    where
+        K: AsRef<str>,
+    {
+        self.kving.contains_raw(&self.key(key.as_ref()))
+    }
+
+    /// Returns a list of all keys in this namespace.
+    ///
+    /// # Returns
+    /// * `Result<Vec<String>>` - List of keys without the namespace prefix, or error
+    pub fn list_keys(&self) -> crate::Result<Vec<String>> {
+        let keys = self
+            .kving
+            .list_raw_keys(&self.prefix)?
+            .iter()
+            .filter_map(|k| String::from_utf8(k[self.prefix.len()..].to_vec()).ok())
+            .collect();
+        Ok(keys)
+    }
+
+    /// Deletes every key of this namespace in one atomic batch.
+    /// Other namespaces are left untouched.
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or error indicator
+    pub fn clear(&self) -> crate::Result<()> {
+        self.kving.clear_namespace(self)
+    }
+
+    /// Returns statistics about the live data of this namespace.
+    ///
+    /// # Returns
+    /// * `Result<NamespaceStats>` - Key count and sizes, or error
+    pub fn stats(&self) -> crate::Result<NamespaceStats> {
+        let mut stats = NamespaceStats::default();
+        for key in self.kving.list_raw_keys(&self.prefix)? {
+            if let Some(value_len) = self.kving.value_len_raw(&key)? {
+                stats.key_count += 1;
+                stats.key_bytes += (key.len() - self.prefix.len()) as u64;
+                stats.value_bytes += value_len;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Registers a listener for changes to keys of this namespace starting with `prefix`.
+    /// See [`Kving::register_listener`] for the threading contract.
+    ///
+    /// # Returns
+    /// * `ListenerHandle` - Handle that unregisters the listener when dropped
+    pub fn register_listener<P, F>(&self, prefix: P, callback: F) -> ListenerHandle
+    where
+        P: AsRef<str>,
+        F: Fn(&Change) + Send + Sync + 'static,
+    {
+        self.kving
+            .register_namespace_listener(Some(self.name.clone()), prefix, callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BatchOp, Config, KvStore, Kving, WriteBatch};
+
+    fn open(dir: &std::path::Path) -> Kving {
+        let config = Config::builder().set_data_dir(dir.to_path_buf()).build();
+        Kving::with_config(config).unwrap()
+    }
+
+    #[test]
+    fn isolates_keys_and_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let kving = open(dir.path());
+            let users = kving.namespace("users").unwrap();
+            users.put_string("id", "user").unwrap();
+            kving.put_string("id", "default").unwrap();
+            kving
+                .namespace("posts")
+                .unwrap()
+                .put_string("id", "post")
+                .unwrap();
+            kving.close().unwrap();
+        }
+
+        let kving = open(dir.path());
+        let users = kving.namespace("users").unwrap();
+        assert_eq!(users.get_string("id").as_deref(), Some("user"));
+        assert_eq!(kving.get_string("id").as_deref(), Some("default"));
+        assert_eq!(kving.list_keys().unwrap(), ["id"]);
+        assert_eq!(users.list_keys().unwrap(), ["id"]);
+        assert_eq!(kving.namespaces().unwrap(), ["posts", "users"]);
+
+        let stats = users.stats().unwrap();
+        assert_eq!(
+            (stats.key_count, stats.key_bytes, stats.value_bytes),
+            (1, 2, 4)
+        );
+
+        users.clear().unwrap();
+        assert!(users.list_keys().unwrap().is_empty());
+        assert_eq!(kving.get_string("id").as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn batches_span_namespaces() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let kving = open(dir.path());
+            let users = kving.namespace("users").unwrap();
+            kving.put_string("stale", "v").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put_string("a", "1").delete("stale");
+            batch.namespace(&users).put_string("a", "2");
+            kving.write_batch(batch).unwrap();
+            kving.close().unwrap();
+        }
+
+        let kving = open(dir.path());
+        assert_eq!(kving.get_string("a").as_deref(), Some("1"));
+        assert_eq!(kving.get_string("stale"), None);
+        let users = kving.namespace("users").unwrap();
+        assert_eq!(users.get_string("a").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn escapes_keys_with_reserved_first_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys: [&[u8]; 4] = [b"\xFFbatch", b"\xFEusers\x00id", b"\xFDkey", b"plain"];
+        {
+            let kving = open(dir.path());
+            let store: &dyn KvStore = &kving;
+            for key in keys {
+                store.put(key, key).unwrap();
+            }
+            // Not confused with the batch markers of Bitcask or with a namespace
+            let ops = [BatchOp::Put(
+                b"\xFFbatch".to_vec(),
+                u64::MAX.to_be_bytes().to_vec(),
+            )];
+            store.write_batch(&ops).unwrap();
+            assert!(kving.namespaces().unwrap().is_empty());
+            assert!(
+                kving
+                    .namespace("users")
+                    .unwrap()
+                    .list_keys()
+                    .unwrap()
+                    .is_empty()
+            );
+            assert!(kving.namespace("bad\0name").is_err());
+        }
+
+        let kving = open(dir.path());
+        let store: &dyn KvStore = &kving;
+        let mut listed = store.list_keys().unwrap();
+        listed.sort();
+        let mut expected = keys.map(<[u8]>::to_vec).to_vec();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(kving.list_keys().unwrap(), ["plain"]);
+        assert_eq!(
+            store.get(b"\xFFbatch").unwrap(),
+            Some(u64::MAX.to_be_bytes().to_vec())
+        );
+        assert_eq!(store.value_len(b"\xFDkey").unwrap(), Some(4));
+        store.delete(b"\xFEusers\x00id").unwrap();
+        assert!(!store.contains(b"\xFEusers\x00id").unwrap());
+        assert!(store.contains(b"\xFDkey").unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::kving::metrics;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Statistics of a single data file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// Id of the data file.
+    pub file_id: u64,
+    /// Size of the file in bytes.
+    pub total_bytes: u64,
+    /// Size of the records that live keys point to, in bytes.
+    pub live_bytes: u64,
+}
+
+/// Operational statistics of a database, see [`Kving::stats`](crate::Kving::stats).
+///
+/// Fields an engine doesn't track are left at zero: only Bitcask reports data files,
+/// file handle cache usage and keydir memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of live keys. Estimated by the LSM engine, which counts overwritten
+    /// versions and deletions in its tables until a compaction merges them away.
+    pub key_count: u64,
+    /// Data files sorted by id, the active file included.
+    pub files: Vec<FileStats>,
+    /// Size of the active file in bytes.
+    pub active_file_size: u64,
+    /// Number of data files besides the active one.
+    pub historical_files: u64,
+    /// Reads served by a cached file handle.
+    pub cache_hits: u64,
+    /// Reads that had to open their data file.
+    pub cache_misses: u64,
+    /// Number of merges completed since the database was opened.
+    pub merge_count: u64,
+    /// Duration of the last completed merge.
+    pub last_merge_duration: Option<Duration>,
+    /// Estimated memory used by the in-memory index, in bytes.
+    pub keydir_memory: u64,
+}
+
+impl Stats {
+    /// Total size of the data files in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.total_bytes).sum()
+    }
+
+    /// Size of the live records in bytes.
+    pub fn live_bytes(&self) -> u64 {
+        self.files.iter().map(|f| f.live_bytes).sum()
+    }
+
+    /// Fraction of the data file bytes that a merge would reclaim, from 0 to 1.
+    pub fn dead_byte_ratio(&self) -> f64 {
+        let total = self.total_bytes();
+        if total == 0 {
+            return 0.0;
+        }
+        total.saturating_sub(self.live_bytes()) as f64 / total as f64
+    }
+
+    /// Fraction of the reads served by a cached file handle, from 0 to 1.
+    pub fn cache_hit_rate(&self) -> f64 {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.cache_hits as f64 / reads as f64
+    }
+}
+
+/// Counts the merges run by a [`Kving`](crate::Kving) instance.
+#[derive(Default)]
+pub(crate) struct MergeStats {
+    count: AtomicU64,
+    last_duration: Mutex<Option<Duration>>,
+}
+
+impl MergeStats {
+    /// Record a completed merge that took `duration`.
+    pub(crate) fn record(&self, duration: Duration) {
+        if let Ok(mut last_duration) = self.last_duration.lock() {
+            *last_duration = Some(duration);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        metrics::merge_completed(duration);
+    }
+
+    /// Fill the merge fields of `stats`.
+    pub(crate) fn fill(&self, stats: &mut Stats) {
+        stats.merge_count = self.count.load(Ordering::Relaxed);
+        stats.last_merge_duration = self.last_duration.lock().ok().and_then(|d| *d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::Config;
+
+    #[test]
+    fn derives_ratios_from_the_counts() {
+        let empty = Stats::default();
+        assert_eq!(empty.dead_byte_ratio(), 0.0);
+        assert_eq!(empty.cache_hit_rate(), 0.0);
+
+        let stats = Stats {
+            files: vec![
+                FileStats {
+                    file_id: 1,
+                    total_bytes: 100,
+                    live_bytes: 25,
+                },
+                FileStats {
+                    file_id: 2,
+                    total_bytes: 100,
+                    live_bytes: 75,
+                },
+            ],
+            cache_hits: 3,
+            cache_misses: 1,
+            ..Stats::default()
+        };
+        assert_eq!(stats.total_bytes(), 200);
+        assert_eq!(stats.live_bytes(), 100);
+        assert_eq!(stats.dead_byte_ratio(), 0.5);
+        assert_eq!(stats.cache_hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn tracks_files_keys_and_merges() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .set_data_dir(dir.path().to_path_buf())
+            .set_max_file_size(64)
+            .build();
+        let kving = Kving::with_config(config).unwrap();
+        for i in 0..4 {
+            kving.put_string(format!("key-{}", i), "first").unwrap();
+        }
+        kving.put_string("key-0", "second").unwrap();
+        kving.delete("key-1").unwrap();
+
+        let stats = kving.stats().unwrap();
+        assert_eq!(stats.key_count, 3);
+        assert_eq!(stats.historical_files + 1, stats.files.len() as u64);
+        assert!(stats.files.windows(2).all(|f| f[0].file_id < f[1].file_id));
+        assert!(stats.live_bytes() < stats.total_bytes());
+        assert!(stats.keydir_memory > 0);
+
+        kving.merge().unwrap();
+        let merged = kving.stats().unwrap();
+        assert_eq!(merged.key_count, 3);
+        assert!(merged.merge_count >= 1);
+        assert!(merged.last_merge_duration.is_some());
+        assert!(merged.dead_byte_ratio() < stats.dead_byte_ratio());
+        kving.close().unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use std::ops::Deref;
+#[cfg(feature = "mmap")]
+use std::ops::Range;
+#[cfg(feature = "mmap")]
+use std::sync::Arc;
+
+/// A value lent by [`Kving::get_ref`](crate::Kving::get_ref), dereferencing to its bytes.
+///
+/// With memory-mapped reads, uncompressed and unencrypted values of immutable data files
+/// are borrowed in place. The mapping lives as long as the guard, even if a merge deletes
+/// the file meanwhile. Other values are copied into the guard.
+pub struct ValueRef(Repr);
+
+enum Repr {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+
+impl ValueRef {
+    /// Lend the bytes at `range` of a mapped data file
+    #[cfg(feature = "mmap")]
+    pub(crate) fn mapped(map: Arc<Mmap>, range: Range<usize>) -> Self {
+        Self(Repr::Mapped(map, range))
+    }
+
+    /// Check if the bytes are borrowed from a mapped data file rather than copied.
+    pub fn is_mapped(&self) -> bool {
+        match &self.0 {
+            Repr::Owned(_) => false,
+            #[cfg(feature = "mmap")]
+            Repr::Mapped(..) => true,
+        }
+    }
+
+    /// Convert into an owned value, copying the bytes only if they are borrowed.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Repr::Owned(value) => value,
+            #[cfg(feature = "mmap")]
+            Repr::Mapped(map, range) => map[range].to_vec(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for ValueRef {
+    fn from(value: Vec<u8>) -> Self {
+        Self(Repr::Owned(value))
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Owned(value) => value,
+            #[cfg(feature = "mmap")]
+            Repr::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl std::fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValueRef")
+            .field("len", &self.len())
+            .field("mapped", &self.is_mapped())
+            .finish()
+    }
+}
@@ -0,0 +1,347 @@
+use crate::kving::diagnostics::{self, ErrorEvent, ErrorHandler, ErrorSource};
+use crate::kving::kv_store::KvStore;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Why a byte range of a data file can't be read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The stored checksum doesn't match the data.
+    Checksum,
+    /// The file ends in the middle of a record, usually after a crash during a write.
+    TornTail,
+    /// The checksum matches but the record can't be decrypted with the configured key.
+    Decryption,
+    /// The checksum matches but the record holds values no writer produces.
+    InvalidRecord,
+}
+
+/// A byte range of a data file that can't be read back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRange {
+    /// Id of the data file, `0` for engines that use a single file.
+    pub file_id: u64,
+    /// Offset of the first corrupt byte.
+    pub offset: u64,
+    /// Length of the range in bytes.
+    pub len: u64,
+    pub kind: CorruptionKind,
+}
+
+/// A valid record that is never applied, because the batch it belongs to is incomplete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanedRecord {
+    pub file_id: u64,
+    /// Offset of the record in the data file.
+    pub offset: u64,
+    pub key: Vec<u8>,
+}
+
+/// A live key whose value can't be read back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadEntry {
+    pub key: Vec<u8>,
+    /// Data file the key points to.
+    pub file_id: u64,
+    /// Offset of the record the key points to.
+    pub offset: u64,
+    /// Description of the failure.
+    pub error: String,
+}
+
+/// Result of [`Kving::verify`](crate::Kving::verify).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of data files that were scanned.
+    pub files_checked: u64,
+    /// Number of records read from the data files, valid or not.
+    pub records_checked: u64,
+    /// Number of live keys whose value was read back.
+    pub keys_checked: u64,
+    pub corrupt_ranges: Vec<CorruptRange>,
+    pub orphaned_records: Vec<OrphanedRecord>,
+    pub bad_entries: Vec<BadEntry>,
+    /// Verification was stopped before it completed, the report only covers part of the data.
+    pub interrupted: bool,
+}
+
+impl VerifyReport {
+    /// Check if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_ranges.is_empty()
+            && self.orphaned_records.is_empty()
+            && self.bad_entries.is_empty()
+    }
+
+    /// Record a corrupt range, extending the previous one if they are adjacent.
+    pub(crate) fn add_corrupt_range(&mut self, range: CorruptRange) {
+        if let Some(last) = self.corrupt_ranges.last_mut()
+            && last.file_id == range.file_id
+            && last.kind == range.kind
+            && last.offset + last.len == range.offset
+        {
+            last.len += range.len;
+            return;
+        }
+        self.corrupt_ranges.push(range);
+    }
+}
+
+/// Result of [`Kving::repair`](crate::Kving::repair).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of data files that were scanned.
+    pub files_checked: u64,
+    /// Byte ranges removed from the data files. A data file that had to be dropped
+    /// as a whole is reported as a single range, empty if the file was missing.
+    pub removed_ranges: Vec<CorruptRange>,
+    /// Valid records removed because the batch they belong to is incomplete.
+    pub orphaned_records: Vec<OrphanedRecord>,
+    /// Bytes cut from the end of the write-ahead log.
+    pub log_bytes_truncated: u64,
+    /// Files holding the removed corrupt data, kept for manual recovery.
+    pub quarantine_files: Vec<PathBuf>,
+}
+
+impl RepairReport {
+    /// Check if nothing had to be removed.
+    pub fn is_clean(&self) -> bool {
+        self.removed_ranges.is_empty()
+            && self.orphaned_records.is_empty()
+            && self.log_bytes_truncated == 0
+    }
+}
+
+/// Limits the read rate of a verification and checks for a stop request.
+pub(crate) struct Throttle<'a> {
+    bytes_per_sec: Option<u64>,
+    stop: &'a AtomicBool,
+    start: Instant,
+    bytes: u64,
+}
+
+impl<'a> Throttle<'a> {
+    pub(crate) fn new(bytes_per_sec: Option<u64>, stop: &'a AtomicBool) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.filter(|rate| *rate > 0),
+            stop,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for `bytes` read, sleeping as long as needed to stay under the rate.
+    /// Returns `false` once the verification should stop.
+    pub(crate) fn consume(&mut self, bytes: u64) -> bool {
+        self.bytes += bytes;
+        if let Some(rate) = self.bytes_per_sec {
+            let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            while !self.stopped() {
+                let elapsed = self.start.elapsed();
+                if elapsed >= due {
+                    break;
+                }
+                std::thread::sleep((due - elapsed).min(Duration::from_millis(100)));
+            }
+        }
+        !self.stopped()
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+/// Background thread verifying the store periodically.
+/// Dropping it stops the thread, interrupting a running verification.
+pub(crate) struct Scrubber {
+    stop: Arc<AtomicBool>,
+    wake: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    last_report: Arc<Mutex<Option<VerifyReport>>>,
+}
+
+impl Scrubber {
+    /// Starts verifying `store` every `interval`, reading at most `bytes_per_sec`.
+    pub(crate) fn start(
+        store: Arc<Box<dyn KvStore>>,
+        interval: Duration,
+        bytes_per_sec: Option<u64>,
+        error_handler: Option<ErrorHandler>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let last_report = Arc::new(Mutex::new(None));
+        let (wake, sleep) = mpsc::channel::<()>();
+
+        let stop_clone = Arc::clone(&stop);
+        let last_report_clone = Arc::clone(&last_report);
+        let handle = std::thread::spawn(move || {
+            // Sleeps for the interval, a message or a disconnect ends the thread
+            while let Err(RecvTimeoutError::Timeout) = sleep.recv_timeout(interval) {
+                match store.verify(bytes_per_sec, &stop_clone) {
+                    Ok(report) => {
+                        if !report.is_ok() {
+                            let error = crate::Error::CorruptedData;
+                            diagnostics::report(
+                                error_handler.as_ref(),
+                                ErrorEvent::new(ErrorSource::Scrub, &error),
+                            );
+                        }
+                        *last_report_clone
+                            .lock()
+                            .expect("Failed to lock scrub report") = Some(report);
+                    }
+                    Err(e) => diagnostics::report(
+                        error_handler.as_ref(),
+                        ErrorEvent::new(ErrorSource::Scrub, &e),
+                    ),
+                }
+            }
+        });
+
+        Self {
+            stop,
+            wake: Some(wake),
+            handle: Some(handle),
+            last_report,
+        }
+    }
+
+    /// Get the report of the last completed scrub.
+    pub(crate) fn last_report(&self) -> Option<VerifyReport> {
+        self.last_report
+            .lock()
+            .expect("Failed to lock scrub report")
+            .clone()
+    }
+
+    /// Ask the thread to stop, interrupting a running verification, without waiting.
+    pub(crate) fn signal_stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        drop(self.wake.take());
+    }
+
+    /// Check if the thread has stopped.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.signal_stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::Config;
+    use std::path::Path;
+
+    fn config(dir: &Path) -> crate::kving::config::Builder {
+        Config::builder().set_data_dir(dir.to_path_buf())
+    }
+
+    /// Flip the last byte of the only data file, inside the value of the last record
+    fn corrupt_last_record(config: &Config) {
+        let extension = config.store_model().extension();
+        let path = std::fs::read_dir(config.database_path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == extension.as_str())
+            })
+            .unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn merges_adjacent_corrupt_ranges() {
+        let range = |offset, kind| CorruptRange {
+            file_id: 1,
+            offset,
+            len: 10,
+            kind,
+        };
+        let mut report = VerifyReport::default();
+        report.add_corrupt_range(range(0, CorruptionKind::Checksum));
+        report.add_corrupt_range(range(10, CorruptionKind::Checksum));
+        report.add_corrupt_range(range(20, CorruptionKind::TornTail));
+        report.add_corrupt_range(range(40, CorruptionKind::TornTail));
+
+        assert_eq!(report.corrupt_ranges.len(), 3);
+        assert_eq!(report.corrupt_ranges[0].len, 20);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn throttle_stops_when_asked() {
+        let stop = AtomicBool::new(false);
+        let mut throttle = Throttle::new(None, &stop);
+        assert!(throttle.consume(1 << 30));
+        stop.store(true, Ordering::Relaxed);
+        assert!(!throttle.consume(1));
+    }
+
+    #[test]
+    fn verify_reports_keys_pointing_at_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path()).build();
+        let kving = Kving::with_config(config.clone()).unwrap();
+        kving.put_string("a", "first").unwrap();
+        kving.put_string("b", "second").unwrap();
+        kving.sync().unwrap();
+        assert!(kving.verify().unwrap().is_ok());
+
+        corrupt_last_record(&config);
+        let report = kving.verify().unwrap();
+        assert_eq!(report.files_checked, 1);
+        assert_eq!(report.corrupt_ranges.len(), 1);
+        assert_eq!(report.corrupt_ranges[0].kind, CorruptionKind::Checksum);
+        assert_eq!(report.bad_entries.len(), 1);
+        assert!(report.bad_entries[0].key.ends_with(b"b"));
+        assert!(!report.interrupted);
+    }
+
+    #[test]
+    fn scrubber_reports_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        let config = config(dir.path())
+            .set_scrub_interval(Duration::from_millis(10))
+            .set_error_handler(move |event| {
+                events_clone.lock().unwrap().push(event.source);
+            })
+            .build();
+        let kving = Kving::with_config(config.clone()).unwrap();
+        kving.put_string("a", "value").unwrap();
+        kving.sync().unwrap();
+        corrupt_last_record(&config);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while kving
+            .last_scrub_report()
+            .is_none_or(|report| report.is_ok())
+        {
+            assert!(Instant::now() < deadline, "no scrub report");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(events.lock().unwrap().contains(&ErrorSource::Scrub));
+        kving.close().unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use crate::kving::diagnostics::{self, ErrorEvent, ErrorHandler, ErrorSource};
+use crate::kving::kv_store::KvStore;
+use crate::kving::merge::{MergeScheduler, Merger};
+use crate::kving::verify::{Scrubber, VerifyReport};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Owns the background threads of a [`Kving`](crate::Kving) instance: merges, the merge
+/// scheduler, the syncer and the scrubber. Dropping it cancels a running merge and joins
+/// them all. Keys never expire, so there is no expiry thread to own.
+pub(crate) struct Workers {
+    merger: Merger,
+    threads: Mutex<Threads>,
+}
+
+#[derive(Default)]
+struct Threads {
+    /// Set once stopping, no thread is started anymore
+    stopped: bool,
+    merges: Vec<JoinHandle<()>>,
+    scrubber: Option<Scrubber>,
+    merge_scheduler: Option<MergeScheduler>,
+    syncer: Option<Syncer>,
+}
+
+impl Threads {
+    fn is_finished(&self) -> bool {
+        self.merges.iter().all(|merge| merge.is_finished())
+            && self.scrubber.as_ref().is_none_or(|s| s.is_finished())
+            && self
+                .merge_scheduler
+                .as_ref()
+                .is_none_or(|s| s.is_finished())
+            && self.syncer.as_ref().is_none_or(|s| s.is_finished())
+    }
+}
+
+impl Workers {
+    pub(crate) fn new(
+        merger: Merger,
+        scrubber: Option<Scrubber>,
+        merge_scheduler: Option<MergeScheduler>,
+        syncer: Option<Syncer>,
+    ) -> Self {
+        Self {
+            merger,
+            threads: Mutex::new(Threads {
+                scrubber,
+                merge_scheduler,
+                syncer,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Start a merge in the background, unless one is running or the workers are stopped.
+    pub(crate) fn spawn_merge(&self) {
+        let mut threads = self.lock();
+        if threads.stopped || !self.merger.try_begin() {
+            return;
+        }
+        threads.merges.retain(|merge| !merge.is_finished());
+        let merger = self.merger.clone();
+        threads
+            .merges
+            .push(std::thread::spawn(move || merger.run_claimed()));
+    }
+
+    /// Get the report of the last completed scrub.
+    pub(crate) fn last_scrub_report(&self) -> Option<VerifyReport> {
+        self.lock().scrubber.as_ref()?.last_report()
+    }
+
+    /// Stop every thread, cancelling a running merge, and wait for them until `deadline`,
+    /// forever if `None`. Returns false if a thread is still running at the deadline.
+    pub(crate) fn stop(&self, deadline: Option<Instant>) -> bool {
+        let mut threads = self.lock();
+        threads.stopped = true;
+        if let Some(scrubber) = threads.scrubber.as_mut() {
+            scrubber.signal_stop();
+        }
+        if let Some(merge_scheduler) = threads.merge_scheduler.as_mut() {
+            merge_scheduler.signal_stop();
+        }
+        if let Some(syncer) = threads.syncer.as_mut() {
+            syncer.signal_stop();
+        }
+
+        // The scheduler may start one last merge before it sees the signal
+        loop {
+            self.merger.cancel();
+            if threads.is_finished() {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        for merge in threads.merges.drain(..) {
+            let _ = merge.join();
+        }
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Threads> {
+        self.threads.lock().expect("Failed to lock workers")
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.stop(None);
+    }
+}
+
+/// Background thread syncing the store periodically.
+/// Dropping it stops the thread, once a running sync has completed.
+pub(crate) struct Syncer {
+    wake: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Starts syncing `store` every `interval`.
+    pub(crate) fn start(
+        store: Arc<Box<dyn KvStore>>,
+        interval: Duration,
+        error_handler: Option<ErrorHandler>,
+    ) -> Self {
+        let (wake, sleep) = mpsc::channel::<()>();
+
+        let handle = std::thread::spawn(move || {
+            // Sleeps for the interval, a message or a disconnect ends the thread
+            while let Err(RecvTimeoutError::Timeout) = sleep.recv_timeout(interval) {
+                if let Err(e) = store.sync() {
+                    diagnostics::report(
+                        error_handler.as_ref(),
+                        ErrorEvent::new(ErrorSource::Sync, &e),
+                    );
+                }
+            }
+        });
+
+        Self {
+            wake: Some(wake),
+            handle: Some(handle),
+        }
+    }
+
+    /// Ask the thread to stop once a running sync has completed, without waiting.
+    pub(crate) fn signal_stop(&mut self) {
+        drop(self.wake.take());
+    }
+
+    /// Check if the thread has stopped.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.signal_stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::Config;
+    use crate::kving::kv_store::BatchOp;
+    use crate::kving::merge::MergeControl;
+    use crate::memory::memory::Memory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An in-memory engine counting its syncs, failing them if asked to
+    struct CountingSyncs {
+        inner: Memory,
+        syncs: Arc<AtomicUsize>,
+        fail: bool,
+    }
+
+    impl KvStore for CountingSyncs {
+        fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+            self.inner.put(key, value)
+        }
+
+        fn delete(&self, key: &[u8]) -> crate::Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn contains(&self, key: &[u8]) -> crate::Result<bool> {
+            self.inner.contains(key)
+        }
+
+        fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+            self.inner.write_batch(ops)
+        }
+
+        fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
+            self.inner.list_keys()
+        }
+
+        fn clear(&self) -> crate::Result<()> {
+            self.inner.clear()
+        }
+
+        fn sync(&self) -> crate::Result<()> {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            if self.fail {
+                return Err(std::io::Error::other("disk gone").into());
+            }
+            Ok(())
+        }
+
+        fn can_merge(&self) -> crate::Result<bool> {
+            Ok(false)
+        }
+
+        fn merge(&self, control: &MergeControl) -> crate::Result<()> {
+            self.inner.merge(control)
+        }
+
+        fn close(&self) -> crate::Result<()> {
+            self.inner.close()
+        }
+    }
+
+    fn counting_syncs(fail: bool) -> (Arc<Box<dyn KvStore>>, Arc<AtomicUsize>) {
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let store = CountingSyncs {
+            inner: Memory::with_config(Config::default()).unwrap(),
+            syncs: Arc::clone(&syncs),
+            fail,
+        };
+        (Arc::new(Box::new(store)), syncs)
+    }
+
+    fn wait_for_syncs(syncs: &AtomicUsize, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while syncs.load(Ordering::Relaxed) < count {
+            assert!(Instant::now() < deadline, "too few syncs");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn syncer_syncs_until_stopped() {
+        let (store, syncs) = counting_syncs(false);
+        let syncer = Syncer::start(store, Duration::from_millis(5), None);
+        wait_for_syncs(&syncs, 3);
+
+        drop(syncer);
+        let stopped_at = syncs.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(syncs.load(Ordering::Relaxed), stopped_at);
+    }
+
+    #[test]
+    fn syncer_reports_failures() {
+        let (store, syncs) = counting_syncs(true);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        let handler = ErrorHandler::new(move |event| {
+            events_clone.lock().unwrap().push(event.source);
+        });
+        let mut syncer = Syncer::start(store, Duration::from_millis(5), Some(handler));
+        wait_for_syncs(&syncs, 2);
+
+        syncer.signal_stop();
+        drop(syncer);
+        let events = events.lock().unwrap();
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|source| *source == ErrorSource::Sync));
+    }
+
+    #[test]
+    fn shutdown_stops_every_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .set_data_dir(dir.path().to_path_buf())
+            .set_sync_interval(Duration::from_millis(5))
+            .set_scrub_interval(Duration::from_millis(5))
+            .build();
+        let kving = Kving::with_config(config.clone()).unwrap();
+        kving.put_string("a", "value").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        kving.shutdown(Duration::from_secs(5)).unwrap();
+        drop(kving);
+        let kving = Kving::with_config(config).unwrap();
+        assert_eq!(kving.get_string("a").unwrap(), "value");
+    }
+}
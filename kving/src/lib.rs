@@ -1,15 +1,70 @@
+#![allow(clippy::module_inception)]
+
 mod kving {
+    #[cfg(feature = "tokio")]
+    pub mod async_kving;
+    pub mod batch;
     pub mod config;
+    pub mod diagnostics;
     pub mod errors;
     pub mod kv_store;
     pub mod kving;
+    pub mod listener;
+    pub mod lock;
+    pub mod merge;
+    pub mod metrics;
+    pub mod namespace;
+    pub mod stats;
+    pub mod value;
+    pub mod verify;
+    pub mod workers;
 }
 
 mod bitcask {
     pub mod bitcask;
+    pub mod codec;
+    pub mod crypto;
+    pub mod io;
+    pub mod mmap;
+}
+
+mod btree {
+    pub mod btree;
+}
+
+mod lsm {
+    pub mod bloom;
+    pub mod lsm;
+    pub mod sstable;
+    pub mod wal;
+}
+
+mod memory {
+    pub mod memory;
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
+#[cfg(feature = "zstd")]
+pub use bitcask::codec::train_compression_dictionary;
+#[cfg(feature = "tokio")]
+pub use kving::async_kving::{
+    AsyncKving, ChangeEvent, ChangeStream, DEFAULT_MAX_BLOCKING_TASKS, EntryStream, KeyStream,
+};
+pub use kving::batch::*;
 pub use kving::config::*;
+pub use kving::diagnostics::{ErrorEvent, ErrorSource};
 pub use kving::errors::*;
+pub use kving::kv_store::{BatchOp, KvStore};
 pub use kving::kving::*;
+pub use kving::listener::{Change, ListenerHandle};
+pub use kving::merge::{MergeControl, MergeProgress};
+#[cfg(feature = "metrics")]
+pub use kving::metrics::describe_metrics;
+#[cfg(feature = "prometheus")]
+pub use kving::metrics::{PrometheusHandle, install_prometheus_recorder, render_prometheus};
+pub use kving::namespace::{Namespace, NamespaceStats};
+pub use kving::stats::{FileStats, Stats};
+pub use kving::value::ValueRef;
+pub use kving::verify::{
+    BadEntry, CorruptRange, CorruptionKind, OrphanedRecord, RepairReport, VerifyReport,
+};
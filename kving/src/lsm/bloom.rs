@@ -0,0 +1,73 @@
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+
+/// A bloom filter over the keys of a SSTable.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Bits reserved per key, which gives a false positive rate of about 1%.
+    const BITS_PER_KEY: usize = 10;
+
+    /// Seed of the second hash used for double hashing.
+    const SEED: u32 = 0x9E37_79B9;
+
+    /// Create an empty filter sized for `keys` keys
+    pub fn with_capacity(keys: usize) -> Self {
+        let bits = (keys * Self::BITS_PER_KEY).max(64);
+        // k = bits_per_key * ln(2) is the optimal number of hash functions
+        let hashes = ((Self::BITS_PER_KEY as f64) * 0.69) as u32;
+        Self {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes: hashes.clamp(1, 30),
+        }
+    }
+
+    /// Add a key to the filter
+    pub fn insert(&mut self, key: &[u8]) {
+        let nbits = self.bits.len() as u64 * 8;
+        for bit in Self::bit_positions(key, self.hashes, nbits) {
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Check if the key may be in the filter. `false` means it definitely isn't.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let nbits = self.bits.len() as u64 * 8;
+        if nbits == 0 {
+            return true;
+        }
+        Self::bit_positions(key, self.hashes, nbits)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Compute the bit positions of a key with double hashing
+    fn bit_positions(key: &[u8], hashes: u32, nbits: u64) -> impl Iterator<Item = u64> {
+        let mut hasher = Hasher::new();
+        hasher.update(key);
+        let h1 = hasher.finalize() as u64;
+        let mut hasher = Hasher::new_with_initial(Self::SEED);
+        hasher.update(key);
+        let h2 = hasher.finalize() as u64 | 1;
+        (0..hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % nbits)
+    }
+
+    /// Encode the filter into a byte array
+    pub fn encode(&self) -> crate::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        buf.write_u32::<BE>(self.hashes)?;
+        buf.extend_from_slice(&self.bits);
+        Ok(buf)
+    }
+
+    /// Decode a filter written by `encode`
+    pub fn decode(mut buf: &[u8]) -> crate::Result<Self> {
+        let hashes = buf.read_u32::<BE>()?;
+        Ok(Self {
+            bits: buf.to_vec(),
+            hashes,
+        })
+    }
+}
@@ -0,0 +1,948 @@
+use crate::kving::config::Config;
+use crate::kving::diagnostics::{self, ErrorEvent, ErrorSource};
+use crate::kving::kv_store::{BatchOp, KvStore};
+use crate::kving::lock::DirLock;
+use crate::kving::merge::MergeControl;
+use crate::kving::metrics;
+use crate::kving::stats::Stats;
+use crate::kving::verify::{CorruptRange, CorruptionKind, RepairReport, Throttle, VerifyReport};
+use crate::lsm::sstable::{Entry, SsTable, SsTableWriter};
+use crate::lsm::wal::Wal;
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Name of the write-ahead log file
+const WAL_FILE_NAME: &str = "wal.log";
+
+/// Name of the manifest file listing the tables of every level
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// Number of levels, including level 0
+const MAX_LEVELS: usize = 7;
+
+/// Size ratio between two adjacent levels
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Estimated memory overhead of one memtable entry
+const MEMTABLE_ENTRY_OVERHEAD: u64 = 16;
+
+/// Mutable state of the LSM-tree
+struct State {
+    /// Most recent writes, `None` marks a deletion
+    memtable: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    memtable_size: u64,
+    wal: Wal,
+    /// Level 0 tables are ordered from oldest to newest and may overlap.
+    /// Tables of deeper levels are sorted by key and don't overlap.
+    levels: Vec<Vec<Arc<SsTable>>>,
+}
+
+/// Tables picked for a compaction, merged while the state lock is released
+struct Compaction {
+    level: usize,
+    inputs: Vec<Arc<SsTable>>,
+    /// Tables of the next level overlapping the inputs
+    overlapping: Vec<Arc<SsTable>>,
+    drop_tombstones: bool,
+}
+
+/// A k-way merge input, sources with lower index hold newer data
+struct MergeSource {
+    iter: Box<dyn Iterator<Item = crate::Result<Entry>>>,
+    head: Option<Entry>,
+}
+
+impl MergeSource {
+    fn new(mut iter: Box<dyn Iterator<Item = crate::Result<Entry>>>) -> crate::Result<Self> {
+        let head = iter.next().transpose()?;
+        Ok(Self { iter, head })
+    }
+
+    fn advance(&mut self) -> crate::Result<()> {
+        self.head = self.iter.next().transpose()?;
+        Ok(())
+    }
+}
+
+/// A log-structured merge-tree storage engine.
+///
+/// Writes go to a write-ahead log and a sorted in-memory memtable. Once the memtable
+/// reaches `max_file_size` it is flushed to a sorted table (SSTable) in level 0. When level 0
+/// holds `max_historical_files` tables they are compacted into level 1, and each deeper
+/// level is compacted into the next once it exceeds ten times the size of the previous one.
+/// Compactions run in `merge`, which [`Kving`](crate::Kving) starts in the background once a
+/// flush leaves a level over its limit. Only the memtable, block indexes and bloom filters
+/// are held in memory.
+pub struct Lsm {
+    config: Config,
+    state: RwLock<State>,
+    /// Table ids are allocated without the state lock, by flushes and compactions alike
+    next_file_id: AtomicU64,
+    /// Set when a flush leaves a level over its limit, until a merge compacts it
+    compaction_pending: AtomicBool,
+    /// Held by the compaction in progress, the levels it replaces must stay put
+    compaction: Mutex<()>,
+    /// Released last, once the log and tables are closed
+    _lock: DirLock,
+}
+
+impl Lsm {
+    /// Open LSM-tree storage engine
+    pub fn with_config(config: Config) -> crate::Result<Self> {
+        if config.encryption_key().is_some() {
+            return Err(crate::Error::InvalidData(
+                "Encryption is not supported by the LSM engine".to_string(),
+            ));
+        }
+        if config.read_only() {
+            return Err(crate::Error::InvalidData(
+                "Read-only mode is not supported by the LSM engine".to_string(),
+            ));
+        }
+        let lock = DirLock::acquire(&config)?;
+
+        let (next_file_id, level_ids) = Self::read_manifest(&config)?;
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live_ids = HashSet::new();
+        for (level, ids) in level_ids.into_iter().enumerate().take(MAX_LEVELS) {
+            for id in ids {
+                let table = SsTable::open(id, &Self::table_path(&config, id))?;
+                levels[level].push(Arc::new(table));
+                live_ids.insert(id);
+            }
+        }
+        Self::remove_orphan_tables(&config, &live_ids)?;
+
+        let wal_path = config.database_path().join(WAL_FILE_NAME);
+        let (ops, wal_len) = Wal::replay(&wal_path)?;
+        let mut state = State {
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            wal: Wal::open(&wal_path, wal_len)?,
+            levels,
+        };
+        for op in ops {
+            state.memtable_size += Self::apply_op(&mut state.memtable, op);
+        }
+
+        let lsm = Lsm {
+            state: RwLock::new(state),
+            next_file_id: AtomicU64::new(next_file_id),
+            compaction_pending: AtomicBool::new(false),
+            compaction: Mutex::new(()),
+            config,
+            _lock: lock,
+        };
+        let pending = lsm.needs_compaction(&*lsm.read_state()?);
+        lsm.compaction_pending.store(pending, Ordering::Relaxed);
+        Ok(lsm)
+    }
+
+    /// Repair a closed database.
+    ///
+    /// Tables that are missing or can't be read back are moved to a `.quarantine` file and
+    /// dropped from the manifest, and the torn tail of the write-ahead log is truncated.
+    /// A corrupted manifest can't be repaired, the order of the tables would be lost.
+    pub fn repair(config: &Config) -> crate::Result<RepairReport> {
+        if config.read_only() {
+            return Err(crate::Error::ReadOnly);
+        }
+        let _lock = DirLock::acquire(config)?;
+        let mut report = RepairReport::default();
+        let (next_file_id, mut level_ids) = Self::read_manifest(config)?;
+
+        let mut removed = false;
+        for ids in level_ids.iter_mut() {
+            let mut kept = Vec::with_capacity(ids.len());
+            for &id in ids.iter() {
+                report.files_checked += 1;
+                let path = Self::table_path(config, id);
+                if Self::check_table(id, &path).is_ok() {
+                    kept.push(id);
+                    continue;
+                }
+
+                let len = match std::fs::metadata(&path) {
+                    Ok(metadata) => {
+                        let extension = config.store_model().extension();
+                        let quarantine_path =
+                            path.with_extension(format!("{}.quarantine", extension));
+                        std::fs::rename(&path, &quarantine_path)?;
+                        report.quarantine_files.push(quarantine_path);
+                        metadata.len()
+                    }
+                    Err(e) if e.kind() == ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                report.removed_ranges.push(CorruptRange {
+                    file_id: id,
+                    offset: 0,
+                    len,
+                    kind: CorruptionKind::Checksum,
+                });
+                removed = true;
+            }
+            *ids = kept;
+        }
+        if removed {
+            Self::store_manifest(config, next_file_id, &level_ids)?;
+        }
+
+        let wal_path = config.database_path().join(WAL_FILE_NAME);
+        let (_, wal_len) = Wal::replay(&wal_path)?;
+        if let Ok(metadata) = std::fs::metadata(&wal_path) {
+            report.log_bytes_truncated = metadata.len().saturating_sub(wal_len);
+            Wal::open(&wal_path, wal_len)?;
+        }
+        Ok(report)
+    }
+
+    /// Open a table and read back all of its entries
+    fn check_table(id: u64, path: &Path) -> crate::Result<()> {
+        let table = Arc::new(SsTable::open(id, path)?);
+        for entry in table.iter() {
+            entry?;
+        }
+        Ok(())
+    }
+
+    /// Get the path of a table file
+    fn table_path(config: &Config, id: u64) -> PathBuf {
+        config
+            .database_path()
+            .join(format!("{}.{}", id, config.store_model().extension()))
+    }
+
+    /// Delete table files that aren't referenced by the manifest, they are leftovers
+    /// of a flush or compaction interrupted before the manifest was updated
+    fn remove_orphan_tables(config: &Config, live_ids: &HashSet<u64>) -> crate::Result<()> {
+        let extension = config.store_model().extension();
+        for entry in std::fs::read_dir(config.database_path())? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != extension.as_str()) {
+                continue;
+            }
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+                && let Ok(id) = stem.parse::<u64>()
+                && !live_ids.contains(&id)
+            {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the manifest: `crc(4) + next_file_id(8) + level_count(4)`, then per level
+    /// `table_count(4) + table_id(8)...`
+    fn read_manifest(config: &Config) -> crate::Result<(u64, Vec<Vec<u64>>)> {
+        let path = config.database_path().join(MANIFEST_FILE_NAME);
+        let mut buf = Vec::new();
+        match OpenOptions::new().read(true).open(&path) {
+            Ok(mut file) => file.read_to_end(&mut buf)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((0, Vec::new())),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut reader = buf.as_slice();
+        let stored_crc = reader.read_u32::<BE>()?;
+        let mut hasher = Hasher::new();
+        hasher.update(reader);
+        if hasher.finalize() != stored_crc {
+            metrics::crc_failure("lsm");
+            return Err(crate::Error::CorruptedData);
+        }
+
+        let next_file_id = reader.read_u64::<BE>()?;
+        let level_count = reader.read_u32::<BE>()?;
+        let mut levels = Vec::with_capacity(level_count as usize);
+        for _ in 0..level_count {
+            let count = reader.read_u32::<BE>()?;
+            let mut ids = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                ids.push(reader.read_u64::<BE>()?);
+            }
+            levels.push(ids);
+        }
+        Ok((next_file_id, levels))
+    }
+
+    /// Atomically replace the manifest with the current levels
+    fn write_manifest(&self, state: &State) -> crate::Result<()> {
+        let level_ids: Vec<Vec<u64>> = state
+            .levels
+            .iter()
+            .map(|level| level.iter().map(|table| table.id()).collect())
+            .collect();
+        Self::store_manifest(
+            &self.config,
+            self.next_file_id.load(Ordering::Relaxed),
+            &level_ids,
+        )
+    }
+
+    /// Atomically replace the manifest with the given table ids of every level
+    fn store_manifest(
+        config: &Config,
+        next_file_id: u64,
+        level_ids: &[Vec<u64>],
+    ) -> crate::Result<()> {
+        let mut payload = Vec::new();
+        payload.write_u64::<BE>(next_file_id)?;
+        payload.write_u32::<BE>(level_ids.len() as u32)?;
+        for ids in level_ids {
+            payload.write_u32::<BE>(ids.len() as u32)?;
+            for id in ids {
+                payload.write_u64::<BE>(*id)?;
+            }
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+
+        let path = config.database_path().join(MANIFEST_FILE_NAME);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        file.write_u32::<BE>(hasher.finalize())?;
+        file.write_all(&payload)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Apply an operation to the memtable, returning the bytes it added
+    fn apply_op(memtable: &mut BTreeMap<Vec<u8>, Option<Vec<u8>>>, op: BatchOp) -> u64 {
+        match op {
+            BatchOp::Put(key, value) => {
+                let size = (key.len() + value.len()) as u64 + MEMTABLE_ENTRY_OVERHEAD;
+                memtable.insert(key, Some(value));
+                size
+            }
+            BatchOp::Delete(key) => {
+                let size = key.len() as u64 + MEMTABLE_ENTRY_OVERHEAD;
+                memtable.insert(key, None);
+                size
+            }
+        }
+    }
+
+    fn read_state(&self) -> crate::Result<RwLockReadGuard<'_, State>> {
+        self.state
+            .read()
+            .map_err(|_| crate::Error::PoisonError("Failed to read lsm state".to_string()))
+    }
+
+    fn write_state(&self) -> crate::Result<RwLockWriteGuard<'_, State>> {
+        self.state
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write lsm state".to_string()))
+    }
+
+    /// Log the operations, apply them to the memtable and flush it if it is full
+    fn write_ops(&self, ops: Vec<BatchOp>) -> crate::Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.write_state()?;
+        state.wal.append(&ops)?;
+        for op in ops {
+            state.memtable_size += Self::apply_op(&mut state.memtable, op);
+        }
+
+        if state.memtable_size >= self.config.max_file_size() {
+            self.flush_memtable(&mut state)?;
+            if self.needs_compaction(&state) {
+                self.compaction_pending.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the memtable to a new level 0 table and reset the log
+    fn flush_memtable(&self, state: &mut State) -> crate::Result<()> {
+        if state.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        let mut writer = SsTableWriter::create(
+            id,
+            &Self::table_path(&self.config, id),
+            state.memtable.len(),
+        )?;
+        for (key, value) in &state.memtable {
+            writer.add(key, value.as_deref())?;
+        }
+        state.levels[0].push(Arc::new(writer.finish()?));
+        self.write_manifest(state)?;
+
+        state.wal.reset()?;
+        state.memtable.clear();
+        state.memtable_size = 0;
+        Ok(())
+    }
+
+    /// Estimate the number of live keys from the entry counts of the tables and the live
+    /// entries of the memtable, without reading any table. Overwritten versions and
+    /// deletions are counted until a compaction merges them with the keys they replace.
+    fn estimate_key_count(state: &State) -> u64 {
+        let table_entries: u64 = state.levels.iter().flatten().map(|t| t.entry_count()).sum();
+        let memtable_live = state.memtable.values().filter(|v| v.is_some()).count() as u64;
+        table_entries + memtable_live
+    }
+
+    /// Look up a key in the tables, newest data first
+    fn get_from_tables(state: &State, key: &[u8]) -> crate::Result<Option<Option<Vec<u8>>>> {
+        for table in state.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        for level in &state.levels[1..] {
+            let idx = level.partition_point(|t| t.last_key() < key);
+            if let Some(table) = level.get(idx)
+                && let Some(value) = table.get(key)?
+            {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replay the tables from oldest to newest, recording whether each key is live
+    fn replay_tables(
+        levels: &[Vec<Arc<SsTable>>],
+        keys: &mut BTreeMap<Vec<u8>, bool>,
+    ) -> crate::Result<()> {
+        let tables = levels[1..].iter().rev().flatten().chain(levels[0].iter());
+        for table in tables {
+            for entry in table.iter() {
+                let (key, value) = entry?;
+                keys.insert(key, value.is_some());
+            }
+        }
+        Ok(())
+    }
+
+    /// Maximum total size of a level before it is compacted into the next one
+    fn max_level_size(&self, level: usize) -> u64 {
+        let base = self.config.max_file_size() * LEVEL_SIZE_MULTIPLIER;
+        base.saturating_mul(LEVEL_SIZE_MULTIPLIER.saturating_pow(level as u32 - 1))
+    }
+
+    /// Check if a level exceeds its limit
+    fn needs_compaction(&self, state: &State) -> bool {
+        self.plan_compaction(state).is_some()
+    }
+
+    /// Pick the tables of the most urgent level to compact into the next one
+    fn plan_compaction(&self, state: &State) -> Option<Compaction> {
+        let (level, inputs) =
+            if state.levels[0].len() >= (self.config.max_historical_files() as usize).max(1) {
+                (0, state.levels[0].clone())
+            } else {
+                let level = (1..MAX_LEVELS - 1).find(|&level| {
+                    let size: u64 = state.levels[level].iter().map(|t| t.file_size()).sum();
+                    size > self.max_level_size(level)
+                })?;
+                // Push the first table of the level down
+                (level, vec![Arc::clone(&state.levels[level][0])])
+            };
+
+        let first = inputs.iter().map(|t| t.first_key()).min().unwrap_or(&[]);
+        let last = inputs.iter().map(|t| t.last_key()).max().unwrap_or(&[]);
+        let overlapping = state.levels[level + 1]
+            .iter()
+            .filter(|t| t.overlaps(first, last))
+            .cloned()
+            .collect();
+
+        Some(Compaction {
+            level,
+            inputs,
+            overlapping,
+            // Deletions can be dropped once no deeper level may still hold the key. Only
+            // compactions fill deeper levels, and they don't run concurrently.
+            drop_tombstones: state.levels[level + 2..].iter().all(|l| l.is_empty()),
+        })
+    }
+
+    /// Compact the most urgent level into the next one. Returns false if no level is
+    /// over its limit.
+    fn compact_once(&self) -> crate::Result<bool> {
+        let Some(compaction) = self.plan_compaction(&*self.read_state()?) else {
+            return Ok(false);
+        };
+        self.compact(compaction)?;
+        Ok(true)
+    }
+
+    /// Merge the tables of a compaction with the state lock released, it is only taken
+    /// to install the outputs
+    fn compact(&self, compaction: Compaction) -> crate::Result<()> {
+        let Compaction {
+            level,
+            inputs,
+            overlapping,
+            drop_tombstones,
+        } = compaction;
+
+        // Newest first: level 0 tables from newest to oldest, then the older level
+        let mut sources = Vec::new();
+        for table in inputs.iter().rev() {
+            sources.push(MergeSource::new(Box::new(table.iter()))?);
+        }
+        let next_level = overlapping.clone();
+        sources.push(MergeSource::new(Box::new(
+            next_level.into_iter().flat_map(|t| t.iter()),
+        ))?);
+
+        let expected_keys = inputs
+            .iter()
+            .chain(overlapping.iter())
+            .map(|t| t.entry_count() as usize)
+            .sum();
+        let outputs = self.write_merged(sources, drop_tombstones, expected_keys)?;
+
+        // Install the outputs and remove the inputs
+        let removed: HashSet<u64> = inputs
+            .iter()
+            .chain(overlapping.iter())
+            .map(|t| t.id())
+            .collect();
+        {
+            let mut state = self.write_state()?;
+            // A clear dropped the inputs meanwhile, the outputs hold stale data
+            let present = state.levels[level]
+                .iter()
+                .chain(state.levels[level + 1].iter())
+                .filter(|t| removed.contains(&t.id()))
+                .count();
+            if present != removed.len() {
+                drop(state);
+                for table in outputs {
+                    std::fs::remove_file(Self::table_path(&self.config, table.id()))?;
+                }
+                return Ok(());
+            }
+
+            state.levels[level].retain(|t| !removed.contains(&t.id()));
+            state.levels[level + 1].retain(|t| !removed.contains(&t.id()));
+            state.levels[level + 1].extend(outputs);
+            state.levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            self.write_manifest(&state)?;
+        }
+
+        drop(inputs);
+        drop(overlapping);
+        for id in removed {
+            std::fs::remove_file(Self::table_path(&self.config, id))?;
+        }
+        Ok(())
+    }
+
+    /// Merge the sources into new tables split at `max_file_size`
+    fn write_merged(
+        &self,
+        mut sources: Vec<MergeSource>,
+        drop_tombstones: bool,
+        expected_keys: usize,
+    ) -> crate::Result<Vec<Arc<SsTable>>> {
+        let mut outputs = Vec::new();
+        let mut writer: Option<SsTableWriter> = None;
+
+        loop {
+            // The smallest key among all sources, the newest source wins on ties
+            let mut newest: Option<usize> = None;
+            for (idx, source) in sources.iter().enumerate() {
+                if let Some((key, _)) = &source.head {
+                    match newest {
+                        Some(n) if sources[n].head.as_ref().unwrap().0 <= *key => {}
+                        _ => newest = Some(idx),
+                    }
+                }
+            }
+            let Some(newest) = newest else {
+                break;
+            };
+
+            let (key, value) = sources[newest].head.take().unwrap();
+            sources[newest].advance()?;
+            // Skip older versions of the key
+            for source in sources.iter_mut() {
+                while source.head.as_ref().is_some_and(|(k, _)| *k == key) {
+                    source.advance()?;
+                }
+            }
+
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+
+            if writer.is_none() {
+                let id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+                writer = Some(SsTableWriter::create(
+                    id,
+                    &Self::table_path(&self.config, id),
+                    expected_keys,
+                )?);
+            }
+            let w = writer.as_mut().unwrap();
+            w.add(&key, value.as_deref())?;
+            if w.size() >= self.config.max_file_size() {
+                outputs.push(Arc::new(writer.take().unwrap().finish()?));
+            }
+        }
+
+        if let Some(writer) = writer {
+            outputs.push(Arc::new(writer.finish()?));
+        }
+        Ok(outputs)
+    }
+}
+
+impl KvStore for Lsm {
+    fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        let state = self.read_state()?;
+        if let Some(value) = state.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        Ok(Self::get_from_tables(&state, key)?.flatten())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        self.write_ops(vec![BatchOp::Put(key.to_vec(), value.to_vec())])
+    }
+
+    fn delete(&self, key: &[u8]) -> crate::Result<()> {
+        self.write_ops(vec![BatchOp::Delete(key.to_vec())])
+    }
+
+    fn contains(&self, key: &[u8]) -> crate::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        self.write_ops(ops.to_vec())
+    }
+
+    fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
+        let state = self.read_state()?;
+        let mut keys = BTreeMap::new();
+        Self::replay_tables(&state.levels, &mut keys)?;
+        for (key, value) in &state.memtable {
+            keys.insert(key.clone(), value.is_some());
+        }
+        Ok(keys
+            .into_iter()
+            .filter_map(|(key, live)| live.then_some(key))
+            .collect())
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        let mut state = self.write_state()?;
+        let ids: Vec<u64> = state.levels.iter().flatten().map(|t| t.id()).collect();
+        state.levels.iter_mut().for_each(|level| level.clear());
+        self.write_manifest(&state)?;
+        for id in ids {
+            std::fs::remove_file(Self::table_path(&self.config, id))?;
+        }
+        state.wal.reset()?;
+        state.memtable.clear();
+        state.memtable_size = 0;
+        Ok(())
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        self.write_state()?.wal.sync()
+    }
+
+    fn can_merge(&self) -> crate::Result<bool> {
+        Ok(self.needs_compaction(&*self.read_state()?))
+    }
+
+    fn merge_pending(&self) -> bool {
+        self.compaction_pending.load(Ordering::Relaxed)
+    }
+
+    /// Compacts until no level is over its budget. Cancellation takes effect between two
+    /// compactions, each of which replaces its tables atomically. Reads and writes go on
+    /// while tables are merged.
+    fn merge(&self, control: &MergeControl) -> crate::Result<()> {
+        let _compaction = self
+            .compaction
+            .lock()
+            .map_err(|_| crate::Error::PoisonError("Failed to lock compaction".to_string()))?;
+        loop {
+            if control.is_cancelled() {
+                return Err(crate::Error::MergeCancelled);
+            }
+            // Cleared first, a flush racing with the last check sets it again
+            self.compaction_pending.store(false, Ordering::Relaxed);
+            if !self.compact_once()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads every table. Tables stay readable while verified, even if a compaction
+    /// replaces them meanwhile.
+    fn verify(
+        &self,
+        max_bytes_per_sec: Option<u64>,
+        stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut throttle = Throttle::new(max_bytes_per_sec, stop);
+        let tables: Vec<Arc<SsTable>> = self.read_state()?.levels.concat();
+
+        for table in tables {
+            report.files_checked += 1;
+            for entry in table.iter() {
+                match entry {
+                    Ok((key, value)) => {
+                        report.records_checked += 1;
+                        let size = key.len() + value.map_or(0, |v| v.len());
+                        if !throttle.consume(size as u64) {
+                            report.interrupted = true;
+                            return Ok(report);
+                        }
+                    }
+                    Err(_) => report.add_corrupt_range(CorruptRange {
+                        file_id: table.id(),
+                        offset: 0,
+                        len: table.file_size(),
+                        kind: CorruptionKind::Checksum,
+                    }),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn catch_up(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> crate::Result<Stats> {
+        Ok(Stats {
+            key_count: Self::estimate_key_count(&*self.read_state()?),
+            ..Stats::default()
+        })
+    }
+
+    fn close(&self) -> crate::Result<()> {
+        self.sync()
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            diagnostics::report(
+                self.config.error_handler(),
+                ErrorEvent::new(ErrorSource::Close, &e),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Kving;
+    use crate::kving::config::StoreModel;
+    use std::time::{Duration, Instant};
+
+    fn config(dir: &Path) -> Config {
+        Config::builder()
+            .set_data_dir(dir.to_path_buf())
+            .set_store_model(StoreModel::Lsm)
+            .set_max_file_size(1024)
+            .set_max_historical_files(2)
+            .build()
+    }
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key-{:04}", i).into_bytes()
+    }
+
+    #[test]
+    fn round_trips_across_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let lsm = Lsm::with_config(config(dir.path())).unwrap();
+            for i in 0..100 {
+                lsm.put(&key(i), b"value").unwrap();
+            }
+            lsm.put(&key(0), b"updated").unwrap();
+            lsm.delete(&key(1)).unwrap();
+            lsm.delete(b"missing").unwrap();
+            lsm.write_batch(&[
+                BatchOp::Put(b"batch".to_vec(), b"v".to_vec()),
+                BatchOp::Delete(b"batch".to_vec()),
+                BatchOp::Put(b"batch".to_vec(), b"w".to_vec()),
+            ])
+            .unwrap();
+            assert_eq!(lsm.list_keys().unwrap().len(), 100);
+        }
+
+        // Part of the data is in tables, the rest is replayed from the log
+        let lsm = Lsm::with_config(config(dir.path())).unwrap();
+        assert!(!lsm.read_state().unwrap().levels[0].is_empty());
+        assert_eq!(lsm.get(&key(0)).unwrap().as_deref(), Some(&b"updated"[..]));
+        assert_eq!(lsm.get(&key(1)).unwrap(), None);
+        assert_eq!(lsm.get(&key(99)).unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(lsm.get(b"batch").unwrap().as_deref(), Some(&b"w"[..]));
+        assert_eq!(lsm.list_keys().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn compacts_only_in_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm = Lsm::with_config(config(dir.path())).unwrap();
+        for i in 0..200 {
+            lsm.put(&key(i), b"value").unwrap();
+        }
+        for i in 0..100 {
+            lsm.delete(&key(i)).unwrap();
+        }
+        assert!(lsm.merge_pending());
+        assert!(lsm.read_state().unwrap().levels[0].len() > 2);
+
+        lsm.merge(&MergeControl::new(0)).unwrap();
+        assert!(!lsm.merge_pending());
+        assert!(!lsm.can_merge().unwrap());
+        assert_eq!(lsm.list_keys().unwrap().len(), 100);
+        assert_eq!(lsm.get(&key(50)).unwrap(), None);
+        assert_eq!(lsm.get(&key(150)).unwrap().as_deref(), Some(&b"value"[..]));
+        drop(lsm);
+
+        let lsm = Lsm::with_config(config(dir.path())).unwrap();
+        assert_eq!(lsm.list_keys().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn discards_a_compaction_overtaken_by_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm = Lsm::with_config(config(dir.path())).unwrap();
+        for i in 0..200 {
+            lsm.put(&key(i), b"value").unwrap();
+        }
+        let compaction = lsm.plan_compaction(&lsm.read_state().unwrap()).unwrap();
+        lsm.clear().unwrap();
+        lsm.compact(compaction).unwrap();
+
+        assert_eq!(lsm.stats().unwrap().key_count, 0);
+        assert!(lsm.list_keys().unwrap().is_empty());
+        let config = config(dir.path());
+        let extension = config.store_model().extension();
+        let tables = std::fs::read_dir(config.database_path())
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension()
+                    .is_some_and(|ext| ext == extension.as_str())
+            })
+            .count();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn kving_compacts_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let kving = Kving::with_config(config(dir.path())).unwrap();
+        for i in 0..200 {
+            kving.put(&key(i), b"value").unwrap();
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while kving.can_merge().unwrap() {
+            assert!(Instant::now() < deadline, "no background compaction");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(kving.stats().unwrap().key_count, 200);
+        assert_eq!(kving.get(&key(7)).unwrap().as_deref(), Some(&b"value"[..]));
+        kving.close().unwrap();
+    }
+
+    #[test]
+    fn estimates_the_key_count_until_compactions_merge_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let lsm = Lsm::with_config(config(dir.path())).unwrap();
+        for i in 0..100 {
+            lsm.put(&key(i), b"value").unwrap();
+        }
+        assert_eq!(lsm.stats().unwrap().key_count, 100);
+        // Overwritten versions are counted until they are merged
+        for i in 0..100 {
+            lsm.put(&key(i), b"updated").unwrap();
+        }
+        lsm.flush_memtable(&mut lsm.write_state().unwrap()).unwrap();
+        assert_eq!(lsm.stats().unwrap().key_count, 200);
+
+        let inputs = lsm.read_state().unwrap().levels[0].clone();
+        lsm.compact(Compaction {
+            level: 0,
+            inputs,
+            overlapping: Vec::new(),
+            drop_tombstones: true,
+        })
+        .unwrap();
+        assert_eq!(lsm.stats().unwrap().key_count, 100);
+    }
+
+    #[test]
+    fn overwrites_keys_of_corrupted_blocks_without_reading_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        {
+            let lsm = Lsm::with_config(config.clone()).unwrap();
+            for i in 0..100 {
+                lsm.put(&key(i), b"value").unwrap();
+            }
+        }
+        let (_, level_ids) = Lsm::read_manifest(&config).unwrap();
+        let path = Lsm::table_path(&config, level_ids[0][0]);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[8] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        let lsm = Lsm::with_config(config).unwrap();
+        assert!(lsm.get(&key(0)).is_err());
+        lsm.put(&key(0), b"updated").unwrap();
+        lsm.delete(&key(1)).unwrap();
+        assert_eq!(lsm.get(&key(0)).unwrap().as_deref(), Some(&b"updated"[..]));
+        assert_eq!(lsm.get(&key(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        {
+            let lsm = Lsm::with_config(config.clone()).unwrap();
+            for i in 0..100 {
+                lsm.put(&key(i), b"value").unwrap();
+            }
+        }
+        let path = config.database_path().join(MANIFEST_FILE_NAME);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(matches!(
+            Lsm::with_config(config),
+            Err(crate::Error::CorruptedData)
+        ));
+    }
+}
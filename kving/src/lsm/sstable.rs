@@ -0,0 +1,429 @@
+use crate::kving::metrics;
+use crate::lsm::bloom::BloomFilter;
+use crate::lsm::wal::TOMBSTONE_LEN;
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A key with its value, `None` marks a deletion
+pub type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Location of a data block in a SSTable
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+    crc: u32,
+}
+
+/// Builds a SSTable from entries added in ascending key order.
+///
+/// File layout: `data blocks | index block | bloom filter | footer`. Each data block is
+/// a sequence of `key_len(4) + value_len(4) + key + value` entries. The index block holds
+/// the first key of the table, then `last_key_len(4) + last_key + offset(8) + size(4) + crc(4)`
+/// per data block. The footer is `index_offset(8) + index_len(8) + bloom_offset(8) +
+/// bloom_len(8) + entry_count(8) + magic(8)`.
+pub struct SsTableWriter {
+    id: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    offset: u64,
+    block: Vec<u8>,
+    block_last_key: Vec<u8>,
+    first_key: Option<Vec<u8>>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    entry_count: u64,
+}
+
+impl SsTableWriter {
+    /// Target size of a data block
+    const BLOCK_SIZE: usize = 4 * 1024;
+
+    /// Create a new table file sized for about `expected_keys` keys
+    pub fn create(id: u64, path: &Path, expected_keys: usize) -> crate::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            id,
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            offset: 0,
+            block: Vec::with_capacity(Self::BLOCK_SIZE),
+            block_last_key: Vec::new(),
+            first_key: None,
+            index: Vec::new(),
+            bloom: BloomFilter::with_capacity(expected_keys),
+            entry_count: 0,
+        })
+    }
+
+    /// Get the number of bytes written so far
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Add an entry, keys must be added in ascending order
+    pub fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> crate::Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        self.block.write_u32::<BE>(key.len() as u32)?;
+        match value {
+            Some(value) => {
+                self.block.write_u32::<BE>(value.len() as u32)?;
+                self.block.write_all(key)?;
+                self.block.write_all(value)?;
+            }
+            None => {
+                self.block.write_u32::<BE>(TOMBSTONE_LEN)?;
+                self.block.write_all(key)?;
+            }
+        }
+        self.block_last_key.clear();
+        self.block_last_key.extend_from_slice(key);
+        self.bloom.insert(key);
+        self.entry_count += 1;
+
+        if self.block.len() >= Self::BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending data block
+    fn finish_block(&mut self) -> crate::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&self.block);
+        self.file.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: std::mem::take(&mut self.block_last_key),
+            offset: self.offset,
+            size: self.block.len() as u32,
+            crc: hasher.finalize(),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write index, bloom filter and footer, sync the file and open it for reading
+    pub fn finish(mut self) -> crate::Result<SsTable> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        let first_key = self.first_key.take().unwrap_or_default();
+        index.write_u32::<BE>(first_key.len() as u32)?;
+        index.write_all(&first_key)?;
+        for handle in &self.index {
+            index.write_u32::<BE>(handle.last_key.len() as u32)?;
+            index.write_all(&handle.last_key)?;
+            index.write_u64::<BE>(handle.offset)?;
+            index.write_u32::<BE>(handle.size)?;
+            index.write_u32::<BE>(handle.crc)?;
+        }
+        let bloom = self.bloom.encode()?;
+
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.file.write_all(&index)?;
+        self.file.write_all(&bloom)?;
+        self.file.write_u64::<BE>(index_offset)?;
+        self.file.write_u64::<BE>(index.len() as u64)?;
+        self.file.write_u64::<BE>(bloom_offset)?;
+        self.file.write_u64::<BE>(bloom.len() as u64)?;
+        self.file.write_u64::<BE>(self.entry_count)?;
+        self.file.write_u64::<BE>(SsTable::MAGIC)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        SsTable::open(self.id, &self.path)
+    }
+}
+
+/// An immutable, sorted table of entries on disk
+pub struct SsTable {
+    id: u64,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    bloom: BloomFilter,
+    first_key: Vec<u8>,
+    entry_count: u64,
+    file_size: u64,
+}
+
+impl SsTable {
+    /// Magic number ending every table
+    const MAGIC: u64 = 0x6B76_696E_6773_7374;
+
+    /// Footer size: `index_offset(8) + index_len(8) + bloom_offset(8) + bloom_len(8) + entry_count(8) + magic(8)`
+    const FOOTER_SIZE: u64 = 6 * 8;
+
+    /// Open an existing table and load its index and bloom filter
+    pub fn open(id: u64, path: &Path) -> crate::Result<Self> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let file_size = file.metadata()?.len();
+        if file_size < Self::FOOTER_SIZE {
+            return Err(crate::Error::CorruptedData);
+        }
+
+        file.seek(SeekFrom::Start(file_size - Self::FOOTER_SIZE))?;
+        let index_offset = file.read_u64::<BE>()?;
+        let index_len = file.read_u64::<BE>()?;
+        let bloom_offset = file.read_u64::<BE>()?;
+        let bloom_len = file.read_u64::<BE>()?;
+        let entry_count = file.read_u64::<BE>()?;
+        // The offsets come from disk, a corrupted footer must not overflow
+        let table_end = bloom_offset
+            .checked_add(bloom_len)
+            .and_then(|end| end.checked_add(Self::FOOTER_SIZE));
+        let index_end = index_offset.checked_add(index_len);
+        if file.read_u64::<BE>()? != Self::MAGIC
+            || table_end != Some(file_size)
+            || index_end != Some(bloom_offset)
+        {
+            return Err(crate::Error::CorruptedData);
+        }
+
+        let mut index_buf = vec![0; index_len as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut index_buf)?;
+        let mut bloom_buf = vec![0; bloom_len as usize];
+        file.read_exact(&mut bloom_buf)?;
+
+        let mut reader = index_buf.as_slice();
+        let first_key = Self::read_key(&mut reader)?;
+        let mut index = Vec::new();
+        while !reader.is_empty() {
+            index.push(BlockHandle {
+                last_key: Self::read_key(&mut reader)?,
+                offset: reader.read_u64::<BE>()?,
+                size: reader.read_u32::<BE>()?,
+                crc: reader.read_u32::<BE>()?,
+            });
+        }
+
+        Ok(Self {
+            id,
+            file: Mutex::new(file),
+            index,
+            bloom: BloomFilter::decode(&bloom_buf)?,
+            first_key,
+            entry_count,
+            file_size,
+        })
+    }
+
+    /// Read a length-prefixed key
+    fn read_key(reader: &mut &[u8]) -> crate::Result<Vec<u8>> {
+        let len = reader.read_u32::<BE>()? as usize;
+        if len > reader.len() {
+            return Err(crate::Error::CorruptedData);
+        }
+        let mut key = vec![0; len];
+        reader.read_exact(&mut key)?;
+        Ok(key)
+    }
+
+    /// Get the table file id
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the table file size in bytes
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Get the number of entries, including deletions
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count
+    }
+
+    /// Get the smallest key of the table
+    pub fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    /// Get the largest key of the table
+    pub fn last_key(&self) -> &[u8] {
+        self.index.last().map_or(&[], |h| h.last_key.as_slice())
+    }
+
+    /// Check if the key range of the table intersects `[first, last]`
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        !self.index.is_empty() && self.first_key() <= last && self.last_key() >= first
+    }
+
+    /// Look up a key. Returns `None` if the table doesn't hold the key,
+    /// `Some(None)` if it holds a deletion.
+    pub fn get(&self, key: &[u8]) -> crate::Result<Option<Option<Vec<u8>>>> {
+        if key < self.first_key() || key > self.last_key() || !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // The first block whose last key is not smaller than the key
+        let block_idx = self.index.partition_point(|h| h.last_key.as_slice() < key);
+        if block_idx >= self.index.len() {
+            return Ok(None);
+        }
+        let entries = self.read_block(block_idx)?;
+        Ok(entries.into_iter().find(|(k, _)| k == key).map(|(_, v)| v))
+    }
+
+    /// Read and decode a data block
+    fn read_block(&self, block_idx: usize) -> crate::Result<VecDeque<Entry>> {
+        let handle = &self.index[block_idx];
+        let mut buf = vec![0; handle.size as usize];
+        {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|_| crate::Error::PoisonError("Failed to lock sstable".to_string()))?;
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&buf);
+        if hasher.finalize() != handle.crc {
+            metrics::crc_failure("lsm");
+            return Err(crate::Error::CorruptedData);
+        }
+
+        let mut entries = VecDeque::new();
+        let mut reader = buf.as_slice();
+        while !reader.is_empty() {
+            let key_len = reader.read_u32::<BE>()? as usize;
+            let value_len = reader.read_u32::<BE>()?;
+            let mut key = vec![0; key_len];
+            reader.read_exact(&mut key)?;
+            let value = if value_len == TOMBSTONE_LEN {
+                None
+            } else {
+                let mut value = vec![0; value_len as usize];
+                reader.read_exact(&mut value)?;
+                Some(value)
+            };
+            entries.push_back((key, value));
+        }
+        Ok(entries)
+    }
+
+    /// Iterate over all entries in key order
+    pub fn iter(self: &Arc<Self>) -> SsTableIter {
+        SsTableIter {
+            table: Arc::clone(self),
+            next_block: 0,
+            entries: VecDeque::new(),
+        }
+    }
+}
+
+/// Iterator over the entries of a SSTable, reading one block at a time
+pub struct SsTableIter {
+    table: Arc<SsTable>,
+    next_block: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl Iterator for SsTableIter {
+    type Item = crate::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() {
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries,
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_table(path: &Path, entries: &[(&[u8], Option<&[u8]>)]) -> SsTable {
+        let mut writer = SsTableWriter::create(1, path, entries.len()).unwrap();
+        for (key, value) in entries {
+            writer.add(key, *value).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trips_entries_and_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        write_table(
+            &path,
+            &[(b"a", Some(b"1")), (b"b", None), (b"c", Some(b""))],
+        );
+
+        let table = Arc::new(SsTable::open(1, &path).unwrap());
+        assert_eq!(table.entry_count(), 3);
+        assert_eq!(table.first_key(), b"a");
+        assert_eq!(table.last_key(), b"c");
+        assert_eq!(table.get(b"a").unwrap(), Some(Some(b"1".to_vec())));
+        assert_eq!(table.get(b"b").unwrap(), Some(None));
+        assert_eq!(table.get(b"c").unwrap(), Some(Some(Vec::new())));
+        assert_eq!(table.get(b"d").unwrap(), None);
+        let keys: Vec<Vec<u8>> = table.iter().map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_overflowing_footer_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        write_table(&path, &[(b"a", Some(b"1"))]);
+
+        // Point the bloom filter so far that its end wraps around to the file size
+        let mut bytes = std::fs::read(&path).unwrap();
+        let footer = bytes.len() - SsTable::FOOTER_SIZE as usize;
+        let wrapped = (bytes.len() as u64).wrapping_sub(SsTable::FOOTER_SIZE);
+        let bloom_len = u64::MAX - 7;
+        let bloom_offset = wrapped.wrapping_sub(bloom_len);
+        bytes[footer + 16..footer + 24].copy_from_slice(&bloom_offset.to_be_bytes());
+        bytes[footer + 24..footer + 32].copy_from_slice(&bloom_len.to_be_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            SsTable::open(1, &path),
+            Err(crate::Error::CorruptedData)
+        ));
+    }
+
+    #[test]
+    fn detects_corrupted_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.sst");
+        write_table(&path, &[(b"a", Some(b"1")), (b"b", Some(b"2"))]);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[8] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let table = Arc::new(SsTable::open(1, &path).unwrap());
+        assert!(matches!(table.get(b"a"), Err(crate::Error::CorruptedData)));
+        assert!(table.iter().any(|entry| entry.is_err()));
+    }
+}
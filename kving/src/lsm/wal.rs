@@ -0,0 +1,135 @@
+use crate::kving::kv_store::BatchOp;
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Value length written for a deletion
+pub const TOMBSTONE_LEN: u32 = u32::MAX;
+
+/// Write-ahead log protecting the memtable.
+///
+/// Each entry holds a whole batch: `crc(4) + payload_len(4) + payload`, where the
+/// payload is `count(4)` followed by `key_len(4) + value_len(4) + key + value` per
+/// operation. A torn or corrupted entry ends the replay, so batches are atomic.
+pub struct Wal {
+    file: BufWriter<File>,
+}
+
+impl Wal {
+    /// Open the log for appending, creating it if missing. The log is truncated to
+    /// `valid_len`, the length returned by [`Wal::replay`], so a torn tail doesn't hide
+    /// the entries appended after it.
+    pub fn open(path: &Path, valid_len: u64) -> crate::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() > valid_len {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Replay all complete entries of the log at `path`, returning their operations
+    /// and the length of the log they span
+    pub fn replay(path: &Path) -> crate::Result<(Vec<BatchOp>, u64)> {
+        let mut ops = Vec::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok((ops, 0)),
+            Err(e) => return Err(e.into()),
+        };
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+
+        while let (Ok(stored_crc), Ok(payload_len)) =
+            (reader.read_u32::<BE>(), reader.read_u32::<BE>())
+        {
+            let payload_len = payload_len as u64;
+            // A length running past the end of the file is a torn tail
+            if offset + 8 + payload_len > file_len {
+                break;
+            }
+            let mut payload = vec![0; payload_len as usize];
+            if reader.read_exact(&mut payload).is_err() {
+                break;
+            }
+            let mut hasher = Hasher::new();
+            hasher.update(&payload);
+            if hasher.finalize() != stored_crc {
+                break;
+            }
+            ops.extend(Self::decode_payload(&payload)?);
+            offset += 8 + payload_len;
+        }
+        Ok((ops, offset))
+    }
+
+    /// Append a batch to the log
+    pub fn append(&mut self, ops: &[BatchOp]) -> crate::Result<()> {
+        let mut payload = Vec::new();
+        payload.write_u32::<BE>(ops.len() as u32)?;
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    payload.write_u32::<BE>(key.len() as u32)?;
+                    payload.write_u32::<BE>(value.len() as u32)?;
+                    payload.write_all(key)?;
+                    payload.write_all(value)?;
+                }
+                BatchOp::Delete(key) => {
+                    payload.write_u32::<BE>(key.len() as u32)?;
+                    payload.write_u32::<BE>(TOMBSTONE_LEN)?;
+                    payload.write_all(key)?;
+                }
+            }
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        self.file.write_u32::<BE>(hasher.finalize())?;
+        self.file.write_u32::<BE>(payload.len() as u32)?;
+        self.file.write_all(&payload)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Flush and fsync the log
+    pub fn sync(&mut self) -> crate::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Discard all entries once the memtable has been flushed to a SSTable
+    pub fn reset(&mut self) -> crate::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(0)?;
+        // The file is opened in append mode, so following writes start over at offset 0
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Decode the operations of one entry payload
+    fn decode_payload(mut payload: &[u8]) -> crate::Result<Vec<BatchOp>> {
+        let count = payload.read_u32::<BE>()?;
+        let mut ops = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key_len = payload.read_u32::<BE>()? as usize;
+            let value_len = payload.read_u32::<BE>()?;
+            let mut key = vec![0; key_len];
+            payload.read_exact(&mut key)?;
+            if value_len == TOMBSTONE_LEN {
+                ops.push(BatchOp::Delete(key));
+            } else {
+                let mut value = vec![0; value_len as usize];
+                payload.read_exact(&mut value)?;
+                ops.push(BatchOp::Put(key, value));
+            }
+        }
+        Ok(ops)
+    }
+}
@@ -0,0 +1,164 @@
+use crate::kving::config::Config;
+use crate::kving::kv_store::{BatchOp, KvStore};
+use crate::kving::merge::MergeControl;
+use crate::kving::stats::Stats;
+use crate::kving::verify::VerifyReport;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+
+/// A pure in-memory storage engine.
+///
+/// Nothing is written to disk, so all data is lost when the store is dropped.
+/// Useful for tests and ephemeral caches.
+pub struct Memory {
+    data: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Memory {
+    /// Open in-memory storage engine
+    pub fn with_config(config: Config) -> crate::Result<Self> {
+        if config.read_only() {
+            return Err(crate::Error::InvalidData(
+                "Read-only mode is not supported by the memory engine".to_string(),
+            ));
+        }
+        Ok(Memory {
+            data: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Read-lock the data map
+    fn read(&self) -> crate::Result<std::sync::RwLockReadGuard<'_, HashMap<Vec<u8>, Vec<u8>>>> {
+        self.data
+            .read()
+            .map_err(|_| crate::Error::PoisonError("Failed to read memory data".to_string()))
+    }
+
+    /// Write-lock the data map
+    fn write(&self) -> crate::Result<std::sync::RwLockWriteGuard<'_, HashMap<Vec<u8>, Vec<u8>>>> {
+        self.data
+            .write()
+            .map_err(|_| crate::Error::PoisonError("Failed to write memory data".to_string()))
+    }
+}
+
+impl KvStore for Memory {
+    fn get(&self, key: &[u8]) -> crate::Result<Option<Vec<u8>>> {
+        Ok(self.read()?.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> crate::Result<()> {
+        self.write()?.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> crate::Result<()> {
+        self.write()?.remove(key);
+        Ok(())
+    }
+
+    fn contains(&self, key: &[u8]) -> crate::Result<bool> {
+        Ok(self.read()?.contains_key(key))
+    }
+
+    fn value_len(&self, key: &[u8]) -> crate::Result<Option<u64>> {
+        Ok(self.read()?.get(key).map(|value| value.len() as u64))
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> crate::Result<()> {
+        let mut data = self.write()?;
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    data.insert(key.clone(), value.clone());
+                }
+                BatchOp::Delete(key) => {
+                    data.remove(key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn list_keys(&self) -> crate::Result<Vec<Vec<u8>>> {
+        Ok(self.read()?.keys().cloned().collect())
+    }
+
+    fn clear(&self) -> crate::Result<()> {
+        self.write()?.clear();
+        Ok(())
+    }
+
+    fn sync(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn can_merge(&self) -> crate::Result<bool> {
+        Ok(false)
+    }
+
+    fn merge(&self, _control: &MergeControl) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn rekey(&self, _key: &[u8; 32]) -> crate::Result<()> {
+        // Nothing is persisted, so there is nothing to encrypt
+        Ok(())
+    }
+
+    fn verify(
+        &self,
+        _max_bytes_per_sec: Option<u64>,
+        _stop: &AtomicBool,
+    ) -> crate::Result<VerifyReport> {
+        Ok(VerifyReport::default())
+    }
+
+    fn catch_up(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> crate::Result<Stats> {
+        Ok(Stats {
+            key_count: self.read()?.len() as u64,
+            ..Stats::default()
+        })
+    }
+
+    fn close(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_values() {
+        let memory = Memory::with_config(Config::default()).unwrap();
+        memory.put(b"a", b"1").unwrap();
+        memory.put(b"a", b"2").unwrap();
+        memory
+            .write_batch(&[
+                BatchOp::Put(b"b".to_vec(), b"3".to_vec()),
+                BatchOp::Delete(b"a".to_vec()),
+            ])
+            .unwrap();
+
+        assert_eq!(memory.get(b"a").unwrap(), None);
+        assert_eq!(memory.get(b"b").unwrap().as_deref(), Some(&b"3"[..]));
+        assert_eq!(memory.value_len(b"b").unwrap(), Some(1));
+        assert_eq!(memory.stats().unwrap().key_count, 1);
+
+        memory.clear().unwrap();
+        assert!(memory.list_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_read_only_mode() {
+        let config = Config::builder().set_read_only(true).build();
+        assert!(Memory::with_config(config).is_err());
+    }
+}